@@ -0,0 +1,231 @@
+//! A minimal client that speaks the relay protocol.
+//!
+//! It opens a session with the `Connect` handshake, authenticates, then either creates a room or joins one, and sends a
+//! small `GameData` packet to every other peer once a second. Everything it
+//! receives is printed.
+//!
+//! Packets are built with the relay's own `Packet` serialization, and big messages are
+//! put back together with its fragment code, so this breaks as soon as the client
+//! and relay disagree on the wire format.
+//!
+//! ```text
+//! cargo run --example client -- <relay address> <app id>              # host a room
+//! cargo run --example client -- <relay address> <app id> <join code>  # join a room
+//! ```
+
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::time::Duration;
+use paperudp::channel::{Channel, DecodeResult};
+use paperudp::packet::PacketType;
+use tokio::net::UdpSocket;
+use crate::protocol::packet::{Packet, CONNECT_PADDING};
+use crate::protocol::version::{PROTOCOL_VERSION, WIRE_VERSION};
+use crate::udp::fragment::{self, Reassembler};
+
+/// Only the parts of the relay that deal with the wire format are pulled in.
+#[allow(dead_code)]
+#[path = "../src/protocol"]
+mod protocol {
+    pub mod error;
+    pub mod ids;
+    pub mod packet;
+    pub mod serialize;
+    pub mod version;
+}
+
+/// Big reliable messages from the relay arrive split into fragments.
+#[allow(dead_code)]
+#[path = "../src/udp"]
+mod udp {
+    pub mod fragment;
+}
+
+const RESEND_WINDOW: Duration = Duration::from_millis(100);
+
+struct Client {
+    socket: UdpSocket,
+    relay: SocketAddr,
+    channel: Channel,
+    fragments: Reassembler,
+}
+
+impl Client {
+    async fn send(&mut self, packet: &Packet, kind: PacketType) -> Result<(), Box<dyn Error>> {
+        let datagram = self.channel.encode(&packet.to_bytes(), kind);
+        self.socket.send_to(&datagram, self.relay).await?;
+        Ok(())
+    }
+
+    async fn send_raw(&mut self, datagram: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.socket.send_to(datagram, self.relay).await?;
+        Ok(())
+    }
+
+    /// Waits for the next datagram and returns the packets in it.
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<Vec<Packet>, Box<dyn Error>> {
+        let (len, _) = self.socket.recv_from(buf).await?;
+
+        let payload = match self.channel.decode(&buf[..len]) {
+            DecodeResult::Unreliable { payload } => payload,
+            DecodeResult::Reliable { payload, ack_packet, .. } => {
+                if let Some(ack) = ack_packet {
+                    self.send_raw(&ack).await?;
+                }
+                payload
+            }
+            DecodeResult::Ack { .. } | DecodeResult::None => Vec::new(),
+        };
+
+        let payload: Vec<Vec<u8>> = payload.into_iter()
+            .filter_map(|p| {
+                if !fragment::is_fragment(&p) {
+                    return Some(p);
+                }
+                match self.fragments.insert(&p) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        eprintln!("dropping fragment: {e}");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        Ok(payload.iter()
+            .filter_map(|p| match Packet::from_bytes(p) {
+                Ok(packet) => Some(packet),
+                Err(e) => {
+                    eprintln!("failed to decode packet: {e}");
+                    None
+                }
+            })
+            .filter(|packet| !matches!(packet, Packet::Heartbeat))
+            .collect())
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = std::env::args().skip(1);
+    let (Some(relay), Some(app_id)) = (args.next(), args.next()) else {
+        eprintln!("usage: client <relay address> <app id> [join code]");
+        std::process::exit(2);
+    };
+    let join_code = args.next();
+
+    let relay: SocketAddr = tokio::net::lookup_host(&relay).await?
+        .next()
+        .ok_or("relay address didn't resolve")?;
+
+    let bind_addr = if relay.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" };
+    let mut client = Client {
+        socket: UdpSocket::bind(bind_addr).await?,
+        relay,
+        channel: Channel::new(),
+        fragments: Reassembler::default(),
+    };
+
+    // The relay ignores anything from a new address until it has sent `Connect`, and only opens a session
+    // once the cookie it answers with is echoed back. The first `Connect` is repeated until that happens.
+    let hello = Packet::Connect { protocol_version: WIRE_VERSION, cookie: vec![0; CONNECT_PADDING] };
+    client.send(&hello, PacketType::Unreliable).await?;
+    let mut cookie_echoed = false;
+
+    let mut peers = BTreeSet::new();
+    let mut own_peer_id = None;
+    let mut buf = [0u8; 65535];
+
+    let mut resend = tokio::time::interval(RESEND_WINDOW);
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            packets = client.recv(&mut buf) => {
+                for packet in packets? {
+                    println!("<- {packet:?}");
+
+                    match packet {
+                        Packet::ConnectAccepted { client_id: 0, cookie } if !cookie_echoed => {
+                            cookie_echoed = true;
+                            let connect = Packet::Connect { protocol_version: WIRE_VERSION, cookie };
+                            client.send(&connect, PacketType::ReliableOrdered).await?;
+                        }
+                        // A cookie for a repeated `Connect`, the first one was already echoed.
+                        Packet::ConnectAccepted { client_id: 0, .. } => {}
+                        Packet::ConnectAccepted { .. } => {
+                            client.send(&Packet::Authenticate {
+                                app_id: app_id.clone(),
+                                version: PROTOCOL_VERSION.to_string(),
+                                stable_id: String::new(),
+                                resume_token: String::new(),
+                            }, PacketType::ReliableOrdered).await?;
+                        }
+                        Packet::ClientAuthenticated { .. } => {
+                            let next = match &join_code {
+                                Some(code) => Packet::ReqJoin { room_id: code.clone(), metadata: String::new(), spectator: false },
+                                None => Packet::CreateRoom {
+                                    is_public: true,
+                                    metadata: HashMap::new(),
+                                    max_players: 0,
+                                    max_join_rtt_ms: 0,
+                                },
+                            };
+                            client.send(&next, PacketType::ReliableOrdered).await?;
+                        }
+                        Packet::RoomCreated { join_code, peer_id, .. } => {
+                            println!("hosting room {join_code}");
+                            own_peer_id = Some(peer_id);
+                        }
+                        Packet::ConnectedToRoom { peer_id, existing_peers, .. } => {
+                            own_peer_id = Some(peer_id);
+                            peers.extend(existing_peers);
+                        }
+                        Packet::PeerJoinAttempt { target_id, .. } => {
+                            // The relay fills in the room from the host's state, so the ID isn't needed.
+                            let answer = Packet::JoinRes { target_id, room_id: String::new(), allowed: true };
+                            client.send(&answer, PacketType::ReliableOrdered).await?;
+                        }
+                        Packet::PeerJoinedRoom { peer_id } => {
+                            peers.insert(peer_id);
+                        }
+                        Packet::PeerLeftRoom { peer_id } => {
+                            peers.remove(&peer_id);
+                        }
+                        Packet::ForceDisconnect => return Ok(()),
+                        _ => {}
+                    }
+                }
+            }
+
+            _ = resend.tick() => {
+                for datagram in client.channel.collect_resends(RESEND_WINDOW) {
+                    client.send_raw(&datagram).await?;
+                }
+            }
+
+            _ = tick.tick() => {
+                if !cookie_echoed {
+                    client.send(&hello, PacketType::Unreliable).await?;
+                    continue;
+                }
+
+                client.send(&Packet::Heartbeat, PacketType::Unreliable).await?;
+
+                let Some(from_peer) = own_peer_id else {
+                    continue;
+                };
+
+                for &peer in &peers {
+                    // Clients put the target in `from_peer`, the relay swaps in the sender on the way out.
+                    let packet = Packet::GameData {
+                        from_peer: peer,
+                        data: format!("hello from {from_peer}").into_bytes(),
+                    };
+                    client.send(&packet, PacketType::Unreliable).await?;
+                }
+            }
+        }
+    }
+}
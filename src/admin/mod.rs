@@ -0,0 +1,156 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, info, warn};
+
+/// The longest command line accepted, so a client can't make the listener buffer without limit.
+const MAX_LINE_LEN: usize = 1024;
+/// How long to wait after a failed accept, so running out of file descriptors doesn't spin the listener.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A command an operator can run against the live relay.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    ListRooms,
+    /// Closes the room with this join code. Codes are only unique within an app,
+    /// so the app token is needed when more than one app uses the code.
+    CloseRoom { join_code: String, app: Option<String> },
+    Kick { client_id: u64 },
+    Stats,
+}
+
+impl AdminCommand {
+    /// Parses one command line, e.g. `close-room ABCDE`.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            return Err("empty command".to_string());
+        };
+        let args: Vec<&str> = words.collect();
+
+        match (name, args.as_slice()) {
+            ("list-rooms", []) => Ok(Self::ListRooms),
+            ("close-room", [join_code]) => Ok(Self::CloseRoom { join_code: (*join_code).to_string(), app: None }),
+            ("close-room", [join_code, app]) => Ok(Self::CloseRoom {
+                join_code: (*join_code).to_string(),
+                app: Some((*app).to_string()),
+            }),
+            ("kick", [client_id]) => client_id.parse()
+                .map(|client_id| Self::Kick { client_id })
+                .map_err(|_| format!("{client_id} is not a client ID")),
+            ("stats", []) => Ok(Self::Stats),
+            ("list-rooms" | "close-room" | "kick" | "stats", _) => Err(format!("wrong arguments for {name}")),
+            _ => Err(format!("unknown command {name}")),
+        }
+    }
+}
+
+/// A command on its way to the relay loop, with somewhere to send the reply.
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: oneshot::Sender<String>,
+}
+
+/// Accepts admin connections and forwards their commands to the relay loop.
+/// Each line received is one command, and each reply is written back followed by a blank line.
+pub async fn run_admin_server(addr: SocketAddr, commands: mpsc::Sender<AdminRequest>) -> Result<(), std::io::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("admin listener on {}", addr);
+
+    loop {
+        // A failed accept only loses that one connection, so the listener keeps going.
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("failed to accept an admin connection: {}", e);
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        debug!("admin connection from {}", peer);
+
+        let commands = commands.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, commands).await {
+                warn!("admin connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, commands: mpsc::Sender<AdminRequest>) -> Result<(), std::io::Error> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let read = (&mut reader).take(MAX_LINE_LEN as u64).read_line(&mut line).await?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        if read >= MAX_LINE_LEN && !line.ends_with('\n') {
+            writer.write_all(b"error: command too long\n").await?;
+            return Ok(());
+        }
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let reply = match AdminCommand::parse(&line) {
+            Ok(command) => {
+                info!("running admin command: {:?}", command);
+                run(&commands, command).await
+            }
+            Err(e) => format!("error: {e}"),
+        };
+
+        writer.write_all(reply.trim_end().as_bytes()).await?;
+        writer.write_all(b"\n\n").await?;
+    }
+}
+
+/// Hands a command to the relay loop and waits for its reply.
+async fn run(commands: &mpsc::Sender<AdminRequest>, command: AdminCommand) -> String {
+    let (reply, response) = oneshot::channel();
+
+    if commands.send(AdminRequest { command, reply }).await.is_err() {
+        return "error: the relay is shutting down".to_string();
+    }
+
+    response.await.unwrap_or_else(|_| "error: the relay dropped the command".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_parsed() {
+        assert_eq!(AdminCommand::parse("list-rooms"), Ok(AdminCommand::ListRooms));
+        assert_eq!(AdminCommand::parse("  stats \n"), Ok(AdminCommand::Stats));
+        assert_eq!(AdminCommand::parse("kick 42"), Ok(AdminCommand::Kick { client_id: 42 }));
+        assert_eq!(
+            AdminCommand::parse("close-room ABCDE"),
+            Ok(AdminCommand::CloseRoom { join_code: "ABCDE".to_string(), app: None })
+        );
+        assert_eq!(
+            AdminCommand::parse("close-room ABCDE my-app"),
+            Ok(AdminCommand::CloseRoom { join_code: "ABCDE".to_string(), app: Some("my-app".to_string()) })
+        );
+    }
+
+    #[test]
+    fn bad_commands_are_refused() {
+        assert_eq!(AdminCommand::parse("   "), Err("empty command".to_string()));
+        assert_eq!(AdminCommand::parse("reboot"), Err("unknown command reboot".to_string()));
+        assert_eq!(AdminCommand::parse("kick alice"), Err("alice is not a client ID".to_string()));
+        assert_eq!(AdminCommand::parse("kick"), Err("wrong arguments for kick".to_string()));
+        assert_eq!(AdminCommand::parse("stats now"), Err("wrong arguments for stats".to_string()));
+        assert_eq!(AdminCommand::parse("close-room A B C"), Err("wrong arguments for close-room".to_string()));
+    }
+}
@@ -10,4 +10,10 @@ pub enum ConfigError {
 
     #[error("Config file could not be parsed: {0}")]
     ParseError(#[from] toml::de::Error),
+
+    #[error("Config could not be read from the environment: {0}")]
+    EnvError(#[from] envy::Error),
+
+    #[error("Invalid config: {0}")]
+    Invalid(String),
 }
\ No newline at end of file
@@ -1,19 +1,81 @@
+use std::collections::HashMap;
 use std::fs;
-use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
 use std::path::PathBuf;
 use crate::config::error::ConfigError;
+use crate::logging::LogFormat;
+use crate::udp::bind::AddressFamily;
+use crate::udp::common::TransferChannel;
+
+pub const CONFIG_PATH: &str = "config.toml";
+
+/// The fewest distinct join codes the configured length and alphabet must allow for.
+const MIN_JOIN_CODES: u64 = 1000;
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
-    #[serde(default = "defaults::udp_bind_address")]
-    pub udp_bind_address: String,
+    /// The addresses to listen on. Clients are answered from the address they sent to.
+    /// Accepts a single address, a list, or a comma-separated string.
+    #[serde(default = "defaults::udp_bind_address", deserialize_with = "one_or_many")]
+    pub udp_bind_address: Vec<String>,
+
+    /// Which kind of address to use when `udp_bind_address` resolves to both IPv4 and IPv6.
+    #[serde(default)]
+    pub udp_address_family: AddressFamily,
+
+    /// When enabled, binding an IPv6 address like `[::]` also accepts IPv4 clients on the same socket.
+    #[serde(default = "defaults::enabled")]
+    pub udp_dual_stack: bool,
+
+    /// The UDP receive buffer size asked of the OS, in bytes. 0 keeps the OS default.
+    /// Raising it stops datagrams being dropped in bursts, but the OS may clamp it.
+    #[serde(default = "defaults::zero_bytes")]
+    pub udp_recv_buffer_bytes: usize,
+
+    /// The UDP send buffer size asked of the OS, in bytes. 0 keeps the OS default.
+    #[serde(default = "defaults::zero_bytes")]
+    pub udp_send_buffer_bytes: usize,
+
+    #[serde(default = "defaults::health_bind_address")]
+    pub health_bind_address: String,
+
+    /// Where the admin command listener binds. Empty disables it.
+    /// It has no authentication, so only loopback addresses are accepted.
+    #[serde(default = "defaults::empty_string")]
+    pub admin_bind_address: String,
 
+    /// Whether logs are written as text or JSON.
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    /// The most verbose level logged: trace, debug, info, warn or error.
+    #[serde(default = "defaults::log_level")]
+    pub log_level: String,
+
+    /// App IDs allowed to connect, where `*` matches any run of characters. Empty allows every app.
     #[serde(default = "defaults::whitelist")]
     pub whitelist: Vec<String>,
 
+    /// Client versions allowed to connect. Plain versions match exactly,
+    /// anything else is tried as a semver range like `>=1.2.0, <2.0.0`.
     #[serde(default = "defaults::allowed_versions")]
     pub allowed_versions: Vec<String>,
 
+    /// Client addresses whose packets are dropped, as single IPs or CIDR ranges like `203.0.113.0/24`.
+    #[serde(default = "defaults::ip_denylist")]
+    pub ip_denylist: Vec<String>,
+
+    /// The oldest client version allowed to connect, compared as semver.
+    /// Versions in `allowed_versions` are allowed regardless. Empty disables this.
+    #[serde(default = "defaults::empty_string")]
+    pub min_client_version: String,
+
+    /// How long a client rejected for its version is kept connected, so it reliably gets the reason.
+    #[serde(default = "defaults::version_reject_grace_ms")]
+    pub version_reject_grace_ms: u64,
+
     #[serde(default = "defaults::empty_string")]
     pub remote_whitelist_endpoint: String,
 
@@ -22,9 +84,457 @@ pub struct Config {
 
     #[serde(default = "defaults::empty_string")]
     pub relay_id: String,
+
+    #[serde(default = "defaults::empty_string")]
+    pub registry_endpoint: String,
+
+    #[serde(default = "defaults::empty_string")]
+    pub registry_token: String,
+
+    /// A PEM bundle of the CAs trusted for the registry's certificate.
+    /// When set, the registry is reached over rustls trusting only these, instead of the system's roots.
+    #[serde(default = "defaults::empty_string")]
+    pub registry_ca_path: String,
+
+    /// A PEM certificate chain presented to the registry for mutual TLS. Needs `registry_ca_path`.
+    #[serde(default = "defaults::empty_string")]
+    pub registry_client_cert_path: String,
+
+    /// The PEM private key for `registry_client_cert_path`.
+    #[serde(default = "defaults::empty_string")]
+    pub registry_client_key_path: String,
+
+    /// The address clients should use to reach this relay.
+    /// Reported to the registry so other relays can redirect clients here.
+    #[serde(default = "defaults::empty_string")]
+    pub public_address: String,
+
+    /// The region this relay runs in, reported to the registry with each room.
+    #[serde(default = "defaults::empty_string")]
+    pub region: String,
+
+    /// How often the relay tells the registry its rooms are still alive. 0 disables heartbeats.
+    #[serde(default = "defaults::registry_heartbeat_interval_ms")]
+    pub registry_heartbeat_interval_ms: u64,
+
+    /// When enabled, a room picks a new host when its host leaves instead of closing.
+    #[serde(default = "defaults::disabled")]
+    pub host_migration: bool,
+
+    /// When enabled, room-management packets sent over the unreliable channel are rejected.
+    /// Off by default, since clients older than this setting send some of them unreliably.
+    #[serde(default = "defaults::disabled")]
+    pub require_reliable_control: bool,
+
+    /// How many authentication attempts a single address can make per window.
+    #[serde(default = "defaults::auth_attempt_limit")]
+    pub auth_attempt_limit: u32,
+
+    #[serde(default = "defaults::auth_attempt_window_ms")]
+    pub auth_attempt_window_ms: u64,
+
+    /// How many times a client can ask to join the same room per window.
+    #[serde(default = "defaults::join_attempt_limit")]
+    pub join_attempt_limit: u32,
+
+    #[serde(default = "defaults::join_attempt_window_ms")]
+    pub join_attempt_window_ms: u64,
+
+    /// The channel used for `GameDataAuto` packets when an app has no entry in `app_game_data_channels`.
+    #[serde(default = "defaults::game_data_channel")]
+    pub default_game_data_channel: TransferChannel,
+
+    /// Per-app channels for `GameDataAuto` packets, keyed by the app token clients authenticate with
+    /// (the same values `whitelist` matches). The relay's internal app IDs change between runs, so they can't be used.
+    #[serde(default)]
+    pub app_game_data_channels: HashMap<String, TransferChannel>,
+
+    /// The most game data a client can send per second, in bytes. 0 disables the limit.
+    /// Unreliable game data past this is dropped, and reliable game data past it gets the sender disconnected.
+    #[serde(default = "defaults::game_data_bytes_per_sec")]
+    pub game_data_bytes_per_sec: u64,
+
+    /// How much game data a client can send at once on top of the steady rate, in bytes.
+    /// Single packets bigger than this are refused.
+    #[serde(default = "defaults::game_data_burst_bytes")]
+    pub game_data_burst_bytes: u64,
+
+    /// How long a client can stay over its game data limit before it's disconnected. 0 never disconnects.
+    #[serde(default = "defaults::game_data_throttle_disconnect_ms")]
+    pub game_data_throttle_disconnect_ms: u64,
+
+    /// The most rooms a single app can have open at once.
+    #[serde(default = "defaults::max_rooms_per_app")]
+    pub max_rooms_per_app: usize,
+
+    /// The largest room metadata accepted in `CreateRoom` and `UpdateRoom`, in bytes.
+    /// Counts every key and value along with their length prefixes.
+    #[serde(default = "defaults::max_metadata_bytes")]
+    pub max_metadata_bytes: usize,
+
+    /// The most room data sent in one `GetRooms` packet, in bytes.
+    /// Longer room lists are split over several packets so each stays well under the MTU.
+    #[serde(default = "defaults::room_list_max_bytes")]
+    pub room_list_max_bytes: usize,
+
+    /// How long a peer that dropped out of a room can reconnect to its old slot.
+    #[serde(default = "defaults::reconnect_grace_ms")]
+    pub reconnect_grace_ms: u64,
+
+    /// How long a session has to go unheard from before another client with its stable ID can take it over.
+    /// Clients send heartbeats more often than this, so a session that's still in use can't be hijacked.
+    #[serde(default = "defaults::resume_after_silence_ms")]
+    pub resume_after_silence_ms: u64,
+
+    /// How long a join request waits on the host's answer before it's dropped.
+    #[serde(default = "defaults::join_request_timeout_ms")]
+    pub join_request_timeout_ms: u64,
+
+    /// The most join requests waiting on a host across the whole relay.
+    #[serde(default = "defaults::max_pending_joins")]
+    pub max_pending_joins: usize,
+
+    /// The most join requests waiting on the host of a single room.
+    #[serde(default = "defaults::max_pending_joins_per_room")]
+    pub max_pending_joins_per_room: usize,
+
+    /// The most spectators a single room can have. 0 turns spectating off.
+    #[serde(default = "defaults::max_spectators")]
+    pub max_spectators: usize,
+
+    /// How many characters generated join codes have.
+    #[serde(default = "defaults::join_code_length")]
+    pub join_code_length: usize,
+
+    /// The characters generated join codes are made of.
+    #[serde(default = "defaults::join_code_alphabet")]
+    pub join_code_alphabet: String,
+
+    /// Where rooms are saved so their join codes survive a restart. Empty disables this.
+    #[serde(default = "defaults::empty_string")]
+    pub room_store_path: String,
+
+    /// How long a restored room waits for its host to come back before it's closed.
+    #[serde(default = "defaults::room_restore_grace_ms")]
+    pub room_restore_grace_ms: u64,
+
+    /// When enabled, client addresses are replaced by a hash in log output.
+    /// The hash is stable while the relay runs, so a client's log lines can still be matched up.
+    #[serde(default = "defaults::disabled")]
+    pub anonymize_log_addresses: bool,
+
+    /// When enabled, the relay promises clients it won't look at game data, and says so when they authenticate.
+    #[serde(default = "defaults::disabled")]
+    pub opaque_forwarding: bool,
+
+    /// The most clients connected at once, including ones that haven't authenticated yet.
+    #[serde(default = "defaults::max_clients")]
+    pub max_clients: usize,
+
+    /// The most new connections accepted per `new_connection_window_ms`, across all addresses.
+    /// Packets from new addresses past this are dropped.
+    #[serde(default = "defaults::new_connection_limit")]
+    pub new_connection_limit: u32,
+
+    #[serde(default = "defaults::new_connection_window_ms")]
+    pub new_connection_window_ms: u64,
+
+    /// The most handshake cookies sent to one IP address per `new_connection_window_ms`.
+    /// Stops the handshake being used to flood an address someone else spoofed.
+    #[serde(default = "defaults::cookie_limit_per_ip")]
+    pub cookie_limit_per_ip: u32,
+
+    /// The most datagrams read from the socket before the relay loop gets a turn.
+    #[serde(default = "defaults::max_datagrams_per_poll")]
+    pub max_datagrams_per_poll: usize,
+
+    /// The most reliable data a client can leave unacknowledged for longer than the resend interval, in bytes.
+    /// A client past this has stopped acking and is disconnected, since everything sent to it is being kept for resends.
+    /// 0 disables the limit.
+    #[serde(default = "defaults::max_unacked_reliable_bytes")]
+    pub max_unacked_reliable_bytes: usize,
+
+    #[serde(default)]
+    pub timing: TimingConfig,
+}
+
+impl Config {
+    /// Copies over the settings that can safely change while the server is running.
+    /// Returns the names of any changed settings that need a restart to take effect.
+    pub fn reload_from(&mut self, new: Config) -> Vec<&'static str> {
+        let mut ignored = Vec::new();
+
+        if self.udp_bind_address != new.udp_bind_address { ignored.push("udp_bind_address"); }
+        if self.udp_address_family != new.udp_address_family { ignored.push("udp_address_family"); }
+        if self.udp_dual_stack != new.udp_dual_stack { ignored.push("udp_dual_stack"); }
+        if self.udp_recv_buffer_bytes != new.udp_recv_buffer_bytes { ignored.push("udp_recv_buffer_bytes"); }
+        if self.udp_send_buffer_bytes != new.udp_send_buffer_bytes { ignored.push("udp_send_buffer_bytes"); }
+        if self.health_bind_address != new.health_bind_address { ignored.push("health_bind_address"); }
+        if self.admin_bind_address != new.admin_bind_address { ignored.push("admin_bind_address"); }
+        if self.log_format != new.log_format { ignored.push("log_format"); }
+        if self.log_level != new.log_level { ignored.push("log_level"); }
+        if self.relay_id != new.relay_id { ignored.push("relay_id"); }
+        if self.registry_endpoint != new.registry_endpoint { ignored.push("registry_endpoint"); }
+        if self.registry_token != new.registry_token { ignored.push("registry_token"); }
+        if self.registry_ca_path != new.registry_ca_path { ignored.push("registry_ca_path"); }
+        if self.registry_client_cert_path != new.registry_client_cert_path { ignored.push("registry_client_cert_path"); }
+        if self.registry_client_key_path != new.registry_client_key_path { ignored.push("registry_client_key_path"); }
+        if self.public_address != new.public_address { ignored.push("public_address"); }
+        if self.region != new.region { ignored.push("region"); }
+        if self.join_code_length != new.join_code_length { ignored.push("join_code_length"); }
+        if self.join_code_alphabet != new.join_code_alphabet { ignored.push("join_code_alphabet"); }
+        if self.room_store_path != new.room_store_path { ignored.push("room_store_path"); }
+        if self.room_restore_grace_ms != new.room_restore_grace_ms { ignored.push("room_restore_grace_ms"); }
+        if self.max_datagrams_per_poll != new.max_datagrams_per_poll { ignored.push("max_datagrams_per_poll"); }
+        if self.opaque_forwarding != new.opaque_forwarding { ignored.push("opaque_forwarding"); }
+        if self.timing != new.timing { ignored.push("timing"); }
+
+        self.whitelist = new.whitelist;
+        self.allowed_versions = new.allowed_versions;
+        self.ip_denylist = new.ip_denylist;
+        self.min_client_version = new.min_client_version;
+        self.version_reject_grace_ms = new.version_reject_grace_ms;
+        self.remote_whitelist_endpoint = new.remote_whitelist_endpoint;
+        self.remote_whitelist_token = new.remote_whitelist_token;
+        self.host_migration = new.host_migration;
+        self.require_reliable_control = new.require_reliable_control;
+        self.registry_heartbeat_interval_ms = new.registry_heartbeat_interval_ms;
+        self.auth_attempt_limit = new.auth_attempt_limit;
+        self.auth_attempt_window_ms = new.auth_attempt_window_ms;
+        self.join_attempt_limit = new.join_attempt_limit;
+        self.join_attempt_window_ms = new.join_attempt_window_ms;
+        self.default_game_data_channel = new.default_game_data_channel;
+        self.app_game_data_channels = new.app_game_data_channels;
+        self.game_data_bytes_per_sec = new.game_data_bytes_per_sec;
+        self.game_data_burst_bytes = new.game_data_burst_bytes;
+        self.game_data_throttle_disconnect_ms = new.game_data_throttle_disconnect_ms;
+        self.max_clients = new.max_clients;
+        self.new_connection_limit = new.new_connection_limit;
+        self.new_connection_window_ms = new.new_connection_window_ms;
+        self.cookie_limit_per_ip = new.cookie_limit_per_ip;
+        self.anonymize_log_addresses = new.anonymize_log_addresses;
+        self.reconnect_grace_ms = new.reconnect_grace_ms;
+        self.resume_after_silence_ms = new.resume_after_silence_ms;
+        self.max_metadata_bytes = new.max_metadata_bytes;
+        self.room_list_max_bytes = new.room_list_max_bytes;
+        self.max_rooms_per_app = new.max_rooms_per_app;
+        self.join_request_timeout_ms = new.join_request_timeout_ms;
+        self.max_pending_joins = new.max_pending_joins;
+        self.max_pending_joins_per_room = new.max_pending_joins_per_room;
+        self.max_spectators = new.max_spectators;
+        self.max_unacked_reliable_bytes = new.max_unacked_reliable_bytes;
+
+        ignored
+    }
+
+    /// Checks for settings that parse fine but can't work together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.udp_bind_address.is_empty() {
+            return Err(ConfigError::Invalid("udp_bind_address needs at least one address".to_string()));
+        }
+
+        for address in &self.udp_bind_address {
+            check_address("udp_bind_address", address)?;
+        }
+
+        if !self.admin_bind_address.is_empty() {
+            let is_loopback = self.admin_bind_address.parse::<SocketAddr>()
+                .is_ok_and(|addr| addr.ip().is_loopback());
+
+            if !is_loopback {
+                return Err(ConfigError::Invalid(format!(
+                    "admin_bind_address {} must be a loopback address and port, the admin listener has no authentication",
+                    self.admin_bind_address,
+                )));
+            }
+        }
+
+        if self.registry_client_cert_path.is_empty() != self.registry_client_key_path.is_empty() {
+            return Err(ConfigError::Invalid(
+                "registry_client_cert_path and registry_client_key_path must be set together".to_string(),
+            ));
+        }
+
+        if !self.registry_client_cert_path.is_empty() && self.registry_ca_path.is_empty() {
+            return Err(ConfigError::Invalid(
+                "registry_client_cert_path needs registry_ca_path, client certificates are only sent over the pinned connection".to_string(),
+            ));
+        }
+
+        if !self.min_client_version.is_empty() {
+            semver::Version::parse(&self.min_client_version).map_err(|e| ConfigError::Invalid(format!(
+                "min_client_version {} is not a valid version: {}", self.min_client_version, e,
+            )))?;
+        }
+
+        if self.allowed_versions.is_empty() && self.min_client_version.is_empty() {
+            return Err(ConfigError::Invalid(
+                "allowed_versions and min_client_version are both empty, so no client could connect".to_string(),
+            ));
+        }
+
+        if let Some(version) = self.allowed_versions.iter().find(|version| version.trim().is_empty()) {
+            return Err(ConfigError::Invalid(format!("allowed_versions contains an empty version: {version:?}")));
+        }
+
+        if let Some(entry) = self.ip_denylist.iter().find(|entry| parse_network(entry).is_none()) {
+            return Err(ConfigError::Invalid(format!(
+                "ip_denylist entry {entry:?} is not an IP address or CIDR range",
+            )));
+        }
+
+        for (name, value) in [
+            ("timing.cleanup_interval_ms", self.timing.cleanup_interval_ms),
+            ("timing.resend_interval_ms", self.timing.resend_interval_ms),
+            ("timing.session_timeout_ms", self.timing.session_timeout_ms),
+            ("timing.resend_window_ms", self.timing.resend_window_ms),
+        ] {
+            if value == 0 {
+                return Err(ConfigError::Invalid(format!("{name} must be greater than 0")));
+            }
+        }
+
+        if self.game_data_bytes_per_sec > 0 && self.game_data_burst_bytes == 0 {
+            return Err(ConfigError::Invalid(
+                "game_data_burst_bytes must be greater than 0 when game_data_bytes_per_sec is set".to_string(),
+            ));
+        }
+
+        let mut alphabet: Vec<char> = self.join_code_alphabet.chars().collect();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+
+        let possible_codes = u32::try_from(self.join_code_length)
+            .ok()
+            .and_then(|length| (alphabet.len() as u64).checked_pow(length))
+            .unwrap_or(u64::MAX);
+
+        if possible_codes < MIN_JOIN_CODES {
+            return Err(ConfigError::Invalid(format!(
+                "join codes of length {} from {} distinct characters allow only {} rooms, at least {} are needed",
+                self.join_code_length, alphabet.len(), possible_codes, MIN_JOIN_CODES,
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Parses `ip_denylist`. Invalid entries are skipped, but `validate` rejects them anyway.
+    pub fn denied_networks(&self) -> Vec<IpNet> {
+        self.ip_denylist.iter()
+            .filter_map(|entry| parse_network(entry))
+            .collect()
+    }
+
+    /// Gets the channel `GameDataAuto` packets should be forwarded on for an app, by its token.
+    pub fn game_data_channel(&self, app_token: &str) -> TransferChannel {
+        self.app_game_data_channels.get(app_token)
+            .copied()
+            .unwrap_or(self.default_game_data_channel)
+    }
+}
+
+/// Parses a CIDR range, or a single IP as a range holding just that address.
+fn parse_network(entry: &str) -> Option<IpNet> {
+    let entry = entry.trim();
+    entry.parse::<IpNet>().ok()
+        .or_else(|| entry.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Checks that an address is written as `host:port`, without resolving it.
+/// Catches typos at load time instead of when the socket is bound.
+fn check_address(field: &str, address: &str) -> Result<(), ConfigError> {
+    if address.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+
+    let valid = address.rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && !host.contains(':') && port.parse::<u16>().is_ok());
+
+    if valid {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid(format!(
+            "{field} {address:?} is not a valid address, expected host:port like 0.0.0.0:8080 or [::]:8080",
+        )))
+    }
+}
+
+/// Reads a list that may also be written as a single string, split on commas.
+/// Keeps configs written before the field became a list working, and is how lists come in from the environment.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let values = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => value.split(',').map(str::to_string).collect(),
+        OneOrMany::Many(values) => values,
+    };
+
+    Ok(values.into_iter()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect())
+}
+
+/// Intervals and timeouts used by the relay loop.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TimingConfig {
+    /// How often timed out sessions are cleaned up.
+    #[serde(default = "defaults::cleanup_interval_ms")]
+    pub cleanup_interval_ms: u64,
+
+    /// How often unacknowledged reliable packets are checked for resending.
+    #[serde(default = "defaults::resend_interval_ms")]
+    pub resend_interval_ms: u64,
+
+    /// How long a client can go without sending anything before it's disconnected.
+    #[serde(default = "defaults::session_timeout_ms")]
+    pub session_timeout_ms: u64,
+
+    /// How long a reliable packet goes unacknowledged before it's resent.
+    #[serde(default = "defaults::resend_window_ms")]
+    pub resend_window_ms: u64,
+
+    /// How long a room can go without any activity before it's closed. 0 disables this.
+    #[serde(default = "defaults::room_idle_timeout_ms")]
+    pub room_idle_timeout_ms: u64,
+
+    /// How long a client can go without being sent anything before the relay sends it a keepalive.
+    /// Checked on each cleanup tick. 0 disables keepalives.
+    #[serde(default = "defaults::keepalive_interval_ms")]
+    pub keepalive_interval_ms: u64,
+
+    /// How long the relay keeps resending on shutdown, so the final `ForceDisconnect`s reach clients.
+    #[serde(default = "defaults::shutdown_drain_ms")]
+    pub shutdown_drain_ms: u64,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            cleanup_interval_ms: defaults::cleanup_interval_ms(),
+            resend_interval_ms: defaults::resend_interval_ms(),
+            session_timeout_ms: defaults::session_timeout_ms(),
+            resend_window_ms: defaults::resend_window_ms(),
+            room_idle_timeout_ms: defaults::room_idle_timeout_ms(),
+            keepalive_interval_ms: defaults::keepalive_interval_ms(),
+            shutdown_drain_ms: defaults::shutdown_drain_ms(),
+        }
+    }
 }
 
 pub fn load_config(path: &str) -> Result<Config, ConfigError> {
+    let config = read_config(path)?;
+    config.validate()?;
+    Ok(config)
+}
+
+fn read_config(path: &str) -> Result<Config, ConfigError> {
     let config_path = PathBuf::from(path);
 
     if config_path.exists() {
@@ -33,22 +543,91 @@ pub fn load_config(path: &str) -> Result<Config, ConfigError> {
     }
 
     // Fallback to environment variables
-    match envy::from_env::<Config>() {
-        Ok(cfg) => Ok(cfg),
-        Err(_) => Ok(Config {
-            udp_bind_address: defaults::udp_bind_address(),
-            whitelist: defaults::whitelist(),
-            allowed_versions: defaults::allowed_versions(),
-            remote_whitelist_endpoint: defaults::empty_string(),
-            remote_whitelist_token: defaults::empty_string(),
-            relay_id: defaults::empty_string(),
-        }),
-    }
+    Ok(envy::from_env::<Config>()?)
 }
 
 mod defaults {
-    pub fn udp_bind_address() -> String { "0.0.0.0:8080".to_string() }
+    use crate::udp::common::TransferChannel;
+
+    pub fn udp_bind_address() -> Vec<String> { vec!["0.0.0.0:8080".to_string()] }
+    pub fn health_bind_address() -> String { "0.0.0.0:8081".to_string() }
+    pub fn log_level() -> String { "info".to_string() }
     pub fn whitelist() -> Vec<String> { vec![] }
     pub fn allowed_versions() -> Vec<String> { vec![] }
+    pub fn ip_denylist() -> Vec<String> { vec![] }
     pub fn empty_string() -> String { "".to_string() }
-}
\ No newline at end of file
+    pub fn disabled() -> bool { false }
+    pub fn enabled() -> bool { true }
+    pub fn zero_bytes() -> usize { 0 }
+    pub fn version_reject_grace_ms() -> u64 { 3000 }
+    pub fn registry_heartbeat_interval_ms() -> u64 { 30_000 }
+    pub fn auth_attempt_limit() -> u32 { 5 }
+    pub fn auth_attempt_window_ms() -> u64 { 10_000 }
+    pub fn join_attempt_limit() -> u32 { 5 }
+    pub fn join_attempt_window_ms() -> u64 { 30_000 }
+    pub fn game_data_channel() -> TransferChannel { TransferChannel::Reliable }
+    pub fn game_data_bytes_per_sec() -> u64 { 256 * 1024 }
+    pub fn game_data_burst_bytes() -> u64 { 64 * 1024 }
+    pub fn game_data_throttle_disconnect_ms() -> u64 { 10_000 }
+    pub fn max_rooms_per_app() -> usize { 1000 }
+    pub fn max_metadata_bytes() -> usize { 1024 }
+    pub fn room_list_max_bytes() -> usize { 1024 }
+    pub fn reconnect_grace_ms() -> u64 { 30_000 }
+    pub fn resume_after_silence_ms() -> u64 { 2000 }
+    pub fn join_request_timeout_ms() -> u64 { 30_000 }
+    pub fn max_pending_joins() -> usize { 1000 }
+    pub fn max_pending_joins_per_room() -> usize { 32 }
+    pub fn max_spectators() -> usize { 16 }
+    pub fn join_code_length() -> usize { 5 }
+    pub fn join_code_alphabet() -> String { "ABCDEFGHJKLMNPQRSTUVWXYZ123456789".to_string() }
+    pub fn room_restore_grace_ms() -> u64 { 2 * 60 * 1000 }
+    pub fn max_clients() -> usize { 1000 }
+    pub fn new_connection_limit() -> u32 { 100 }
+    pub fn new_connection_window_ms() -> u64 { 1000 }
+    pub fn cookie_limit_per_ip() -> u32 { 20 }
+    pub fn max_datagrams_per_poll() -> usize { 256 }
+    pub fn max_unacked_reliable_bytes() -> usize { 1024 * 1024 }
+    pub fn cleanup_interval_ms() -> u64 { 1000 }
+    pub fn resend_interval_ms() -> u64 { 50 }
+    pub fn session_timeout_ms() -> u64 { 5000 }
+    pub fn resend_window_ms() -> u64 { 100 }
+    pub fn room_idle_timeout_ms() -> u64 { 10 * 60 * 1000 }
+    pub fn keepalive_interval_ms() -> u64 { 2000 }
+    pub fn shutdown_drain_ms() -> u64 { 500 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(r#"allowed_versions = ["1.0.0"]"#).unwrap()
+    }
+
+    #[test]
+    fn defaults_with_an_allowed_version_are_valid() {
+        assert!(config().validate().is_ok());
+    }
+
+    #[test]
+    fn bind_addresses_without_a_port_are_rejected() {
+        let mut config = config();
+        config.udp_bind_address = vec!["0.0.0.0".to_string()];
+
+        let Err(ConfigError::Invalid(msg)) = config.validate() else {
+            panic!("expected the bind address to be rejected");
+        };
+        assert!(msg.contains("udp_bind_address"));
+    }
+
+    #[test]
+    fn zero_timeouts_are_rejected() {
+        let mut config = config();
+        config.timing.session_timeout_ms = 0;
+
+        let Err(ConfigError::Invalid(msg)) = config.validate() else {
+            panic!("expected the timeout to be rejected");
+        };
+        assert!(msg.contains("timing.session_timeout_ms"));
+    }
+}
@@ -0,0 +1,148 @@
+pub mod stats;
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::warn;
+use crate::health::stats::StatsSnapshot;
+use crate::metrics::METRICS;
+use crate::protocol::version::{PROTOCOL_VERSION, WIRE_VERSION};
+
+/// How many times binding the health server is attempted before giving up.
+const MAX_BIND_ATTEMPTS: u32 = 5;
+/// The delay before the first bind retry. Each following retry waits twice as long.
+const INITIAL_BIND_BACKOFF: Duration = Duration::from_millis(500);
+
+/// An identifier for the build, set with the `RELAY_BUILD_ID` environment variable at compile time.
+const BUILD_ID: &str = match option_env!("RELAY_BUILD_ID") {
+    Some(id) => id,
+    None => "unknown",
+};
+
+#[derive(Clone)]
+struct HealthState {
+    stats: watch::Receiver<StatsSnapshot>,
+    started_at: Instant,
+}
+
+/// Build and runtime details served on `/info`.
+#[derive(Serialize)]
+struct Info {
+    version: &'static str,
+    build_id: &'static str,
+    uptime_secs: u64,
+    protocol_version: &'static str,
+    wire_version: u16,
+}
+
+/// Serves the health, info and stats endpoints.
+/// Stats are read from the latest snapshot published by the relay loop.
+pub async fn run_health_server(addr: SocketAddr, stats: watch::Receiver<StatsSnapshot>) -> Result<(), std::io::Error> {
+    let app = router(HealthState {
+        stats,
+        started_at: Instant::now(),
+    });
+
+    let listener = bind_with_retries(addr).await?;
+    axum::serve(listener, app).await
+}
+
+fn router(state: HealthState) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/info", get(get_info))
+        .route("/stats", get(get_stats))
+        .route("/metrics", get(get_metrics))
+        .with_state(state)
+}
+
+/// Binds the health server's listener, retrying with backoff in case the port is briefly busy
+/// (e.g. the previous process is still shutting down).
+/// Returns the last error if every attempt fails.
+async fn bind_with_retries(addr: SocketAddr) -> Result<TcpListener, std::io::Error> {
+    let mut backoff = INITIAL_BIND_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if attempt >= MAX_BIND_ATTEMPTS => return Err(e),
+            Err(e) => {
+                warn!("failed to bind health server to {} (attempt {}/{}), retrying in {:?}: {}", addr, attempt, MAX_BIND_ATTEMPTS, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn health() -> &'static str {
+    "OK"
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+}
+
+async fn get_info(State(state): State<HealthState>) -> Json<Info> {
+    Json(Info {
+        version: env!("CARGO_PKG_VERSION"),
+        build_id: BUILD_ID,
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        protocol_version: PROTOCOL_VERSION,
+        wire_version: WIRE_VERSION,
+    })
+}
+
+async fn get_stats(State(state): State<HealthState>) -> Json<StatsSnapshot> {
+    Json(state.stats.borrow().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct InfoReply {
+        version: String,
+        build_id: String,
+        uptime_secs: u64,
+        protocol_version: String,
+        wire_version: u16,
+    }
+
+    #[tokio::test]
+    async fn info_reports_the_build_and_uptime() {
+        let state = HealthState {
+            stats: watch::channel(StatsSnapshot::default()).1,
+            started_at: Instant::now().checked_sub(Duration::from_secs(90)).unwrap(),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, router(state)).await.unwrap();
+        });
+
+        let info: InfoReply = reqwest::get(format!("http://{addr}/info")).await.unwrap()
+            .json().await.unwrap();
+
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.build_id, BUILD_ID);
+        assert!((90..95).contains(&info.uptime_secs));
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(info.wire_version, WIRE_VERSION);
+    }
+}
@@ -0,0 +1,13 @@
+use std::collections::HashMap;
+use serde::Serialize;
+
+/// A point-in-time view of the relay's state, served on `/stats`.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StatsSnapshot {
+    pub clients: usize,
+    pub apps: usize,
+    pub rooms: usize,
+    /// Room counts keyed by the relay's internal app ID, the same one that appears in logs.
+    /// The app token isn't used since it's a credential.
+    pub rooms_per_app: HashMap<String, usize>,
+}
@@ -0,0 +1,62 @@
+use std::str::FromStr;
+use serde::Deserialize;
+use tracing::Level;
+use tracing_subscriber::FmtSubscriber;
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregators.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format {other}, expected text or json")),
+        }
+    }
+}
+
+/// Reads the logging settings straight from `LOG_FORMAT` and `LOG_LEVEL`.
+/// Used when the config couldn't be loaded, so the error can still be logged the way the operator asked.
+pub fn settings_from_env() -> (LogFormat, String) {
+    let format = std::env::var("LOG_FORMAT")
+        .ok()
+        .and_then(|format| format.parse().ok())
+        .unwrap_or_default();
+    let level = std::env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
+
+    (format, level)
+}
+
+/// Installs the global tracing subscriber.
+/// An unknown level falls back to `info`, and is reported once logging is up.
+pub fn init(format: LogFormat, level: &str) {
+    let (max_level, is_known) = match Level::from_str(level) {
+        Ok(level) => (level, true),
+        Err(_) => (Level::INFO, false),
+    };
+
+    let result = match format {
+        LogFormat::Text => tracing::subscriber::set_global_default(
+            FmtSubscriber::builder().with_max_level(max_level).finish()
+        ),
+        LogFormat::Json => tracing::subscriber::set_global_default(
+            FmtSubscriber::builder().with_max_level(max_level).json().finish()
+        ),
+    };
+    result.expect("setting default subscriber failed");
+
+    if !is_known {
+        tracing::warn!("unknown log level {}, using info", level);
+    }
+}
@@ -8,34 +8,86 @@ use std::error::Error;
 use std::net::{SocketAddr, ToSocketAddrs};
 use tokio::signal;
 use tracing::{error, info};
-use tracing_subscriber::FmtSubscriber;
+use crate::admin::run_admin_server;
+use crate::health::run_health_server;
 use crate::relay::server::RelayServer;
+use crate::udp::{bind, log_addr};
+use crate::udp::bind::SocketOptions;
 use crate::udp::paper_interface::PaperInterface;
 
+mod admin;
 mod config;
+mod health;
+mod logging;
+mod metrics;
 mod udp;
 mod protocol;
 mod relay;
+mod registry;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let subscriber = FmtSubscriber::builder()
-        .with_max_level(tracing::Level::INFO)
-        .finish();
+    dotenvy::dotenv().ok();
 
-    tracing::subscriber::set_global_default(subscriber)
-        .expect("setting default subscriber failed");
+    // Logging is set up from the config, so if that can't be loaded the settings are read from the environment.
+    let config = match config::loader::load_config(config::loader::CONFIG_PATH) {
+        Ok(config) => {
+            logging::init(config.log_format, &config.log_level);
+            config
+        }
+        Err(e) => {
+            let (format, level) = logging::settings_from_env();
+            logging::init(format, &level);
+            error!("failed to load config: {}", e);
+            return Err(e.into());
+        }
+    };
+    log_addr::set_anonymize(config.anonymize_log_addresses);
 
-    dotenvy::dotenv().ok();
-    let config = config::loader::load_config("config.toml")?;
-    let addr: SocketAddr = config.udp_bind_address
-        .to_socket_addrs()?
-        .next()
-        .ok_or("Failed to resolve host name")?;
+    let addrs = config.udp_bind_address.iter()
+        .map(|address| bind::resolve(address, config.udp_address_family))
+        .collect::<Result<Vec<_>, _>>()?;
+    let socket_options = SocketOptions {
+        dual_stack: config.udp_dual_stack,
+        recv_buffer_bytes: config.udp_recv_buffer_bytes,
+        send_buffer_bytes: config.udp_send_buffer_bytes,
+    };
+    let transport = PaperInterface::bind(&addrs, socket_options, config.max_datagrams_per_poll)?;
+    for addr in &addrs {
+        info!("listening on {}", addr);
+    }
+
+    // The health server is auxiliary, so failing to start it shouldn't take the relay down.
+    let health_addr = config.health_bind_address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut addrs| addrs.next());
+
+    // Empty when the admin listener is disabled. Validation already checked it's a loopback address.
+    let admin_addr = config.admin_bind_address.parse::<SocketAddr>().ok();
 
-    let transport = PaperInterface::new(addr).await?;
+    let mut server = RelayServer::new(transport, config)?;
+
+    if let Some(health_addr) = health_addr {
+        let stats = server.stats();
+        tokio::spawn(async move {
+            if let Err(e) = run_health_server(health_addr, stats).await {
+                error!("health server stopped, continuing without it: {}", e);
+            }
+        });
+    } else {
+        error!("failed to resolve health bind address, continuing without the health server");
+    }
+
+    if let Some(admin_addr) = admin_addr {
+        let commands = server.admin();
+        tokio::spawn(async move {
+            if let Err(e) = run_admin_server(admin_addr, commands).await {
+                error!("admin listener stopped, continuing without it: {}", e);
+            }
+        });
+    }
 
-    let mut server = RelayServer::new(transport, config);
     info!("relay server started");
     tokio::select! {
         res = server.run() => {
@@ -0,0 +1,194 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::udp::common::{DisconnectReason, TransferChannel};
+
+/// Global relay metrics, rendered in the Prometheus text format on `/metrics`.
+pub static METRICS: Metrics = Metrics::new();
+
+/// Counters and gauges updated from the hot path.
+/// Everything is a relaxed atomic so recording never takes a lock.
+pub struct Metrics {
+    packets_received_reliable: AtomicU64,
+    packets_received_unreliable: AtomicU64,
+    packets_received_sequenced: AtomicU64,
+    packets_sent_reliable: AtomicU64,
+    packets_sent_unreliable: AtomicU64,
+    packets_sent_sequenced: AtomicU64,
+    resends: AtomicU64,
+    game_data_outside_room: AtomicU64,
+    game_data_throttled: AtomicU64,
+    handler_errors: AtomicU64,
+    saturated_disconnects: AtomicU64,
+    disconnects_timeout: AtomicU64,
+    disconnects_graceful: AtomicU64,
+    disconnects_protocol_error: AtomicU64,
+    disconnects_kicked: AtomicU64,
+    disconnects_saturated: AtomicU64,
+    disconnects_server_shutdown: AtomicU64,
+    unacked_reliable_bytes: AtomicU64,
+    active_rooms: AtomicU64,
+    active_clients: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            packets_received_reliable: AtomicU64::new(0),
+            packets_received_unreliable: AtomicU64::new(0),
+            packets_received_sequenced: AtomicU64::new(0),
+            packets_sent_reliable: AtomicU64::new(0),
+            packets_sent_unreliable: AtomicU64::new(0),
+            packets_sent_sequenced: AtomicU64::new(0),
+            resends: AtomicU64::new(0),
+            game_data_outside_room: AtomicU64::new(0),
+            game_data_throttled: AtomicU64::new(0),
+            handler_errors: AtomicU64::new(0),
+            saturated_disconnects: AtomicU64::new(0),
+            disconnects_timeout: AtomicU64::new(0),
+            disconnects_graceful: AtomicU64::new(0),
+            disconnects_protocol_error: AtomicU64::new(0),
+            disconnects_kicked: AtomicU64::new(0),
+            disconnects_saturated: AtomicU64::new(0),
+            disconnects_server_shutdown: AtomicU64::new(0),
+            unacked_reliable_bytes: AtomicU64::new(0),
+            active_rooms: AtomicU64::new(0),
+            active_clients: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record_received(&self, channel: TransferChannel) {
+        match channel {
+            TransferChannel::Reliable => self.packets_received_reliable.fetch_add(1, Ordering::Relaxed),
+            TransferChannel::Unreliable => self.packets_received_unreliable.fetch_add(1, Ordering::Relaxed),
+            TransferChannel::UnreliableSequenced => self.packets_received_sequenced.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn record_sent(&self, channel: TransferChannel) {
+        match channel {
+            TransferChannel::Reliable => self.packets_sent_reliable.fetch_add(1, Ordering::Relaxed),
+            TransferChannel::Unreliable => self.packets_sent_unreliable.fetch_add(1, Ordering::Relaxed),
+            TransferChannel::UnreliableSequenced => self.packets_sent_sequenced.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn record_resend(&self) {
+        self.resends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_game_data_outside_room(&self) {
+        self.game_data_outside_room.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_game_data_throttled(&self) {
+        self.game_data_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_handler_error(&self) {
+        self.handler_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_saturated_disconnect(&self) {
+        self.saturated_disconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_disconnect(&self, reason: DisconnectReason) {
+        match reason {
+            DisconnectReason::Timeout => self.disconnects_timeout.fetch_add(1, Ordering::Relaxed),
+            DisconnectReason::Graceful => self.disconnects_graceful.fetch_add(1, Ordering::Relaxed),
+            DisconnectReason::ProtocolError => self.disconnects_protocol_error.fetch_add(1, Ordering::Relaxed),
+            DisconnectReason::Kicked => self.disconnects_kicked.fetch_add(1, Ordering::Relaxed),
+            DisconnectReason::Saturated => self.disconnects_saturated.fetch_add(1, Ordering::Relaxed),
+            DisconnectReason::ServerShutdown => self.disconnects_server_shutdown.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub fn set_unacked_reliable_bytes(&self, bytes: u64) {
+        self.unacked_reliable_bytes.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn room_opened(&self) {
+        self.active_rooms.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn room_closed(&self) {
+        decrement(&self.active_rooms);
+    }
+
+    pub fn client_connected(&self) {
+        self.active_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        decrement(&self.active_clients);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_metric(&mut out, "relay_packets_received_total", "counter", "Packets received from clients.", &[
+            ("channel=\"reliable\"", &self.packets_received_reliable),
+            ("channel=\"unreliable\"", &self.packets_received_unreliable),
+            ("channel=\"unreliable_sequenced\"", &self.packets_received_sequenced),
+        ]);
+        write_metric(&mut out, "relay_packets_sent_total", "counter", "Packets sent to clients.", &[
+            ("channel=\"reliable\"", &self.packets_sent_reliable),
+            ("channel=\"unreliable\"", &self.packets_sent_unreliable),
+            ("channel=\"unreliable_sequenced\"", &self.packets_sent_sequenced),
+        ]);
+        write_metric(&mut out, "relay_resends_total", "counter", "Reliable packets resent after going unacknowledged.", &[
+            ("", &self.resends),
+        ]);
+        write_metric(&mut out, "relay_game_data_outside_room_total", "counter", "GameData packets sent by clients that aren't in a room.", &[
+            ("", &self.game_data_outside_room),
+        ]);
+        write_metric(&mut out, "relay_game_data_throttled_total", "counter", "GameData packets dropped because the sender went over its bandwidth limit.", &[
+            ("", &self.game_data_throttled),
+        ]);
+        write_metric(&mut out, "relay_handler_errors_total", "counter", "Packets that couldn't be handled because the relay's state was inconsistent.", &[
+            ("", &self.handler_errors),
+        ]);
+        write_metric(&mut out, "relay_saturated_disconnects_total", "counter", "Clients disconnected for leaving too much reliable data unacknowledged.", &[
+            ("", &self.saturated_disconnects),
+        ]);
+        write_metric(&mut out, "relay_disconnects_total", "counter", "Clients disconnected, by why they left.", &[
+            ("reason=\"timeout\"", &self.disconnects_timeout),
+            ("reason=\"graceful\"", &self.disconnects_graceful),
+            ("reason=\"protocol_error\"", &self.disconnects_protocol_error),
+            ("reason=\"kicked\"", &self.disconnects_kicked),
+            ("reason=\"saturated\"", &self.disconnects_saturated),
+            ("reason=\"server_shutdown\"", &self.disconnects_server_shutdown),
+        ]);
+        write_metric(&mut out, "relay_unacked_reliable_bytes", "gauge", "Reliable data that went unacknowledged for a whole resend interval, as of the last resend, in bytes.", &[
+            ("", &self.unacked_reliable_bytes),
+        ]);
+        write_metric(&mut out, "relay_active_rooms", "gauge", "Rooms currently open.", &[
+            ("", &self.active_rooms),
+        ]);
+        write_metric(&mut out, "relay_active_clients", "gauge", "Client sessions currently open.", &[
+            ("", &self.active_clients),
+        ]);
+
+        out
+    }
+}
+
+fn decrement(value: &AtomicU64) {
+    // Never wrap below zero, even if a decrement is somehow doubled up.
+    let _ = value.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| Some(v.saturating_sub(1)));
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, values: &[(&str, &AtomicU64)]) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+
+    for (labels, value) in values {
+        let value = value.load(Ordering::Relaxed);
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name} {value}");
+        } else {
+            let _ = writeln!(out, "{name}{{{labels}}} {value}");
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::protocol::error_code::ErrorCode;
+use crate::protocol::packet::Packet;
+use crate::protocol::serialize::MAX_STRING_LEN;
+use crate::udp::common::TransferChannel;
+
+/// Builds outgoing packets along with the channel they should be sent on.
+/// Strings are clamped to the limits the wire reader enforces, so a built
+/// packet is always one a client can decode.
+///
+/// Packets are sent reliably unless told otherwise.
+pub struct PacketBuilder {
+    packet: Packet,
+    channel: TransferChannel,
+}
+
+impl PacketBuilder {
+    fn new(packet: Packet) -> Self {
+        Self {
+            packet,
+            channel: TransferChannel::Reliable,
+        }
+    }
+
+    pub fn game_data(from_peer: i32, data: &[u8]) -> Self {
+        Self::new(Packet::GameData {
+            from_peer,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn error(error_code: ErrorCode, message: &str) -> Self {
+        Self::new(Packet::Error {
+            error_code: error_code.as_i32(),
+            error_message: clamp_string(message),
+        })
+    }
+
+    pub fn ping(nonce: u64) -> Self {
+        Self::new(Packet::Ping { nonce })
+    }
+
+    pub fn pong(nonce: u64) -> Self {
+        Self::new(Packet::Pong { nonce })
+    }
+
+    pub fn reliable(self) -> Self {
+        self.channel(TransferChannel::Reliable)
+    }
+
+    pub fn unreliable(self) -> Self {
+        self.channel(TransferChannel::Unreliable)
+    }
+
+    pub fn channel(mut self, channel: TransferChannel) -> Self {
+        self.channel = channel;
+        self
+    }
+
+    pub fn build(self) -> (Packet, TransferChannel) {
+        (self.packet, self.channel)
+    }
+}
+
+/// Cuts a string down to `MAX_STRING_LEN` bytes without splitting a character.
+fn clamp_string(value: &str) -> String {
+    let max = MAX_STRING_LEN as usize;
+    if value.len() <= max {
+        return value.to_string();
+    }
+
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    value[..end].to_string()
+}
@@ -18,5 +18,11 @@ pub enum ProtocolError {
     InvalidUtf8String(#[from] std::string::FromUtf8Error),
 
     #[error("Negative vector length")]
-    NegativeVectorLength()
+    NegativeVectorLength(),
+
+    #[error("String too long: {0} bytes")]
+    StringTooLong(i32),
+
+    #[error("Vector too long: {0} elements")]
+    VectorTooLong(i32),
 }
\ No newline at end of file
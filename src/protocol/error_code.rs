@@ -0,0 +1,30 @@
+/// The codes sent in `Packet::Error`, so clients can react to a failure without parsing the message.
+/// They follow the HTTP status code with the closest meaning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The packet can't be handled as sent, e.g. a control packet on the unreliable channel.
+    BadRequest = 400,
+    /// Authentication failed: the client's version or app isn't allowed.
+    Unauthorized = 401,
+    /// The client isn't allowed to do this, e.g. a peer acting as the host.
+    Forbidden = 403,
+    NotFound = 404,
+    /// The client waited too long for something, e.g. a host's answer to a join request.
+    Timeout = 408,
+    /// The request doesn't fit the current state, e.g. joining a full room.
+    Conflict = 409,
+    /// Something the client refers to no longer exists, e.g. an expired reconnect token.
+    Gone = 410,
+    TooLarge = 413,
+    /// The room is locked by its host.
+    Locked = 423,
+    RateLimited = 429,
+    /// The relay, or the room asked for, can't take anyone right now. Clients should try again later.
+    ServerFull = 503,
+}
+
+impl ErrorCode {
+    pub fn as_i32(self) -> i32 {
+        self as i32
+    }
+}
@@ -12,4 +12,34 @@ pub const REQ_ROOMS: u8 = 10;
 pub const GET_ROOMS: u8 = 11;
 pub const UPDATE_ROOM: u8 = 12;
 pub const JOIN_RES: u8 = 13;
-pub const PEER_JOIN_ATTEMPT: u8 = 14;
\ No newline at end of file
+pub const PEER_JOIN_ATTEMPT: u8 = 14;
+pub const BECAME_HOST: u8 = 15;
+pub const HOST_CHANGED: u8 = 16;
+pub const PING: u8 = 17;
+pub const PONG: u8 = 18;
+pub const REDIRECT: u8 = 19;
+pub const REQ_VERSION_INFO: u8 = 20;
+pub const VERSION_INFO: u8 = 21;
+pub const SET_ROOM_ALLOWLIST: u8 = 22;
+pub const ROOM_CREATED: u8 = 23;
+pub const DISCONNECT: u8 = 24;
+pub const GAME_DATA_AUTO: u8 = 25;
+pub const RECONNECT: u8 = 26;
+pub const SET_ROOM_LOCKED: u8 = 27;
+pub const REQ_HOST: u8 = 28;
+pub const HOST_INFO: u8 = 29;
+pub const ROOM_SNAPSHOT: u8 = 30;
+pub const REQ_ROSTER: u8 = 31;
+pub const ROSTER: u8 = 32;
+pub const HEARTBEAT: u8 = 33;
+/// What clients from before wire version 7 send as a heartbeat. A lone byte can't be a
+/// complete `JOIN_ROOM`, so it's read as a `HEARTBEAT` instead.
+pub const LEGACY_HEARTBEAT: &[u8] = &[JOIN_ROOM];
+pub const SPECTATOR_JOINED: u8 = 34;
+pub const SPECTATOR_LEFT: u8 = 35;
+pub const CONNECT: u8 = 36;
+pub const CONNECT_ACCEPTED: u8 = 37;
+pub const TRANSFER_HOST: u8 = 38;
+/// Wraps a packet sent on the unreliable-sequenced channel: a u32 sequence number, then the packet itself.
+/// It's unwrapped by the transport, so `Packet` never sees it.
+pub const SEQUENCED: u8 = 39;
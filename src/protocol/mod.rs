@@ -1,5 +1,7 @@
 mod ids;
+pub mod builder;
 pub mod packet;
 mod serialize;
 pub mod version;
-pub mod error;
\ No newline at end of file
+pub mod error;
+pub mod error_code;
\ No newline at end of file
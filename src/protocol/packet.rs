@@ -1,80 +1,246 @@
+use std::collections::HashMap;
 use crate::protocol::ids::*;
 use crate::protocol::error::ProtocolError;
-use crate::protocol::serialize::{push_bool, push_i32, push_string, push_u64, push_vec_room_info, read_bool, read_i32, read_string, read_u64, read_vec_room_info};
+use crate::protocol::serialize::{push_bool, push_i32, push_string, push_string_map, push_u16, push_u32, push_u64, push_vec_i32, push_vec_peer, push_vec_room_info, push_vec_string, read_bool, read_i32, read_optional, read_string, read_string_map, read_u16, read_u32, read_u64, read_vec_i32, read_vec_peer, read_vec_room_info, read_vec_string, string_map_len};
 
-#[derive(Debug, Clone)]
+/// The `GameData` target that sends to everyone else in the room, spectators included.
+/// Matches Godot's `TARGET_PEER_BROADCAST`. Relays before wire version 8 dropped game data sent to it.
+pub const BROADCAST_PEER: i32 = 0;
+
+/// A room's metadata, as key-value pairs set by its host.
+pub type RoomMetadata = HashMap<String, String>;
+
+/// The number of bytes room metadata takes up when serialized.
+pub fn metadata_len(metadata: &RoomMetadata) -> usize {
+    string_map_len(metadata)
+}
+
+/// The shortest a `Connect` without a valid cookie can be. It's padded out with zeros in `cookie`.
+/// The relay's reply is never longer, so a spoofed `Connect` can't be used to amplify traffic.
+pub const MIN_CONNECT_LEN: usize = 64;
+/// How many zeros the first `Connect` carries in place of a cookie, bringing it up to `MIN_CONNECT_LEN`.
+pub const CONNECT_PADDING: usize = MIN_CONNECT_LEN - 3;
+
+/// Returns the cookie from a `Connect` packet, without decoding the rest of it.
+/// Returns `None` if the bytes don't hold a `Connect`.
+pub fn connect_cookie(bytes: &[u8]) -> Option<&[u8]> {
+    match bytes {
+        [CONNECT, _, _, cookie @ ..] => Some(cookie),
+        _ => None,
+    }
+}
+
+/// Wraps a packet for the unreliable-sequenced channel, in a `SEQUENCED` header with its sequence number.
+pub fn wrap_sequenced(seq: u32, packet: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + 4 + packet.len());
+    buf.push(SEQUENCED);
+    push_u32(&mut buf, seq);
+    buf.extend(packet);
+    buf
+}
+
+/// Splits a payload from the unreliable-sequenced channel into its sequence number and packet.
+/// Returns `None` if the payload wasn't sent on that channel, or an error if its header is cut short.
+pub fn unwrap_sequenced(bytes: &[u8]) -> Option<Result<(u32, &[u8]), ProtocolError>> {
+    if bytes.first() != Some(&SEQUENCED) {
+        return None;
+    }
+
+    Some(read_u32(&bytes[1..]))
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct RoomInfo {
     pub join_code: String,
-    pub metadata: String,
+    pub metadata: RoomMetadata,
+    pub player_count: i32,
+    /// 0 means the room has no player limit.
+    pub max_players: i32,
+    /// Locked rooms are listed, but can't be joined.
+    pub locked: bool,
 }
 
-#[derive(Debug, Clone)]
+impl RoomInfo {
+    /// The number of bytes this room takes up when serialized.
+    pub fn encoded_len(&self) -> usize {
+        4 + self.join_code.len() + metadata_len(&self.metadata) + 4 + 4 + 4
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Packet {
-    Authenticate { app_id: String, version: String },
-    ClientAuthenticated,
-    CreateRoom { is_public: bool, metadata: String },
-    ReqRooms,
-    GetRooms { rooms: Vec<RoomInfo> },
-    UpdateRoom { room_id: String, metadata: String },
-    ReqJoin { room_id: String, metadata: String },
+    /// Must be the first packet a client sends. Datagrams from unknown addresses that
+    /// don't start with one are dropped without opening a session.
+    /// `protocol_version` is the client's `WIRE_VERSION`.
+    /// The first `Connect` has no `cookie` and should be sent unreliably, since the relay keeps no state for it.
+    /// Its `cookie` has to be `CONNECT_PADDING` zeros instead, so it's no shorter than the reply.
+    /// The session only opens once the client sends it again, reliably, with the cookie from `ConnectAccepted`.
+    Connect { protocol_version: u16, cookie: Vec<u8> },
+    /// The reply to `Connect`. To a `Connect` without a cookie, `client_id` is 0 and `cookie` is what the
+    /// client has to echo back, proving it can receive at its address. Once the session is open,
+    /// `client_id` is the ID the relay knows the client by and `cookie` is empty.
+    ConnectAccepted { client_id: u64, cookie: Vec<u8> },
+    /// `resume_token` is the one from the client's last `ClientAuthenticated`, or empty if it has none.
+    /// It's needed to take over a previous session with the same `stable_id`.
+    Authenticate { app_id: String, version: String, stable_id: String, resume_token: String },
+    /// `capabilities` is a set of `CAP_*` flags from `protocol::version`.
+    /// `resume_token` is a secret for resuming the session later, empty if the client sent no `stable_id`.
+    ClientAuthenticated { capabilities: u32, resume_token: String },
+    CreateRoom { is_public: bool, metadata: RoomMetadata, max_players: i32, max_join_rtt_ms: u32 },
+    /// `filter` is matched against each room's metadata: `key=value` matches rooms with that entry,
+    /// and a bare `key` matches rooms that have the key at all. An empty filter matches every room.
+    ReqRooms { stream: bool, offset: u32, limit: u32, filter: String },
+    /// Room lists too big for one packet are split into pages numbered from 0.
+    /// `last` is set on the final page, and clients concatenate the pages until they see it.
+    GetRooms { rooms: Vec<RoomInfo>, total: u32, page: u32, last: bool },
+    UpdateRoom { room_id: String, metadata: RoomMetadata },
+    /// Spectators watch the room without taking a player slot. See `SpectatorJoined`.
+    ReqJoin { room_id: String, metadata: String, spectator: bool },
     JoinRes { target_id: u64, room_id: String, allowed: bool },
-    ConnectedToRoom { room_id: String, peer_id: i32 },
-    PeerJoinAttempt { target_id: u64, metadata: String },
+    /// `room_id` is the relay ID followed by the join code, the same in `RoomCreated` and `ConnectedToRoom`.
+    RoomCreated { room_id: String, join_code: String, peer_id: i32 },
+    /// `reconnect_token` can be sent in a `Reconnect` to get this slot back after dropping out.
+    ConnectedToRoom { room_id: String, peer_id: i32, existing_peers: Vec<i32>, reconnect_token: String },
+    PeerJoinAttempt { target_id: u64, metadata: String, spectator: bool },
     PeerJoinedRoom { peer_id: i32 },
     PeerLeftRoom { peer_id: i32 },
+    /// Clients put the target in `from_peer`, and the relay swaps in the sender on the way out.
+    /// A target of `BROADCAST_PEER` sends to everyone else in the room, spectators included.
     GameData { from_peer: i32, data: Vec<u8> },
+    /// Game data sent on whichever channel the app defaults to.
+    GameDataAuto { from_peer: i32, data: Vec<u8> },
     ForceDisconnect,
+    Disconnect,
+    /// Sent unreliably by both sides to keep an idle session (and the NAT mapping in front of it) alive.
+    Heartbeat,
+    BecameHost,
+    HostChanged { peer_id: i32 },
+    ReqHost,
+    /// Sent by the host to hand the room to another peer without leaving.
+    /// The new host gets `BecameHost` and everyone else `HostChanged`.
+    TransferHost { peer_id: i32 },
+    HostInfo { peer_id: i32 },
+    Ping { nonce: u64 },
+    Pong { nonce: u64 },
+    Redirect { address: String },
+    Reconnect { token: String },
+    /// The full state of a room, sent to a client that reconnected into it.
+    /// `peers` holds every peer's Godot ID and the metadata it joined with.
+    RoomSnapshot { peers: Vec<(i32, String)>, host: i32, metadata: RoomMetadata },
+    ReqRoster,
+    /// The Godot IDs of everyone in the room, the host included.
+    Roster { peers: Vec<i32>, host_peer_id: i32 },
+    /// Tells the host a spectator joined. Spectators get broadcast game data,
+    /// but can't send any and aren't counted as players.
+    SpectatorJoined { peer_id: i32 },
+    SpectatorLeft { peer_id: i32 },
+    ReqVersionInfo,
+    VersionInfo { allowed_versions: Vec<String>, protocol_version: u16 },
+    SetRoomAllowlist { ids: Vec<String> },
+    SetRoomLocked { locked: bool },
     Error { error_code: i32, error_message: String }
 }
 
 impl Packet {
+    /// Returns true for packets that drive authentication and room management.
+    /// These must not be lost, so they're expected on the reliable channel.
+    pub fn is_control(&self) -> bool {
+        matches!(
+            self,
+            Packet::Connect { .. }
+                | Packet::Authenticate { .. }
+                | Packet::CreateRoom { .. }
+                | Packet::ReqRooms { .. }
+                | Packet::UpdateRoom { .. }
+                | Packet::ReqJoin { .. }
+                | Packet::JoinRes { .. }
+                | Packet::SetRoomAllowlist { .. }
+                | Packet::SetRoomLocked { .. }
+                | Packet::Reconnect { .. }
+                | Packet::TransferHost { .. }
+        )
+    }
+
     pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
         if bytes.is_empty() {
             return Err(ProtocolError::EmptyPacket);
         }
 
+        if bytes == LEGACY_HEARTBEAT {
+            return Ok(Packet::Heartbeat);
+        }
+
         let packet_id = bytes[0];
         let rest = &bytes[1..];
 
         Ok(match packet_id {
+            CONNECT => {
+                let (protocol_version, r) = read_u16(rest)?;
+                Packet::Connect { protocol_version, cookie: r.to_vec() }
+            }
+
+            CONNECT_ACCEPTED => {
+                let (client_id, r) = read_u64(rest)?;
+                Packet::ConnectAccepted { client_id, cookie: r.to_vec() }
+            }
+
             AUTHENTICATE => {
                 let (app_id, r) = read_string(rest)?;
-                let (version, _) = read_string(r)?;
-                Packet::Authenticate { app_id, version }
+                let (version, r) = read_string(r)?;
+                // Older clients don't send a stable ID or resume token
+                let (stable_id, r) = read_optional(r, String::new(), read_string)?;
+                let (resume_token, _) = read_optional(r, String::new(), read_string)?;
+                Packet::Authenticate { app_id, version, stable_id, resume_token }
             }
 
-            CLIENT_AUTHENTICATED => Packet::ClientAuthenticated,
+            CLIENT_AUTHENTICATED => {
+                // Older relays don't send any capabilities.
+                let (capabilities, r) = read_optional(rest, 0, read_u32)?;
+                let (resume_token, _) = read_optional(r, String::new(), read_string)?;
+                Packet::ClientAuthenticated { capabilities, resume_token }
+            }
 
             CREATE_ROOM => {
                 let (is_public, r) = read_bool(rest)?;
-                let metadata = match read_string(r) {
-                    Ok((name, _)) => {
-                        name
-                    }
-                    Err(_) => {
-                        String::new()
-                    }
-                };
-
-                Packet::CreateRoom { is_public, metadata }
+                // Before wire version 10 metadata was a string, which clients always sent. An empty string
+                // reads as an empty map, but anything else fails to decode, which is what the version bump is for.
+                // Older clients may also leave out the player or round-trip time limit.
+                // A limit of 0 means the room has no limit.
+                let (metadata, r) = read_optional(r, RoomMetadata::new(), read_string_map)?;
+                let (max_players, r) = read_optional(r, 0, read_i32)?;
+                let (max_join_rtt_ms, _) = read_optional(r, 0, read_u32)?;
+
+                Packet::CreateRoom { is_public, metadata, max_players, max_join_rtt_ms }
             },
 
             JOIN_ROOM => {
                 let (room_id, r) = read_string(rest)?;
-                let (metadata, _) = read_string(r)?;
-                Packet::ReqJoin { room_id, metadata }
+                let (metadata, r) = read_string(r)?;
+                // Older clients can't ask to spectate.
+                let (spectator, _) = read_optional(r, false, read_bool)?;
+                Packet::ReqJoin { room_id, metadata, spectator }
             }
 
-            CONNECTED_TO_ROOM => {
+            ROOM_CREATED => {
                 let (room_id, r) = read_string(rest)?;
+                let (join_code, r) = read_string(r)?;
                 let (peer_id, _) = read_i32(r)?;
-                Packet::ConnectedToRoom { room_id, peer_id }
+                Packet::RoomCreated { room_id, join_code, peer_id }
+            }
+
+            CONNECTED_TO_ROOM => {
+                let (room_id, r) = read_string(rest)?;
+                let (peer_id, r) = read_i32(r)?;
+                let (existing_peers, r) = read_vec_i32(r)?;
+                let (reconnect_token, _) = read_string(r)?;
+                Packet::ConnectedToRoom { room_id, peer_id, existing_peers, reconnect_token }
             }
 
             PEER_JOIN_ATTEMPT => {
                 let (target_id, r) = read_u64(rest)?;
-                let (metadata, _) = read_string(r)?;
-                Packet::PeerJoinAttempt { target_id, metadata }
+                let (metadata, r) = read_string(r)?;
+                let (spectator, _) = read_optional(r, false, read_bool)?;
+                Packet::PeerJoinAttempt { target_id, metadata, spectator }
             }
 
             PEER_JOINED => {
@@ -92,24 +258,127 @@ impl Packet {
                 Packet::GameData { from_peer: peer_id, data: r.to_vec() }
             }
 
+            GAME_DATA_AUTO => {
+                let (peer_id, r) = read_i32(rest)?;
+                Packet::GameDataAuto { from_peer: peer_id, data: r.to_vec() }
+            }
+
             FORCE_DISCONNECT => Packet::ForceDisconnect,
 
+            DISCONNECT => Packet::Disconnect,
+
+            HEARTBEAT => Packet::Heartbeat,
+
+            BECAME_HOST => Packet::BecameHost,
+
+            TRANSFER_HOST => {
+                let (peer_id, _) = read_i32(rest)?;
+                Packet::TransferHost { peer_id }
+            }
+
+            HOST_CHANGED => {
+                let (peer_id, _) = read_i32(rest)?;
+                Packet::HostChanged { peer_id }
+            }
+
+            REQ_HOST => Packet::ReqHost,
+
+            HOST_INFO => {
+                let (peer_id, _) = read_i32(rest)?;
+                Packet::HostInfo { peer_id }
+            }
+
+            PING => {
+                let (nonce, _) = read_u64(rest)?;
+                Packet::Ping { nonce }
+            }
+
+            PONG => {
+                let (nonce, _) = read_u64(rest)?;
+                Packet::Pong { nonce }
+            }
+
+            REDIRECT => {
+                let (address, _) = read_string(rest)?;
+                Packet::Redirect { address }
+            }
+
+            RECONNECT => {
+                let (token, _) = read_string(rest)?;
+                Packet::Reconnect { token }
+            }
+
+            ROOM_SNAPSHOT => {
+                let (peers, r) = read_vec_peer(rest)?;
+                let (host, r) = read_i32(r)?;
+                let (metadata, _) = read_string_map(r)?;
+                Packet::RoomSnapshot { peers, host, metadata }
+            }
+
+            REQ_ROSTER => Packet::ReqRoster,
+
+            ROSTER => {
+                let (peers, r) = read_vec_i32(rest)?;
+                let (host_peer_id, _) = read_i32(r)?;
+                Packet::Roster { peers, host_peer_id }
+            }
+
+            SPECTATOR_JOINED => {
+                let (peer_id, _) = read_i32(rest)?;
+                Packet::SpectatorJoined { peer_id }
+            }
+
+            SPECTATOR_LEFT => {
+                let (peer_id, _) = read_i32(rest)?;
+                Packet::SpectatorLeft { peer_id }
+            }
+
+            REQ_VERSION_INFO => Packet::ReqVersionInfo,
+
+            VERSION_INFO => {
+                let (allowed_versions, r) = read_vec_string(rest)?;
+                let (protocol_version, _) = read_u16(r)?;
+                Packet::VersionInfo { allowed_versions, protocol_version }
+            }
+
+            SET_ROOM_ALLOWLIST => {
+                let (ids, _) = read_vec_string(rest)?;
+                Packet::SetRoomAllowlist { ids }
+            }
+
+            SET_ROOM_LOCKED => {
+                let (locked, _) = read_bool(rest)?;
+                Packet::SetRoomLocked { locked }
+            }
+
             ERROR_PACKET => {
                 let (error_code, r) = read_i32(rest)?;
                 let (error_message, _) = read_string(r)?;
                 Packet::Error { error_code, error_message }
             }
 
-            REQ_ROOMS => Packet::ReqRooms,
+            REQ_ROOMS => {
+                // Older clients don't send the stream flag or paging.
+                // A limit of 0 means "as many as the server allows".
+                let (stream, r) = read_optional(rest, false, read_bool)?;
+                let (offset, r) = read_optional(r, 0, read_u32)?;
+                let (limit, r) = read_optional(r, 0, read_u32)?;
+                let (filter, _) = read_optional(r, String::new(), read_string)?;
+                Packet::ReqRooms { stream, offset, limit, filter }
+            }
 
             GET_ROOMS => {
-                let (rooms, _) = read_vec_room_info(rest)?;
-                Packet::GetRooms { rooms }
+                let (rooms, r) = read_vec_room_info(rest)?;
+                let (total, r) = read_u32(r)?;
+                // Older relays always send the whole list in one packet.
+                let (page, r) = read_optional(r, 0, read_u32)?;
+                let (last, _) = read_optional(r, true, read_bool)?;
+                Packet::GetRooms { rooms, total, page, last }
             }
 
             UPDATE_ROOM => {
                 let (room_id, r) = read_string(rest)?;
-                let (metadata, _) = read_string(r)?;
+                let (metadata, _) = read_string_map(r)?;
                 Packet::UpdateRoom { room_id, metadata }
             }
 
@@ -128,41 +397,67 @@ impl Packet {
         let mut buf = Vec::new();
 
         match self {
-            Packet::Authenticate { app_id, version } => {
+            Packet::Connect { protocol_version, cookie } => {
+                buf.push(CONNECT);
+                push_u16(&mut buf, *protocol_version);
+                buf.extend(cookie);
+            }
+
+            Packet::ConnectAccepted { client_id, cookie } => {
+                buf.push(CONNECT_ACCEPTED);
+                push_u64(&mut buf, *client_id);
+                buf.extend(cookie);
+            }
+
+            Packet::Authenticate { app_id, version, stable_id, resume_token } => {
                 buf.push(AUTHENTICATE);
                 push_string(&mut buf, app_id);
                 push_string(&mut buf, version);
+                push_string(&mut buf, stable_id);
+                push_string(&mut buf, resume_token);
             }
 
-            Packet::ClientAuthenticated => {
+            Packet::ClientAuthenticated { capabilities, resume_token } => {
                 buf.push(CLIENT_AUTHENTICATED);
+                push_u32(&mut buf, *capabilities);
+                push_string(&mut buf, resume_token);
             }
 
-            Packet::CreateRoom { is_public, metadata } => {
+            Packet::CreateRoom { is_public, metadata, max_players, max_join_rtt_ms } => {
                 buf.push(CREATE_ROOM);
                 push_bool(&mut buf, *is_public);
-                push_string(&mut buf, metadata);
+                push_string_map(&mut buf, metadata);
+                push_i32(&mut buf, *max_players);
+                push_u32(&mut buf, *max_join_rtt_ms);
             }
 
-            Packet::ReqRooms => {
+            Packet::ReqRooms { stream, offset, limit, filter } => {
                 buf.push(REQ_ROOMS);
+                push_bool(&mut buf, *stream);
+                push_u32(&mut buf, *offset);
+                push_u32(&mut buf, *limit);
+                push_string(&mut buf, filter);
             }
 
-            Packet::GetRooms { rooms } => {
+            Packet::GetRooms { rooms, total, page, last } => {
                 buf.push(GET_ROOMS);
                 push_vec_room_info(&mut buf, rooms);
+                push_u32(&mut buf, *total);
+                push_u32(&mut buf, *page);
+                push_bool(&mut buf, *last);
             }
 
             Packet::UpdateRoom { room_id, metadata } => {
                 buf.push(UPDATE_ROOM);
                 push_string(&mut buf, room_id);
-                push_string(&mut buf, metadata);
+                push_string_map(&mut buf, metadata);
             }
 
-            Packet::ReqJoin { room_id, metadata } => {
+            Packet::ReqJoin { room_id, metadata, spectator } => {
                 buf.push(JOIN_ROOM);
                 push_string(&mut buf, room_id);
                 push_string(&mut buf, metadata);
+                push_bool(&mut buf, *spectator);
             }
 
             Packet::JoinRes { target_id, room_id, allowed } => {
@@ -172,16 +467,26 @@ impl Packet {
                 push_bool(&mut buf, *allowed);
             }
 
-            Packet::ConnectedToRoom { room_id, peer_id } => {
+            Packet::RoomCreated { room_id, join_code, peer_id } => {
+                buf.push(ROOM_CREATED);
+                push_string(&mut buf, room_id);
+                push_string(&mut buf, join_code);
+                push_i32(&mut buf, *peer_id);
+            }
+
+            Packet::ConnectedToRoom { room_id, peer_id, existing_peers, reconnect_token } => {
                 buf.push(CONNECTED_TO_ROOM);
                 push_string(&mut buf, room_id);
                 push_i32(&mut buf, *peer_id);
+                push_vec_i32(&mut buf, existing_peers);
+                push_string(&mut buf, reconnect_token);
             }
 
-            Packet::PeerJoinAttempt { target_id, metadata } => {
+            Packet::PeerJoinAttempt { target_id, metadata, spectator } => {
                 buf.push(PEER_JOIN_ATTEMPT);
                 push_u64(&mut buf, *target_id);
                 push_string(&mut buf, metadata);
+                push_bool(&mut buf, *spectator);
             }
 
             Packet::PeerJoinedRoom { peer_id } => {
@@ -200,10 +505,114 @@ impl Packet {
                 buf.extend(data);
             }
 
+            Packet::GameDataAuto { from_peer: peer_id, data } => {
+                buf.push(GAME_DATA_AUTO);
+                push_i32(&mut buf, *peer_id);
+                buf.extend(data);
+            }
+
             Packet::ForceDisconnect => {
                 buf.push(FORCE_DISCONNECT);
             }
 
+            Packet::Disconnect => {
+                buf.push(DISCONNECT);
+            }
+
+            Packet::Heartbeat => {
+                buf.push(HEARTBEAT);
+            }
+
+            Packet::TransferHost { peer_id } => {
+                buf.push(TRANSFER_HOST);
+                push_i32(&mut buf, *peer_id);
+            }
+
+            Packet::BecameHost => {
+                buf.push(BECAME_HOST);
+            }
+
+            Packet::HostChanged { peer_id } => {
+                buf.push(HOST_CHANGED);
+                push_i32(&mut buf, *peer_id);
+            }
+
+            Packet::ReqHost => {
+                buf.push(REQ_HOST);
+            }
+
+            Packet::HostInfo { peer_id } => {
+                buf.push(HOST_INFO);
+                push_i32(&mut buf, *peer_id);
+            }
+
+            Packet::Ping { nonce } => {
+                buf.push(PING);
+                push_u64(&mut buf, *nonce);
+            }
+
+            Packet::Pong { nonce } => {
+                buf.push(PONG);
+                push_u64(&mut buf, *nonce);
+            }
+
+            Packet::Redirect { address } => {
+                buf.push(REDIRECT);
+                push_string(&mut buf, address);
+            }
+
+            Packet::Reconnect { token } => {
+                buf.push(RECONNECT);
+                push_string(&mut buf, token);
+            }
+
+            Packet::RoomSnapshot { peers, host, metadata } => {
+                buf.push(ROOM_SNAPSHOT);
+                push_vec_peer(&mut buf, peers);
+                push_i32(&mut buf, *host);
+                push_string_map(&mut buf, metadata);
+            }
+
+            Packet::ReqRoster => {
+                buf.push(REQ_ROSTER);
+            }
+
+            Packet::Roster { peers, host_peer_id } => {
+                buf.push(ROSTER);
+                push_vec_i32(&mut buf, peers);
+                push_i32(&mut buf, *host_peer_id);
+            }
+
+            Packet::SpectatorJoined { peer_id } => {
+                buf.push(SPECTATOR_JOINED);
+                push_i32(&mut buf, *peer_id);
+            }
+
+            Packet::SpectatorLeft { peer_id } => {
+                buf.push(SPECTATOR_LEFT);
+                push_i32(&mut buf, *peer_id);
+            }
+
+            Packet::ReqVersionInfo => {
+                buf.push(REQ_VERSION_INFO);
+            }
+
+            Packet::VersionInfo { allowed_versions, protocol_version } => {
+                buf.push(VERSION_INFO);
+                push_vec_string(&mut buf, allowed_versions);
+                push_u16(&mut buf, *protocol_version);
+            }
+
+            Packet::SetRoomAllowlist { ids } => {
+                buf.push(SET_ROOM_ALLOWLIST);
+                push_vec_string(&mut buf, ids);
+            }
+
+            Packet::SetRoomLocked { locked } => {
+                buf.push(SET_ROOM_LOCKED);
+                push_bool(&mut buf, *locked);
+            }
+
             Packet::Error { error_code, error_message } => {
                 buf.push(ERROR_PACKET);
                 push_i32(&mut buf, *error_code);
@@ -214,3 +623,125 @@ impl Packet {
         buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> RoomMetadata {
+        RoomMetadata::from([
+            ("name".to_string(), "Friday night".to_string()),
+            ("map".to_string(), "dust".to_string()),
+        ])
+    }
+
+    #[test]
+    fn packets_survive_a_round_trip() {
+        let packets = vec![
+            Packet::Connect { protocol_version: 7, cookie: Vec::new() },
+            Packet::Connect { protocol_version: 7, cookie: vec![1, 2, 3] },
+            Packet::ConnectAccepted { client_id: u64::MAX, cookie: Vec::new() },
+            Packet::ConnectAccepted { client_id: 0, cookie: vec![4, 5, 6] },
+            Packet::Authenticate {
+                app_id: "app".to_string(),
+                version: "1.0.0".to_string(),
+                stable_id: "abc".to_string(),
+                resume_token: "def".to_string(),
+            },
+            Packet::ClientAuthenticated { capabilities: 3, resume_token: "def".to_string() },
+            Packet::CreateRoom { is_public: true, metadata: metadata(), max_players: 8, max_join_rtt_ms: 150 },
+            Packet::ReqRooms { stream: true, offset: 10, limit: 20, filter: "map=dust".to_string() },
+            Packet::GetRooms {
+                rooms: vec![RoomInfo { join_code: "ABCDE".to_string(), metadata: metadata(), player_count: 2, max_players: 4, locked: true }],
+                total: 1,
+                page: 0,
+                last: true,
+            },
+            Packet::UpdateRoom { room_id: "ABCDE".to_string(), metadata: metadata() },
+            Packet::ReqJoin { room_id: "ABCDE".to_string(), metadata: "hi".to_string(), spectator: true },
+            Packet::JoinRes { target_id: 5, room_id: "ABCDE".to_string(), allowed: true },
+            Packet::RoomCreated { room_id: "relay-ABCDE".to_string(), join_code: "ABCDE".to_string(), peer_id: 1 },
+            Packet::ConnectedToRoom { room_id: "ABCDE".to_string(), peer_id: 2, existing_peers: vec![1, 3], reconnect_token: "t".to_string() },
+            Packet::PeerJoinAttempt { target_id: 9, metadata: "hi".to_string(), spectator: false },
+            Packet::PeerJoinedRoom { peer_id: 2 },
+            Packet::PeerLeftRoom { peer_id: 2 },
+            Packet::GameData { from_peer: -1, data: vec![0, 1, 2, 255] },
+            Packet::GameDataAuto { from_peer: 0, data: vec![] },
+            Packet::ForceDisconnect,
+            Packet::Disconnect,
+            Packet::Heartbeat,
+            Packet::BecameHost,
+            Packet::HostChanged { peer_id: 3 },
+            Packet::ReqHost,
+            Packet::TransferHost { peer_id: 3 },
+            Packet::HostInfo { peer_id: 1 },
+            Packet::Ping { nonce: 42 },
+            Packet::Pong { nonce: 42 },
+            Packet::Redirect { address: "203.0.113.1:8080".to_string() },
+            Packet::Reconnect { token: "t".to_string() },
+            Packet::RoomSnapshot { peers: vec![(1, "a".to_string()), (2, "b".to_string())], host: 1, metadata: metadata() },
+            Packet::ReqRoster,
+            Packet::Roster { peers: vec![1, 2], host_peer_id: 1 },
+            Packet::SpectatorJoined { peer_id: 4 },
+            Packet::SpectatorLeft { peer_id: 4 },
+            Packet::ReqVersionInfo,
+            Packet::VersionInfo { allowed_versions: vec!["1.0.0".to_string(), ">=2.0.0".to_string()], protocol_version: 7 },
+            Packet::SetRoomAllowlist { ids: vec!["a".to_string(), "b".to_string()] },
+            Packet::SetRoomLocked { locked: true },
+            Packet::Error { error_code: 404, error_message: "Room not found".to_string() },
+        ];
+
+        for packet in packets {
+            assert_eq!(Packet::from_bytes(&packet.to_bytes()).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn older_clients_can_leave_out_optional_fields() {
+        let mut bytes = Packet::Authenticate {
+            app_id: "app".to_string(),
+            version: "1.0.0".to_string(),
+            stable_id: String::new(),
+            resume_token: String::new(),
+        }.to_bytes();
+        // Drops the length prefixes of the empty stable ID and resume token.
+        bytes.truncate(bytes.len() - 8);
+
+        let Packet::Authenticate { stable_id, resume_token, .. } = Packet::from_bytes(&bytes).unwrap() else {
+            panic!("expected Authenticate");
+        };
+        assert!(stable_id.is_empty() && resume_token.is_empty());
+
+        assert_eq!(
+            Packet::from_bytes(&[REQ_ROOMS]).unwrap(),
+            Packet::ReqRooms { stream: false, offset: 0, limit: 0, filter: String::new() },
+        );
+    }
+
+    #[test]
+    fn older_clients_heartbeats_read_as_heartbeats() {
+        assert_eq!(Packet::from_bytes(LEGACY_HEARTBEAT).unwrap(), Packet::Heartbeat);
+
+        // Game data that happens to be the same byte is still game data.
+        let packet = Packet::GameData { from_peer: 1, data: LEGACY_HEARTBEAT.to_vec() };
+        assert_eq!(Packet::from_bytes(&packet.to_bytes()).unwrap(), packet);
+    }
+
+    #[test]
+    fn bad_packets_are_rejected() {
+        assert!(matches!(Packet::from_bytes(&[]), Err(ProtocolError::EmptyPacket)));
+        assert!(matches!(Packet::from_bytes(&[0xEE]), Err(ProtocolError::UnknownPacketType(0xEE))));
+        assert!(Packet::from_bytes(&[CONNECTED_TO_ROOM, 0, 0]).is_err());
+        // Metadata claiming an entry that isn't there, instead of being left out.
+        assert!(Packet::from_bytes(&[CREATE_ROOM, 1, 0, 0, 0, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn padded_connects_are_no_shorter_than_the_reply() {
+        let request = Packet::Connect { protocol_version: 7, cookie: vec![0; CONNECT_PADDING] }.to_bytes();
+        assert_eq!(request.len(), MIN_CONNECT_LEN);
+
+        let reply = Packet::ConnectAccepted { client_id: 0, cookie: vec![0; 32] }.to_bytes();
+        assert!(reply.len() <= MIN_CONNECT_LEN);
+    }
+}
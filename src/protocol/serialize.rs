@@ -1,6 +1,14 @@
+use std::collections::HashMap;
 use crate::protocol::error::ProtocolError;
 use crate::protocol::packet::RoomInfo;
 
+/// The longest string accepted from the wire, in bytes.
+pub const MAX_STRING_LEN: i32 = 64 * 1024;
+/// The most elements accepted in a vector from the wire.
+pub const MAX_VEC_LEN: i32 = 4096;
+/// The fewest bytes a serialized `RoomInfo` can take up.
+const MIN_ROOM_INFO_LEN: usize = 4 + 4 + 4 + 4 + 4;
+
 pub fn read_bool(bytes: &[u8]) -> Result<(bool, &[u8]), ProtocolError> {
     let (value, rest) = read_i32(bytes)?;
     Ok((value != 0, rest))
@@ -16,6 +24,28 @@ pub fn read_i32(bytes: &[u8]) -> Result<(i32, &[u8]), ProtocolError> {
     Ok((value, &bytes[4..]))
 }
 
+pub fn read_u16(bytes: &[u8]) -> Result<(u16, &[u8]), ProtocolError> {
+    if bytes.len() < 2 {
+        return Err(ProtocolError::NotEnoughBytes(
+            format!("for u16 (need {} bytes, have {})", 2, bytes.len())
+        ));
+    }
+
+    let value = u16::from_be_bytes(bytes[..2].try_into()?);
+    Ok((value, &bytes[2..]))
+}
+
+pub fn read_u32(bytes: &[u8]) -> Result<(u32, &[u8]), ProtocolError> {
+    if bytes.len() < 4 {
+        return Err(ProtocolError::NotEnoughBytes(
+            format!("for u32 (need {} bytes, have {})", 4, bytes.len())
+        ));
+    }
+
+    let value = u32::from_be_bytes(bytes[..4].try_into()?);
+    Ok((value, &bytes[4..]))
+}
+
 pub fn read_u64(bytes: &[u8]) -> Result<(u64, &[u8]), ProtocolError> {
     if bytes.len() < 8 {
         return Err(ProtocolError::NotEnoughBytes(
@@ -30,24 +60,56 @@ pub fn read_u64(bytes: &[u8]) -> Result<(u64, &[u8]), ProtocolError> {
 pub fn read_string(bytes: &[u8]) -> Result<(String, &[u8]), ProtocolError> {
     let (len, rest) = read_i32(bytes)?;
 
-    if rest.len() < len as usize {
+    if len > MAX_STRING_LEN {
+        return Err(ProtocolError::StringTooLong(len));
+    }
+
+    let Ok(len) = usize::try_from(len) else {
+        return Err(ProtocolError::NotEnoughBytes(
+            format!("for string (negative length {len})")
+        ));
+    };
+
+    if rest.len() < len {
         return Err(ProtocolError::NotEnoughBytes(
             format!("for string (need {} bytes, have {})", len, rest.len())
         ));
     }
 
-    let string_bytes = &rest[..len as usize];
-    let remaining = &rest[len as usize..];
+    let (string_bytes, remaining) = rest.split_at(len);
 
     Ok((String::from_utf8(string_bytes.to_vec())?, remaining))
 }
 
 pub fn push_string(buf: &mut Vec<u8>, value: &str) {
     let bytes = value.as_bytes();
-    buf.extend((bytes.len() as i32).to_be_bytes());
+    push_len(buf, bytes.len());
     buf.extend(bytes);
 }
 
+/// Writes a string or vector length. Nothing the relay sends comes close to `i32::MAX`.
+fn push_len(buf: &mut Vec<u8>, len: usize) {
+    push_i32(buf, i32::try_from(len).unwrap_or(i32::MAX));
+}
+
+/// Reads a vector's element count, rejecting negative counts and ones over `MAX_VEC_LEN`.
+fn read_vec_len(bytes: &[u8]) -> Result<(usize, &[u8]), ProtocolError> {
+    let (len, rest) = read_i32(bytes)?;
+
+    if len > MAX_VEC_LEN {
+        return Err(ProtocolError::VectorTooLong(len));
+    }
+
+    let len = usize::try_from(len).map_err(|_| ProtocolError::NegativeVectorLength())?;
+    Ok((len, rest))
+}
+
+/// How many elements to reserve room for when reading `len` of them, each at least `min_size` bytes.
+/// The count comes from the client, so no more is reserved than the remaining bytes could hold.
+fn capacity(len: usize, rest: &[u8], min_size: usize) -> usize {
+    len.min(rest.len() / min_size)
+}
+
 pub fn push_bool(buf: &mut Vec<u8>, value: bool) {
     push_i32(buf, if value { 1 } else { 0 });
 }
@@ -56,23 +118,135 @@ pub fn push_i32(buf: &mut Vec<u8>, value: i32) {
     buf.extend(value.to_be_bytes());
 }
 
+pub fn push_u16(buf: &mut Vec<u8>, value: u16) { buf.extend(value.to_be_bytes()) }
+
+pub fn push_u32(buf: &mut Vec<u8>, value: u32) { buf.extend(value.to_be_bytes()) }
+
 pub fn push_u64(buf: &mut Vec<u8>, value: u64) { buf.extend(value.to_be_bytes()) }
 
+pub fn read_vec_i32(bytes: &[u8]) -> Result<(Vec<i32>, &[u8]), ProtocolError> {
+    let (len, mut rest) = read_vec_len(bytes)?;
+
+    let mut values = Vec::with_capacity(capacity(len, rest, 4));
+    for _ in 0..len {
+        let (value, remaining) = read_i32(rest)?;
+        values.push(value);
+        rest = remaining;
+    }
+
+    Ok((values, rest))
+}
+
+pub fn push_vec_i32(buf: &mut Vec<u8>, values: &[i32]) {
+    push_len(buf, values.len());
+    for value in values {
+        push_i32(buf, *value);
+    }
+}
+
+pub fn read_vec_string(bytes: &[u8]) -> Result<(Vec<String>, &[u8]), ProtocolError> {
+    let (len, mut rest) = read_vec_len(bytes)?;
+
+    let mut values = Vec::with_capacity(capacity(len, rest, 4));
+    for _ in 0..len {
+        let (value, remaining) = read_string(rest)?;
+        values.push(value);
+        rest = remaining;
+    }
+
+    Ok((values, rest))
+}
+
+pub fn push_vec_string(buf: &mut Vec<u8>, values: &[String]) {
+    push_len(buf, values.len());
+    for value in values {
+        push_string(buf, value);
+    }
+}
+
+/// Reads a trailing field that older clients or relays leave out, falling back to `default` if there's nothing left.
+/// A field that's there but cut short is still an error.
+pub fn read_optional<'a, T>(
+    bytes: &'a [u8],
+    default: T,
+    read: impl FnOnce(&'a [u8]) -> Result<(T, &'a [u8]), ProtocolError>,
+) -> Result<(T, &'a [u8]), ProtocolError> {
+    if bytes.is_empty() {
+        return Ok((default, bytes));
+    }
+
+    read(bytes)
+}
+
+/// Reads a map of strings, written as an entry count followed by each key and value.
+/// Duplicate keys keep the last value.
+pub fn read_string_map(bytes: &[u8]) -> Result<(HashMap<String, String>, &[u8]), ProtocolError> {
+    let (len, mut rest) = read_vec_len(bytes)?;
+
+    let mut map = HashMap::with_capacity(capacity(len, rest, 8));
+    for _ in 0..len {
+        let (key, r) = read_string(rest)?;
+        let (value, r) = read_string(r)?;
+        map.insert(key, value);
+        rest = r;
+    }
+
+    Ok((map, rest))
+}
+
+pub fn push_string_map(buf: &mut Vec<u8>, map: &HashMap<String, String>) {
+    push_len(buf, map.len());
+    for (key, value) in map {
+        push_string(buf, key);
+        push_string(buf, value);
+    }
+}
+
+/// The number of bytes a map takes up when written with `push_string_map`.
+pub fn string_map_len(map: &HashMap<String, String>) -> usize {
+    4 + map.iter().map(|(key, value)| 4 + key.len() + 4 + value.len()).sum::<usize>()
+}
+
+/// A peer's Godot ID and metadata.
+type Peer = (i32, String);
+
+/// Reads a list of peers, each a Godot ID followed by its metadata.
+pub fn read_vec_peer(bytes: &[u8]) -> Result<(Vec<Peer>, &[u8]), ProtocolError> {
+    let (len, mut rest) = read_vec_len(bytes)?;
+
+    let mut peers = Vec::with_capacity(capacity(len, rest, 8));
+    for _ in 0..len {
+        let (peer_id, r) = read_i32(rest)?;
+        let (metadata, r) = read_string(r)?;
+        peers.push((peer_id, metadata));
+        rest = r;
+    }
+
+    Ok((peers, rest))
+}
+
+pub fn push_vec_peer(buf: &mut Vec<u8>, peers: &[Peer]) {
+    push_len(buf, peers.len());
+    for (peer_id, metadata) in peers {
+        push_i32(buf, *peer_id);
+        push_string(buf, metadata);
+    }
+}
+
 pub fn read_room_info(bytes: &[u8]) -> Result<(RoomInfo, &[u8]), ProtocolError> {
     let (id, r) = read_string(bytes)?;
-    let (metadata, r) = read_string(r)?;
+    let (metadata, r) = read_string_map(r)?;
+    let (player_count, r) = read_i32(r)?;
+    let (max_players, r) = read_i32(r)?;
+    let (locked, r) = read_bool(r)?;
 
-    Ok((RoomInfo { join_code: id, metadata }, r))
+    Ok((RoomInfo { join_code: id, metadata, player_count, max_players, locked }, r))
 }
 
 pub fn read_vec_room_info(bytes: &[u8]) -> Result<(Vec<RoomInfo>, &[u8]), ProtocolError> {
-    let (len, mut rest) = read_i32(bytes)?;
-
-    if len < 0 {
-        return Err(ProtocolError::NegativeVectorLength());
-    }
+    let (len, mut rest) = read_vec_len(bytes)?;
 
-    let mut rooms = Vec::with_capacity(len as usize);
+    let mut rooms = Vec::with_capacity(capacity(len, rest, MIN_ROOM_INFO_LEN));
     for _ in 0..len {
         let (room, remaining) = read_room_info(rest)?;
         rooms.push(room);
@@ -83,9 +257,97 @@ pub fn read_vec_room_info(bytes: &[u8]) -> Result<(Vec<RoomInfo>, &[u8]), Protoc
 }
 
 pub fn push_vec_room_info(buf: &mut Vec<u8>, rooms: &[RoomInfo]) {
-    push_i32(buf, rooms.len() as i32);
+    push_len(buf, rooms.len());
     for room in rooms {
         push_string(buf, &room.join_code);
-        push_string(buf, &room.metadata);
+        push_string_map(buf, &room.metadata);
+        push_i32(buf, room.player_count);
+        push_i32(buf, room.max_players);
+        push_bool(buf, room.locked);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserved_capacity_is_capped_by_the_bytes_left() {
+        assert_eq!(capacity(4096, &[0; 40], 4), 10);
+        assert_eq!(capacity(3, &[0; 40], 4), 3);
+        assert_eq!(capacity(4096, &[], MIN_ROOM_INFO_LEN), 0);
+    }
+
+    #[test]
+    fn bad_lengths_are_rejected() {
+        let negative = (-1i32).to_be_bytes();
+        assert!(matches!(read_vec_i32(&negative), Err(ProtocolError::NegativeVectorLength())));
+        assert!(matches!(read_string(&negative), Err(ProtocolError::NotEnoughBytes(_))));
+
+        let too_long = (MAX_VEC_LEN + 1).to_be_bytes();
+        assert!(matches!(read_vec_room_info(&too_long), Err(ProtocolError::VectorTooLong(_))));
+
+        let too_long = (MAX_STRING_LEN + 1).to_be_bytes();
+        assert!(matches!(read_string(&too_long), Err(ProtocolError::StringTooLong(_))));
+
+        // Claims more rooms than there are bytes for.
+        let truncated = MAX_VEC_LEN.to_be_bytes();
+        assert!(matches!(read_vec_room_info(&truncated), Err(ProtocolError::NotEnoughBytes(_))));
+    }
+
+    #[test]
+    fn string_maps_round_trip() {
+        let maps = [
+            HashMap::new(),
+            HashMap::from([
+                ("name".to_string(), "Soirée 🎮".to_string()),
+                ("地图".to_string(), "沙漠".to_string()),
+                (String::new(), String::new()),
+            ]),
+        ];
+
+        for map in maps {
+            let mut buf = Vec::new();
+            push_string_map(&mut buf, &map);
+            assert_eq!(buf.len(), string_map_len(&map));
+
+            let (read, rest) = read_string_map(&buf).unwrap();
+            assert_eq!(read, map);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[test]
+    fn truncated_string_maps_are_rejected() {
+        let mut buf = Vec::new();
+        push_string_map(&mut buf, &HashMap::from([("key".to_string(), "value".to_string())]));
+
+        for len in 1..buf.len() {
+            assert!(read_string_map(&buf[..len]).is_err(), "{len} bytes should be too few");
+        }
+    }
+
+    #[test]
+    fn optional_fields_only_default_when_missing() {
+        assert_eq!(read_optional(&[], 7, read_i32).unwrap(), (7, &[][..]));
+        assert_eq!(read_optional(&[0, 0, 0, 1], 7, read_i32).unwrap(), (1, &[][..]));
+        assert!(read_optional(&[0, 0], 7, read_i32).is_err());
+    }
+
+    #[test]
+    fn strings_and_vectors_round_trip() {
+        let mut buf = Vec::new();
+        push_string(&mut buf, "héllo");
+        push_vec_i32(&mut buf, &[1, -2, 3]);
+        push_vec_string(&mut buf, &["a".to_string(), String::new()]);
+
+        let (string, rest) = read_string(&buf).unwrap();
+        let (ints, rest) = read_vec_i32(rest).unwrap();
+        let (strings, rest) = read_vec_string(rest).unwrap();
+
+        assert_eq!(string, "héllo");
+        assert_eq!(ints, vec![1, -2, 3]);
+        assert_eq!(strings, vec!["a".to_string(), String::new()]);
+        assert!(rest.is_empty());
     }
 }
@@ -1 +1,8 @@
-pub const PROTOCOL_VERSION: &str = "1.1.0_beta";
\ No newline at end of file
+pub const PROTOCOL_VERSION: &str = "1.1.0_beta";
+
+/// Bumped whenever the packet layout changes.
+/// Sent in `VersionInfo` so clients can compare without parsing version strings.
+pub const WIRE_VERSION: u16 = 16;
+/// Sent in `ClientAuthenticated` when the relay runs with `opaque_forwarding`.
+/// Game data is forwarded as-is and never logged, so clients can encrypt it with a key the relay never sees.
+pub const CAP_OPAQUE_FORWARDING: u32 = 1 << 0;
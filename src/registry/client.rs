@@ -0,0 +1,401 @@
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+use crate::config::loader::Config;
+
+type RegistryResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// How many times a registry write is attempted before giving up.
+const MAX_ATTEMPTS: u32 = 3;
+/// The delay before the first retry. Each following retry waits 4x longer.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// The most room touches in flight at once during a heartbeat.
+const MAX_CONCURRENT_TOUCHES: usize = 8;
+/// How long a single registry request can take before it's abandoned.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A room entry as stored in the registry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RegistryRoom {
+    pub app_id: String,
+    pub join_code: String,
+    pub relay_id: String,
+    pub relay_address: String,
+    /// The region of the relay hosting the room. Empty if the relay doesn't set one.
+    #[serde(default)]
+    pub region: String,
+}
+
+/// A room touched by a heartbeat.
+#[derive(Debug, Clone)]
+pub struct HeartbeatRoom {
+    pub app_id: String,
+    pub join_code: String,
+}
+
+/// The answer to a room lookup started by `spawn_lookup_room`.
+#[derive(Debug)]
+pub struct RoomLookup {
+    pub client_id: u64,
+    pub join_code: String,
+    pub result: RegistryResult<Option<RegistryRoom>>,
+}
+
+/// The body of a touch. Any update refreshes the record's `updated` timestamp,
+/// which is what the registry expires rooms by.
+#[derive(Serialize, Debug)]
+struct Touch<'a> {
+    relay_id: &'a str,
+}
+
+/// Shares room state with the registry so multiple relays can find each other's rooms.
+/// When no registry endpoint is configured every call is a no-op.
+#[derive(Clone)]
+pub struct RegistryClient {
+    http: reqwest::Client,
+    endpoint: String,
+    token: String,
+    relay_id: String,
+    relay_address: String,
+    region: String,
+    /// Where the answers to `spawn_lookup_room` are sent.
+    lookups: mpsc::Sender<RoomLookup>,
+    /// Set while a heartbeat is still touching rooms, so a slow registry doesn't get them piling up.
+    heartbeat_in_flight: Arc<AtomicBool>,
+}
+
+impl RegistryClient {
+    /// Registry requests go through `http`, unless a TLS setup is given.
+    /// Then they get their own client using it, so the pinned CAs and client certificate only apply to the registry.
+    pub fn new(
+        http: reqwest::Client,
+        config: &Config,
+        tls: Option<rustls::ClientConfig>,
+        lookups: mpsc::Sender<RoomLookup>,
+    ) -> Result<Self, reqwest::Error> {
+        let http = match tls {
+            Some(tls) => reqwest::Client::builder()
+                .use_preconfigured_tls(tls)
+                .timeout(REQUEST_TIMEOUT)
+                .build()?,
+            None => http,
+        };
+
+        Ok(Self {
+            http,
+            endpoint: config.registry_endpoint.clone(),
+            token: config.registry_token.clone(),
+            relay_id: config.relay_id.clone(),
+            relay_address: config.public_address.clone(),
+            region: config.region.clone(),
+            lookups,
+            heartbeat_in_flight: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.endpoint.is_empty() && !self.token.is_empty()
+    }
+
+    /// Returns true if the given registry entry belongs to this relay.
+    pub fn is_local(&self, room: &RegistryRoom) -> bool {
+        room.relay_id == self.relay_id
+    }
+
+    /// Builds a URL under the endpoint, percent-encoding each path segment.
+    fn url(&self, segments: &[&str]) -> RegistryResult<Url> {
+        let mut url = Url::parse(&self.endpoint)?;
+        url.path_segments_mut()
+            .map_err(|()| format!("registry endpoint {} can't have a path", self.endpoint))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    pub async fn register_room(&self, app: &str, join_code: &str) -> RegistryResult<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        with_retries(|| self.try_register_room(app, join_code)).await
+    }
+
+    pub async fn deregister_room(&self, app: &str, join_code: &str) -> RegistryResult<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+
+        with_retries(|| self.try_deregister_room(app, join_code)).await
+    }
+
+    async fn try_register_room(&self, app: &str, join_code: &str) -> RegistryResult<()> {
+        let room = RegistryRoom {
+            app_id: app.to_string(),
+            join_code: join_code.to_string(),
+            relay_id: self.relay_id.clone(),
+            relay_address: self.relay_address.clone(),
+            region: self.region.clone(),
+        };
+
+        let res = self.http
+            .post(self.url(&["rooms"])?)
+            .header("X-Relay-Token", &self.token)
+            .json(&room)
+            .send()
+            .await?;
+
+        match res.status() {
+            s if s.is_success() => Ok(()),
+            s => Err(format!("unexpected status from registry: {s}").into()),
+        }
+    }
+
+    async fn try_deregister_room(&self, app: &str, join_code: &str) -> RegistryResult<()> {
+        let res = self.http
+            .delete(self.url(&["rooms", app, join_code])?)
+            .header("X-Relay-Token", &self.token)
+            .send()
+            .await?;
+
+        match res.status() {
+            s if s.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Ok(()),
+            s => Err(format!("unexpected status from registry: {s}").into()),
+        }
+    }
+
+    /// Refreshes a room's record, so the registry knows it's still alive.
+    /// Returns false if the registry no longer has the room.
+    pub async fn touch_room(&self, app: &str, join_code: &str) -> RegistryResult<bool> {
+        if !self.is_enabled() {
+            return Ok(true);
+        }
+
+        with_retries(|| self.try_touch_room(app, join_code)).await
+    }
+
+    async fn try_touch_room(&self, app: &str, join_code: &str) -> RegistryResult<bool> {
+        let res = self.http
+            .patch(self.url(&["rooms", app, join_code])?)
+            .header("X-Relay-Token", &self.token)
+            .json(&Touch { relay_id: &self.relay_id })
+            .send()
+            .await?;
+
+        match res.status() {
+            s if s.is_success() => Ok(true),
+            StatusCode::NOT_FOUND => Ok(false),
+            s => Err(format!("unexpected status from registry: {s}").into()),
+        }
+    }
+
+    /// Finds which relay owns a room.
+    /// Returns `None` if the registry doesn't know about the room.
+    pub async fn lookup_room(&self, app: &str, join_code: &str) -> RegistryResult<Option<RegistryRoom>> {
+        if !self.is_enabled() {
+            return Ok(None);
+        }
+
+        // The app token is a credential, so it's kept out of the URL where proxies and access logs would record it.
+        let res = self.http
+            .get(self.url(&["rooms", join_code])?)
+            .header("X-Relay-Token", &self.token)
+            .header("X-App-Token", app)
+            .send()
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(Some(res.json().await?)),
+            StatusCode::NOT_FOUND => Ok(None),
+            s => Err(format!("unexpected status from registry: {s}").into()),
+        }
+    }
+
+    /// Looks up a room in the background so the relay loop isn't blocked.
+    /// The answer is sent to the channel this client was created with.
+    pub fn spawn_lookup_room(&self, client_id: u64, app: &str, join_code: &str) {
+        let registry = self.clone();
+        let app = app.to_string();
+        let join_code = join_code.to_string();
+        tokio::spawn(async move {
+            let result = registry.lookup_room(&app, &join_code).await;
+            // The relay only drops the receiver when it shuts down, at which point no one is waiting.
+            let _ = registry.lookups.send(RoomLookup { client_id, join_code, result }).await;
+        });
+    }
+
+    /// Registers a room in the background so the relay loop isn't blocked.
+    pub fn spawn_register_room(&self, app: &str, join_code: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let registry = self.clone();
+        let app = app.to_string();
+        let join_code = join_code.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = registry.register_room(&app, &join_code).await {
+                warn!("failed to register room {} with registry: {}", join_code, e);
+            }
+        });
+    }
+
+    /// Deregisters a room in the background so the relay loop isn't blocked.
+    pub fn spawn_deregister_room(&self, app: &str, join_code: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let registry = self.clone();
+        let app = app.to_string();
+        let join_code = join_code.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = registry.deregister_room(&app, &join_code).await {
+                warn!("failed to deregister room {} from registry: {}", join_code, e);
+            }
+        });
+    }
+
+    /// Touches every given room in the background so the relay loop isn't blocked.
+    /// At most `MAX_CONCURRENT_TOUCHES` are sent at once.
+    /// Skipped if the previous heartbeat is still going.
+    pub fn spawn_heartbeat(&self, rooms: Vec<HeartbeatRoom>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        if self.heartbeat_in_flight.swap(true, Ordering::AcqRel) {
+            debug!("skipping registry heartbeat, the last one is still in flight");
+            return;
+        }
+
+        let registry = self.clone();
+        tokio::spawn(async move {
+            let mut touches = JoinSet::new();
+
+            for room in rooms {
+                if touches.len() >= MAX_CONCURRENT_TOUCHES {
+                    touches.join_next().await;
+                }
+
+                let registry = registry.clone();
+                touches.spawn(async move {
+                    match registry.touch_room(&room.app_id, &room.join_code).await {
+                        Ok(true) => {}
+                        Ok(false) => warn!("room {} was purged from the registry while still open", room.join_code),
+                        Err(e) => warn!("failed to touch room {} in registry: {}", room.join_code, e),
+                    }
+                });
+            }
+
+            while touches.join_next().await.is_some() {}
+            registry.heartbeat_in_flight.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// Runs a registry call up to `MAX_ATTEMPTS` times with exponential backoff.
+/// Returns the last error if every attempt fails.
+async fn with_retries<T, F, Fut>(mut call: F) -> RegistryResult<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = RegistryResult<T>>,
+{
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt >= MAX_ATTEMPTS => return Err(e),
+            Err(e) => {
+                debug!("registry call failed (attempt {}/{}), retrying in {:?}: {}", attempt, MAX_ATTEMPTS, backoff, e);
+                tokio::time::sleep(backoff).await;
+                backoff *= 4;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use axum::{Json, Router};
+    use axum::routing::{patch, post};
+    use crate::relay::testing;
+    use super::*;
+
+    fn registry(endpoint: &str) -> RegistryClient {
+        registry_in(endpoint, "")
+    }
+
+    fn registry_in(endpoint: &str, region: &str) -> RegistryClient {
+        let mut config = testing::config();
+        config.registry_endpoint = endpoint.to_string();
+        config.registry_token = "secret".to_string();
+        config.region = region.to_string();
+        let (lookups, _) = mpsc::channel(1);
+        RegistryClient::new(reqwest::Client::new(), &config, None, lookups).unwrap()
+    }
+
+    #[test]
+    fn path_segments_are_percent_encoded() {
+        let url = registry("http://registry.test/api/").url(&["rooms", "my app/1?x", "ABCDE"]).unwrap();
+        assert_eq!(url.as_str(), "http://registry.test/api/rooms/my%20app%2F1%3Fx/ABCDE");
+    }
+
+    #[tokio::test]
+    async fn heartbeats_are_skipped_while_one_is_in_flight() {
+        let touches = Arc::new(AtomicUsize::new(0));
+        let counter = touches.clone();
+        let app = Router::new().route("/rooms/{app}/{join_code}", patch(move || {
+            let counter = counter.clone();
+            async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let registry = registry(&format!("http://{}", listener.local_addr().unwrap()));
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        let rooms = vec![HeartbeatRoom { app_id: "app".to_string(), join_code: "ABCDE".to_string() }];
+        registry.spawn_heartbeat(rooms.clone());
+        registry.spawn_heartbeat(rooms.clone());
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert_eq!(touches.load(Ordering::SeqCst), 1);
+
+        registry.spawn_heartbeat(rooms);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(touches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn registered_rooms_carry_the_relay_region() {
+        let (registered, mut rooms) = mpsc::channel(1);
+        let app = Router::new().route("/rooms", post(move |Json(room): Json<RegistryRoom>| async move {
+            registered.send(room).await.unwrap();
+        }));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let registry = registry_in(&format!("http://{}", listener.local_addr().unwrap()), "eu-west");
+        tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        registry.try_register_room("app", "ABCDE").await.unwrap();
+        let room = rooms.recv().await.unwrap();
+        assert_eq!(room.join_code, "ABCDE");
+        assert_eq!(room.region, "eu-west");
+    }
+}
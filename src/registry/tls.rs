@@ -0,0 +1,45 @@
+use std::error::Error;
+use std::sync::Arc;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore};
+use crate::config::loader::Config;
+
+/// Builds the TLS setup for reaching the registry from the configured PEM files.
+/// Returns `None` when no CA bundle is set, in which case the default TLS setup is used.
+pub fn client_config(config: &Config) -> Result<Option<ClientConfig>, Box<dyn Error + Send + Sync>> {
+    if config.registry_ca_path.is_empty() {
+        return Ok(None);
+    }
+
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(&config.registry_ca_path)? {
+        roots.add(cert)?;
+    }
+
+    let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots);
+
+    if config.registry_client_cert_path.is_empty() {
+        return Ok(Some(builder.with_no_client_auth()));
+    }
+
+    let certs = read_certs(&config.registry_client_cert_path)?;
+    let key = PrivateKeyDer::from_pem_file(&config.registry_client_key_path)
+        .map_err(|e| format!("failed to read {}: {e}", config.registry_client_key_path))?;
+
+    Ok(Some(builder.with_client_auth_cert(certs, key)?))
+}
+
+fn read_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error + Send + Sync>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| format!("failed to read {path}: {e}"))?;
+
+    if certs.is_empty() {
+        return Err(format!("{path} has no certificates").into());
+    }
+
+    Ok(certs)
+}
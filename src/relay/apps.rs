@@ -1,49 +1,66 @@
 use std::collections::HashMap;
-use crate::relay::rooms::Rooms;
+use crate::relay::rooms::{JoinCodeFormat, Rooms};
 
 pub struct App {
     pub id: u64,
     pub token: String,
     pub rooms: Rooms,
+    /// The number of authenticated clients using this app, in a room or not.
+    clients: usize,
 }
 
 impl App {
-    pub fn new(id: u64, token: String) -> Self {
+    pub fn new(id: u64, token: String, join_code_format: JoinCodeFormat) -> Self {
         Self {
             id,
             token,
-            rooms: Rooms::new()
+            rooms: Rooms::new(join_code_format),
+            clients: 0,
         }
     }
 }
 
-#[derive(Default)]
 pub struct Apps {
     by_id: HashMap<u64, App>,
     token_to_id: HashMap<String, u64>,
     next_id: u64,
+    /// The join code format used by every app's rooms.
+    join_code_format: JoinCodeFormat,
 }
 
 impl Apps {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(join_code_format: JoinCodeFormat) -> Self {
+        Self {
+            by_id: HashMap::new(),
+            token_to_id: HashMap::new(),
+            next_id: 0,
+            join_code_format,
+        }
     }
 
     pub fn create(&mut self, token: String) -> u64 {
         let app_id = self.next_id;
         self.next_id += 1;
 
-        let app = App::new(app_id, token.clone());
+        let app = App::new(app_id, token.clone(), self.join_code_format.clone());
         self.by_id.insert(app_id, app);
         self.token_to_id.insert(token, app_id);
 
         app_id
     }
     
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &App> {
         self.by_id.values()
     }
 
+    pub fn get(&self, id: u64) -> Option<&App> {
+        self.by_id.get(&id)
+    }
+
     pub fn get_mut(&mut self, id: u64) -> Option<&mut App> {
         self.by_id.get_mut(&id)
     }
@@ -52,4 +69,81 @@ impl Apps {
         let id = self.token_to_id.get(token)?;
         self.by_id.get(id)
     }
+
+    /// Records that a client has authenticated under an app.
+    pub fn add_client(&mut self, id: u64) {
+        if let Some(app) = self.by_id.get_mut(&id) {
+            app.clients += 1;
+        }
+    }
+
+    /// Records that a client has left an app.
+    /// The app is removed once nothing references it anymore.
+    pub fn remove_client(&mut self, id: u64) {
+        if let Some(app) = self.by_id.get_mut(&id) {
+            app.clients = app.clients.saturating_sub(1);
+        }
+
+        self.remove_if_unused(id);
+    }
+
+    /// Removes an app if it has no rooms and no clients.
+    /// Returns true if the app was removed.
+    pub fn remove_if_unused(&mut self, id: u64) -> bool {
+        let Some(app) = self.by_id.get(&id) else {
+            return false;
+        };
+
+        if app.clients > 0 || !app.rooms.is_empty() {
+            return false;
+        }
+
+        if let Some(app) = self.by_id.remove(&id) {
+            self.token_to_id.remove(&app.token);
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::loader::Config;
+    use crate::protocol::packet::RoomMetadata;
+    use super::*;
+
+    fn apps() -> Apps {
+        let config: Config = toml::from_str("").unwrap();
+        Apps::new(JoinCodeFormat::from_config(&config))
+    }
+
+    #[test]
+    fn apps_are_dropped_with_their_last_client() {
+        let mut apps = apps();
+        let app_id = apps.create("app".to_string());
+        apps.add_client(app_id);
+        apps.add_client(app_id);
+
+        apps.remove_client(app_id);
+        assert!(apps.get(app_id).is_some());
+
+        apps.remove_client(app_id);
+        assert!(apps.get(app_id).is_none());
+        assert!(apps.get_by_token("app").is_none());
+    }
+
+    #[test]
+    fn apps_with_rooms_are_kept_without_clients() {
+        let mut apps = apps();
+        let app_id = apps.create("app".to_string());
+        apps.add_client(app_id);
+        let room_id = apps.get_mut(app_id).unwrap().rooms.create(1, true, RoomMetadata::new(), 4).id;
+
+        apps.remove_client(app_id);
+        assert!(apps.get(app_id).is_some());
+
+        apps.get_mut(app_id).unwrap().rooms.remove(room_id);
+        assert!(apps.remove_if_unused(app_id));
+        assert!(apps.get(app_id).is_none());
+    }
 }
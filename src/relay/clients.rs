@@ -1,4 +1,6 @@
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::relay::rate_limit::ByteBucket;
 
 /// An enum to store different states that a client can be in.
 /// Defaults to `Connected`
@@ -6,21 +8,84 @@ use std::collections::HashMap;
 pub enum ClientState {
     #[default]
     Connected,
+    /// Set while an `Authenticate` packet is being checked,
+    /// so a second one can't start another check.
+    Authenticating,
     Authenticated { app_id: u64 },
     InRoom { app_id: u64, room_id: u64 }
 }
 
+impl ClientState {
+    /// Returns the app this client is authenticated under, if any.
+    pub fn app_id(&self) -> Option<u64> {
+        match self {
+            ClientState::Connected | ClientState::Authenticating => None,
+            ClientState::Authenticated { app_id } | ClientState::InRoom { app_id, .. } => Some(*app_id),
+        }
+    }
+}
+
+/// A join request waiting on the host's answer.
+pub struct PendingJoin {
+    /// Room IDs are only unique within an app, so a request is always matched on both.
+    pub app_id: u64,
+    pub room_id: u64,
+    /// The metadata the client sent with the request, passed on to the room once it's let in.
+    pub metadata: String,
+    /// Whether the client asked to join as a spectator.
+    pub spectator: bool,
+    pub expires_at: Instant,
+}
+
+impl PendingJoin {
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires_at
+    }
+}
+
 /// Stores data about a client.
 /// See: `ClientState`
 #[derive(Default)]
 pub struct Client {
     pub state: ClientState,
+    /// An optional ID supplied by the client that survives reconnects.
+    /// Used to recognize a client that came back on a new address.
+    pub stable_id: Option<String>,
+    /// The secret given to a client with a stable ID, which it has to send back to resume its session.
+    pub resume_token: Option<String>,
+    /// When this client was last sent a `VersionInfo`, used to rate limit requests.
+    pub last_version_info: Option<Instant>,
+    /// The join request this client is waiting on, until the host answers or it expires.
+    pub pending_join: Option<PendingJoin>,
+    /// The last measured round-trip time between the relay and this client.
+    pub rtt: Option<Duration>,
+    /// The nonce and send time of the `Ping` currently waiting on a `Pong`.
+    rtt_probe: Option<(u64, Instant)>,
+    /// How much game data this client can still send before it's throttled.
+    pub game_data_budget: ByteBucket,
 }
 
 impl Client {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Starts a round-trip time measurement.
+    /// Returns the nonce to send to the client in a `Ping`.
+    pub fn start_rtt_probe(&mut self) -> u64 {
+        let nonce = rand::random();
+        self.rtt_probe = Some((nonce, Instant::now()));
+        nonce
+    }
+
+    /// Finishes a round-trip time measurement if the nonce matches the last `Ping` sent.
+    /// Late or unknown pongs are ignored.
+    pub fn finish_rtt_probe(&mut self, nonce: u64) {
+        if let Some((_, sent_at)) = self.rtt_probe.filter(|(expected, _)| *expected == nonce) {
+            self.rtt = Some(sent_at.elapsed());
+            self.rtt_probe = None;
+        }
+    }
 }
 
 /// Stores all clients that are connected to the relay server.
@@ -46,11 +111,58 @@ impl Clients {
         self.by_id.remove(&id)
     }
 
+    /// Returns the number of connected clients.
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    /// Gets the IDs of every connected client.
+    pub fn ids(&self) -> Vec<u64> {
+        self.by_id.keys().copied().collect()
+    }
+
     /// Gets a reference to a client by ID.
     pub fn get(&self, id: u64) -> Option<&Client> {
         self.by_id.get(&id)
     }
 
+    /// Finds the IDs of the clients with the given stable ID.
+    /// There can be more than one, since a client can't resume a session that's still live.
+    pub fn find_by_stable_id(&self, stable_id: &str) -> Vec<u64> {
+        self.by_id.iter()
+            .filter(|(_, client)| client.stable_id.as_deref() == Some(stable_id))
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Gets an iterator for all clients and their IDs.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (u64, &mut Client)> {
+        self.by_id.iter_mut().map(|(&id, client)| (id, client))
+    }
+
+    /// Counts the join requests still waiting on a host, in total and for the given room.
+    /// `except` is left out of both counts, so a client replacing its own request isn't counted twice.
+    pub fn pending_join_counts(&self, app_id: u64, room_id: u64, except: u64) -> (usize, usize) {
+        self.by_id.iter()
+            .filter(|(id, _)| **id != except)
+            .filter_map(|(_, client)| client.pending_join.as_ref())
+            .fold((0, 0), |(total, in_room), join| {
+                (total + 1, in_room + usize::from(join.app_id == app_id && join.room_id == room_id))
+            })
+    }
+
+    /// Drops every join request that has gone unanswered for too long.
+    /// Returns the IDs of the clients whose request was dropped.
+    pub fn take_expired_joins(&mut self) -> Vec<u64> {
+        self.by_id.iter_mut()
+            .filter(|(_, client)| client.pending_join.as_ref().is_some_and(PendingJoin::is_expired))
+            .map(|(&id, client)| {
+                client.pending_join = None;
+                id
+            })
+            .collect()
+    }
+
     /// Gets a mutable reference to a client by ID.
     pub fn get_mut(&mut self, id: u64) -> Option<&mut Client> {
         self.by_id.get_mut(&id)
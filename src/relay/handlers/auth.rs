@@ -1,25 +1,46 @@
 use std::error::Error;
+use std::time::Duration;
 use reqwest::StatusCode;
-use tracing::warn;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
 use crate::config::loader::Config;
+use crate::protocol::error_code::ErrorCode;
 use crate::protocol::packet::Packet;
+use crate::protocol::version::CAP_OPAQUE_FORWARDING;
 use crate::relay::apps::Apps;
+use crate::relay::handlers::error::{HandlerError, HandlerResult};
 use crate::relay::clients::{ClientState, Clients};
+use crate::relay::rooms::public_room_id;
+use crate::relay::secret;
 use crate::udp::common::TransferChannel;
 use crate::udp::paper_interface::PaperInterface;
+use crate::udp::socket::DatagramSocket;
 
-pub struct AuthHandler<'a> {
-    udp: &'a mut PaperInterface,
+/// The answer to an app check started by `authenticate_client`, finished by `finish_authentication`.
+#[derive(Debug)]
+pub struct AuthCheck {
+    pub client_id: u64,
+    pub app_token: String,
+    pub stable_id: String,
+    pub resume_token: String,
+    pub allowed: bool,
+}
+
+pub struct AuthHandler<'a, S> {
+    udp: &'a mut PaperInterface<S>,
     http: &'a reqwest::Client,
+    /// Where the answers to remote whitelist checks are sent.
+    checks: &'a mpsc::Sender<AuthCheck>,
 
     clients: &'a mut Clients,
     apps: &'a mut Apps,
     config: &'a Config,
 }
 
-impl<'a> AuthHandler<'a> {
-    pub fn new(udp: &'a mut PaperInterface,
+impl<'a, S: DatagramSocket> AuthHandler<'a, S> {
+    pub fn new(udp: &'a mut PaperInterface<S>,
                http: &'a reqwest::Client,
+               checks: &'a mpsc::Sender<AuthCheck>,
                clients: &'a mut Clients,
                apps: &'a mut Apps,
                config: &'a Config
@@ -27,117 +48,392 @@ impl<'a> AuthHandler<'a> {
         Self {
             udp,
             http,
+            checks,
             clients,
             apps,
             config
         }
     }
 
-    pub async fn authenticate_client(&mut self, sender_id: u64, app_token: &str, version: &str) {
+    pub async fn authenticate_client(&mut self, sender_id: u64, app_token: &str, version: &str, stable_id: &str, resume_token: &str) -> HandlerResult {
+        let Some(client) = self.clients.get_mut(sender_id) else {
+            return Err(HandlerError::MissingClient(sender_id));
+        };
+
+        client.state = ClientState::Authenticating;
+
         // Check version
-        if !self.is_version_allowed(version) {
-            let msg = format!("Version {version} is not allowed.");
-            self.send_err(sender_id, &msg).await;
-            self.force_disconnect(sender_id).await;
-            return;
+        if let Err(msg) = check_version(self.config, version) {
+            // This is the most common rejection, so the session is kept open a little longer
+            // to make sure the client finds out it needs to update.
+            self.udp.send_err(sender_id, ErrorCode::Unauthorized, &msg).await;
+            self.clients.remove(sender_id);
+            self.udp.send_packet(sender_id, &Packet::ForceDisconnect, TransferChannel::Reliable).await;
+            self.udp.disconnect_client_after(sender_id, Duration::from_millis(self.config.version_reject_grace_ms));
+            return Ok(());
+        }
+
+        let check = AuthCheck {
+            client_id: sender_id,
+            app_token: app_token.to_string(),
+            stable_id: stable_id.to_string(),
+            resume_token: resume_token.to_string(),
+            allowed: false,
+        };
+
+        // Check app whitelist. The remote one is asked in the background so the relay loop isn't blocked,
+        // and the client stays `Authenticating` until the answer comes back.
+        if self.uses_remote_whitelist() {
+            self.spawn_remote_check(check);
+            return Ok(());
+        }
+
+        let allowed = self.check_local_whitelist(app_token);
+        self.finish_authentication(AuthCheck { allowed, ..check }).await
+    }
+
+    /// Finishes authenticating a client once its app has been checked.
+    /// The answer is dropped if the client left or its state moved on while it was being checked.
+    pub async fn finish_authentication(&mut self, check: AuthCheck) -> HandlerResult {
+        let AuthCheck { client_id: sender_id, app_token, stable_id, resume_token, allowed } = check;
+
+        let authenticating = self.clients.get(sender_id)
+            .is_some_and(|client| matches!(client.state, ClientState::Authenticating));
+        if !authenticating {
+            debug!("dropping the app check for {}, it left or is no longer authenticating", sender_id);
+            return Ok(());
         }
 
-        // Check app whitelist
-        if !self.app_allowed(app_token).await {
+        if !allowed {
             let msg = format!("App token {app_token} is not allowed.");
-            self.send_err(sender_id, &msg).await;
-            self.force_disconnect(sender_id).await;
-            return;
+            self.udp.send_err(sender_id, ErrorCode::Unauthorized, &msg).await;
+            self.reject(sender_id).await;
+            return Ok(());
         }
 
         let Some(client) = self.clients.get_mut(sender_id) else {
-            warn!("attempted to authenticate a missing client {}", sender_id);
-            return;
+            return Err(HandlerError::MissingClient(sender_id));
         };
 
-        let app_id = match self.apps.get_by_token(app_token) {
+        let app_id = match self.apps.get_by_token(&app_token) {
             Some(app) => app.id,
-            None => self.apps.create(app_token.to_string())
+            None => self.apps.create(app_token)
         };
 
         client.state = ClientState::Authenticated { app_id };
-        self.send_packet(sender_id, &Packet::ClientAuthenticated, TransferChannel::Reliable, ).await;
+        self.apps.add_client(app_id);
+
+        if stable_id.is_empty() {
+            self.send_authenticated(sender_id, String::new()).await;
+            return Ok(());
+        }
+
+        let previous = self.clients.find_by_stable_id(&stable_id);
+        let resumable = previous.iter().copied().find(|&old_id| self.can_resume(old_id, &resume_token));
+
+        // A resumed session keeps its token, anyone else gets a new one.
+        let token = match resumable {
+            Some(_) => resume_token.clone(),
+            None => secret::generate(),
+        };
+
+        if let Some(client) = self.clients.get_mut(sender_id) {
+            client.stable_id = Some(stable_id.clone());
+            client.resume_token = Some(token.clone());
+        }
+
+        self.send_authenticated(sender_id, token).await;
+
+        if let Some(old_id) = resumable {
+            self.resume_session(old_id, sender_id, app_id).await;
+        } else if previous.is_empty() {
+            self.reclaim_room(sender_id, app_id, &stable_id, &resume_token).await;
+        } else {
+            debug!("not resuming a session for {}, it's still live or the token is wrong", sender_id);
+        }
+
+        Ok(())
     }
 
-    fn is_version_allowed(&self, version: &str) -> bool {
-        let versions = &self.config.allowed_versions;
-        versions.contains(&version.to_string())
+    /// Hands a room restored after a restart back to its host, if it presents the resume token it had when the room was saved.
+    /// The host is told about it the same way as when it first created the room.
+    async fn reclaim_room(&mut self, client_id: u64, app_id: u64, stable_id: &str, host_secret: &str) {
+        let Some(room) = self.apps.get_mut(app_id).and_then(|app| app.rooms.find_restored(stable_id, host_secret)) else {
+            return;
+        };
+
+        let peer_id = room.claim(client_id);
+        let room_id = room.id;
+        let join_code = room.join_code.clone();
+
+        if let Some(client) = self.clients.get_mut(client_id) {
+            client.state = ClientState::InRoom { app_id, room_id };
+        }
+
+        info!("client {} reclaimed restored room {}", client_id, join_code);
+        self.udp.send_packet(
+            client_id,
+            &Packet::RoomCreated {
+                room_id: public_room_id(&self.config.relay_id, &join_code),
+                join_code,
+                peer_id,
+            },
+            TransferChannel::Reliable,
+        ).await;
     }
 
-    async fn app_allowed(&mut self, app: &str) -> bool {
-        let remote = &self.config.remote_whitelist_endpoint;
-        let token = &self.config.remote_whitelist_token;
+    /// Checks whether a client may take over the session of `old_id`.
+    /// It needs the session's resume token, and the old session has to have gone quiet.
+    fn can_resume(&self, old_id: u64, resume_token: &str) -> bool {
+        let token_matches = self.clients.get(old_id)
+            .and_then(|client| client.resume_token.as_deref())
+            .is_some_and(|expected| secret::matches(expected, resume_token));
 
-        if remote.is_empty() || token.is_empty() {
-            self.check_local_whitelist(app)
-        } else {
-            match self.check_remote_whitelist(remote, app, token).await {
+        let silence = Duration::from_millis(self.config.resume_after_silence_ms);
+        token_matches && !self.udp.connection_manager.is_active(old_id, silence)
+    }
+
+    /// Moves a previous session's state over to a client that reconnected on a new address.
+    /// The old session is dropped silently, as its address is most likely dead.
+    async fn resume_session(&mut self, old_id: u64, new_id: u64, app_id: u64) {
+        let Some(old_client) = self.clients.get(old_id) else {
+            return;
+        };
+
+        let ClientState::InRoom { app_id: old_app_id, room_id } = old_client.state else {
+            return;
+        };
+
+        if old_app_id != app_id {
+            return;
+        }
+
+        let Some(room) = self.apps.get_mut(app_id).and_then(|app| app.rooms.get_mut(room_id)) else {
+            return;
+        };
+
+        let Some(peer_id) = room.replace_peer(old_id, new_id) else {
+            return;
+        };
+
+        let join_code = room.join_code.clone();
+        let existing_peers = room.get_peers_except(new_id);
+        let reconnect_token = room.reconnect_token(new_id).unwrap_or_default().to_string();
+
+        self.clients.remove(old_id);
+        self.apps.remove_client(app_id);
+        self.udp.remove_client(old_id);
+
+        if let Some(client) = self.clients.get_mut(new_id) {
+            client.state = ClientState::InRoom { app_id, room_id };
+        }
+
+        info!("client {} resumed session of {} in room {}", new_id, old_id, join_code);
+        self.udp.send_packet(
+            new_id,
+            &Packet::ConnectedToRoom {
+                room_id: public_room_id(&self.config.relay_id, &join_code),
+                peer_id,
+                existing_peers,
+                reconnect_token,
+            },
+            TransferChannel::Reliable,
+        ).await;
+    }
+
+    fn uses_remote_whitelist(&self) -> bool {
+        !self.config.remote_whitelist_endpoint.is_empty() && !self.config.remote_whitelist_token.is_empty()
+    }
+
+    /// Checks an app against the remote whitelist in the background, falling back to the local one if that fails.
+    /// The answer is sent to `checks`.
+    fn spawn_remote_check(&self, check: AuthCheck) {
+        let http = self.http.clone();
+        let checks = self.checks.clone();
+        let endpoint = self.config.remote_whitelist_endpoint.clone();
+        let token = self.config.remote_whitelist_token.clone();
+        let whitelist = self.config.whitelist.clone();
+
+        tokio::spawn(async move {
+            let allowed = match check_remote_whitelist(&http, &endpoint, &check.app_token, &token).await {
                 Ok(res) => res,
                 Err(e) => {
                     warn!("failed to check remote whitelist, defaulting to local: {}", e);
-                    self.check_local_whitelist(app)
+                    local_whitelist_allows(&whitelist, &check.app_token)
                 }
-            }
-        }
+            };
+
+            // The relay only drops the receiver when it shuts down, at which point no one is waiting.
+            let _ = checks.send(AuthCheck { allowed, ..check }).await;
+        });
     }
 
     fn check_local_whitelist(&self, app: &str) -> bool {
-        let whitelist = &self.config.whitelist;
-
-        if whitelist.is_empty() {
-            true
-        } else {
-            whitelist.contains(&app.to_string())
-        }
+        local_whitelist_allows(&self.config.whitelist, app)
     }
 
-    async fn check_remote_whitelist(
-        &self,
-        endpoint: &str,
-        app: &str,
-        relay_token: &str,
-    ) -> Result<bool, Box<dyn Error>> {
-        let url = format!("{endpoint}/{app}");
-
-        let res = self.http
-            .get(&url)
-            .header("X-Relay-Token", relay_token)
-            .send()
-            .await?;
-
-        match res.status() {
-            StatusCode::OK => Ok(true),
-            StatusCode::NOT_FOUND => Ok(false),
-            s => Err(format!("unexpected status from endpoint: {s}").into()),
+    /// Tells a client it's authenticated, along with what this relay supports and its resume token.
+    async fn send_authenticated(&mut self, target: u64, resume_token: String) {
+        let mut capabilities = 0;
+        if self.config.opaque_forwarding {
+            capabilities |= CAP_OPAQUE_FORWARDING;
         }
-    }
 
-    async fn send_packet(&mut self, target: u64, packet: &Packet, channel: TransferChannel) {
-        if let Err(e) = self.udp.send(target, packet.to_bytes(), channel).await {
-            warn!("failed to send packet: {}", e);
-        }
+        self.udp.send_packet(target, &Packet::ClientAuthenticated { capabilities, resume_token }, TransferChannel::Reliable).await;
     }
 
-    async fn send_err(&mut self, target: u64, msg: &str) {
-        self.send_packet(
-            target,
-            &Packet::Error {
-                error_code: 401,
-                error_message: msg.to_string(),
-            },
-            TransferChannel::Reliable,
-        )
-            .await;
+    /// Drops a client that failed authentication.
+    async fn reject(&mut self, target: u64) {
+        self.clients.remove(target);
+        self.force_disconnect(target).await;
     }
 
     async fn force_disconnect(&mut self, target: u64) {
-        self.send_packet(target, &Packet::ForceDisconnect, TransferChannel::Reliable)
+        self.udp.send_packet(target, &Packet::ForceDisconnect, TransferChannel::Reliable)
             .await;
-        self.udp.remove_client(&target);
+        self.udp.disconnect_client(target);
+    }
+}
+
+fn local_whitelist_allows(whitelist: &[String], app: &str) -> bool {
+    whitelist.is_empty() || whitelist.iter().any(|pattern| glob_matches(pattern, app))
+}
+
+async fn check_remote_whitelist(
+    http: &reqwest::Client,
+    endpoint: &str,
+    app: &str,
+    relay_token: &str,
+) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let url = format!("{endpoint}/{app}");
+
+    let res = http
+        .get(&url)
+        .header("X-Relay-Token", relay_token)
+        .send()
+        .await?;
+
+    match res.status() {
+        StatusCode::OK => Ok(true),
+        StatusCode::NOT_FOUND => Ok(false),
+        s => Err(format!("unexpected status from endpoint: {s}").into()),
+    }
+}
+
+/// Checks a client version against `allowed_versions`, then `min_client_version`.
+/// Returns the message to send the client if the version is rejected.
+fn check_version(config: &Config, version: &str) -> Result<(), String> {
+    let versions = &config.allowed_versions;
+    if versions.iter().any(|allowed| version_matches(allowed, version)) {
+        return Ok(());
+    }
+
+    let allowed = versions.join(", ");
+
+    let Ok(min) = semver::Version::parse(&config.min_client_version) else {
+        // No minimum set, so only the list counts
+        return Err(format!("Version {version} is not allowed, please update. Allowed versions: {allowed}"));
+    };
+
+    match semver::Version::parse(version) {
+        Ok(parsed) if parsed >= min => Ok(()),
+        Ok(_) => Err(format!("Version {version} is not allowed, please update to {min} or newer.")),
+        Err(_) => Err(format!("Version {version} is not a valid version, expected something like {min}.")),
+    }
+}
+
+/// Checks a client version against one `allowed_versions` entry.
+/// Entries that are a plain version (or not semver at all, like `1.1.0_beta`) have to match exactly.
+/// Anything else that parses as a semver requirement, like `>=1.2.0, <2.0.0`, is matched as a range.
+fn version_matches(allowed: &str, version: &str) -> bool {
+    if allowed == version {
+        return true;
+    }
+
+    // A bare version would parse as a caret requirement and let in far more than it names.
+    if semver::Version::parse(allowed).is_ok() {
+        return false;
+    }
+
+    let (Ok(req), Ok(version)) = (semver::VersionReq::parse(allowed), semver::Version::parse(version)) else {
+        return false;
+    };
+
+    req.matches(&version)
+}
+
+/// Matches `value` against a pattern where `*` stands for any run of characters, e.g. `mygame-*`.
+fn glob_matches(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard, so the whole value has to match.
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        let Some(index) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[index + part.len()..];
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_versions_match_exactly() {
+        assert!(version_matches("1.1.0_beta", "1.1.0_beta"));
+        assert!(version_matches("1.2.0", "1.2.0"));
+        assert!(!version_matches("1.2.0", "1.2.1"));
+        assert!(!version_matches("1.2.0", "1.3.0"));
+    }
+
+    #[test]
+    fn ranges_match_any_version_inside_them() {
+        assert!(version_matches(">=1.2.0, <2.0.0", "1.2.0"));
+        assert!(version_matches(">=1.2.0, <2.0.0", "1.9.9"));
+        assert!(!version_matches(">=1.2.0, <2.0.0", "2.0.0"));
+        assert!(!version_matches(">=1.2.0, <2.0.0", "1.1.0_beta"));
+    }
+
+    #[test]
+    fn versions_are_checked_against_the_minimum() {
+        let config: Config = toml::from_str(r#"min_client_version = "1.2.0""#).unwrap();
+
+        assert!(check_version(&config, "1.1.9").is_err());
+        assert!(check_version(&config, "1.2.0").is_ok());
+        assert!(check_version(&config, "1.3.0").is_ok());
+
+        // Prereleases sort before their release.
+        assert!(check_version(&config, "1.2.0-beta.1").is_err());
+        assert!(check_version(&config, "1.3.0-beta.1").is_ok());
+
+        let Err(msg) = check_version(&config, "1.1.0_beta") else {
+            panic!("expected a version that isn't semver to be rejected");
+        };
+        assert!(msg.contains("not a valid version"));
+    }
+
+    #[test]
+    fn globs_match_any_run_of_characters() {
+        assert!(glob_matches("mygame", "mygame"));
+        assert!(!glob_matches("mygame", "mygame-dev"));
+        assert!(glob_matches("mygame-*", "mygame-dev"));
+        assert!(glob_matches("mygame-*", "mygame-"));
+        assert!(!glob_matches("mygame-*", "othergame-dev"));
+        assert!(glob_matches("*-dev", "mygame-dev"));
+        assert!(glob_matches("my*game*dev", "my-cool-game-dev"));
+        assert!(!glob_matches("my*game*dev", "my-cool-dev"));
+        assert!(glob_matches("*", ""));
     }
 }
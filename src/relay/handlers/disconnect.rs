@@ -1,33 +1,47 @@
+use std::time::Duration;
 use tracing::{info, warn};
+use crate::config::loader::Config;
 use crate::protocol::packet::Packet;
+use crate::registry::client::RegistryClient;
 use crate::relay::apps::Apps;
 use crate::relay::clients::{ClientState, Clients};
 use crate::relay::handlers::room::RoomHandler;
 use crate::udp::common::TransferChannel;
 use crate::udp::paper_interface::PaperInterface;
+use crate::udp::socket::DatagramSocket;
 
 struct DisconnectInfo {
     is_host: bool,
     godot_id: i32,
+    /// Everyone else in the room, spectators included.
     other_peers: Vec<u64>,
+    /// Whether anyone who could take over as host is left. Spectators can't, and neither can
+    /// peers whose session is already closing.
+    has_other_players: bool,
 }
 
-pub struct DisconnectHandler<'a> {
-    udp: &'a mut PaperInterface,
+pub struct DisconnectHandler<'a, S> {
+    udp: &'a mut PaperInterface<S>,
     clients: &'a mut Clients,
     apps: &'a mut Apps,
+    registry: &'a RegistryClient,
+    config: &'a Config,
 }
 
-impl<'a> DisconnectHandler<'a> {
+impl<'a, S: DatagramSocket> DisconnectHandler<'a, S> {
     pub fn new(
-        udp: &'a mut PaperInterface,
+        udp: &'a mut PaperInterface<S>,
         clients: &'a mut Clients,
         apps: &'a mut Apps,
+        registry: &'a RegistryClient,
+        config: &'a Config,
     ) -> Self {
         Self {
             udp,
             clients,
             apps,
+            registry,
+            config,
         }
     }
 
@@ -40,9 +54,22 @@ impl<'a> DisconnectHandler<'a> {
         if let ClientState::InRoom { app_id, room_id } = client.state {
             self.handle_room_disconnect(client_id, app_id, room_id).await;
         }
+
+        if let Some(app_id) = client.state.app_id() {
+            self.apps.remove_client(app_id);
+        }
     }
 
     async fn handle_room_disconnect(&mut self, sender_id: u64, app_id: u64, room_id: u64) {
+        let spectator_id = self.apps.get(app_id)
+            .and_then(|app| app.rooms.get(room_id))
+            .and_then(|room| room.spectator_id(sender_id));
+
+        if let Some(godot_id) = spectator_id {
+            self.handle_spectator_disconnect(app_id, room_id, sender_id, godot_id).await;
+            return;
+        }
+
         let disconnect_info = {
             let Some(app) = self.apps.get_mut(app_id) else {
                 warn!("{} had invalid app_id on disconnect", sender_id);
@@ -66,10 +93,13 @@ impl<'a> DisconnectHandler<'a> {
                     .into_iter()
                     .filter(|&id| id != sender_id)
                     .collect(),
+                has_other_players: room.next_host(|id| id != sender_id && self.udp.connection_manager.is_open(id)).is_some(),
             }
         };
 
-        if disconnect_info.is_host {
+        if disconnect_info.is_host && self.config.host_migration && disconnect_info.has_other_players {
+            self.handle_host_migration(app_id, room_id, sender_id, disconnect_info.godot_id, disconnect_info.other_peers).await;
+        } else if disconnect_info.is_host {
             self.handle_host_disconnect(app_id, room_id, disconnect_info.other_peers).await;
         } else {
             self.handle_peer_disconnect(app_id, room_id, sender_id, disconnect_info.godot_id, disconnect_info.other_peers).await;
@@ -82,44 +112,124 @@ impl<'a> DisconnectHandler<'a> {
             self.udp,
             self.apps,
             self.clients,
+            self.registry,
+            self.config,
         ).remove_room(app_id, room_id);
 
         for peer_id in peers_to_kick {
-            self.clients.remove(peer_id);
+            if let Some(peer) = self.clients.remove(peer_id) {
+                if let Some(app_id) = peer.state.app_id() {
+                    self.apps.remove_client(app_id);
+                }
+            }
             self.force_disconnect(peer_id).await;
         }
     }
 
+    async fn handle_host_migration(&mut self, app_id: u64, room_id: u64, host_id: u64, host_godot_id: i32, other_peers: Vec<u64>) {
+        let new_host = {
+            let Some(app) = self.apps.get_mut(app_id) else {
+                return;
+            };
+
+            let Some(room) = app.rooms.get_mut(room_id) else {
+                return;
+            };
+
+            room.remove_peer(host_id);
+
+            let next_host = room.next_host(|id| self.udp.connection_manager.is_open(id));
+            if let Some((new_host_id, _)) = next_host {
+                room.set_host(new_host_id);
+            }
+
+            next_host
+        };
+
+        let Some((new_host_id, new_host_godot_id)) = new_host else {
+            RoomHandler::new(
+                self.udp,
+                self.apps,
+                self.clients,
+                self.registry,
+                self.config,
+            ).remove_room(app_id, room_id);
+            return;
+        };
+
+        info!("host disconnected, migrated to {}", new_host_id);
+
+        for peer_id in other_peers {
+            if self.is_leaving(peer_id) {
+                continue;
+            }
+
+            self.udp.send_packet(peer_id, &Packet::PeerLeftRoom { peer_id: host_godot_id }, TransferChannel::Reliable).await;
+
+            if peer_id == new_host_id {
+                self.udp.send_packet(peer_id, &Packet::BecameHost, TransferChannel::Reliable).await;
+            } else {
+                self.udp.send_packet(peer_id, &Packet::HostChanged { peer_id: new_host_godot_id }, TransferChannel::Reliable).await;
+            }
+        }
+    }
+
     async fn handle_peer_disconnect(&mut self, app_id: u64, room_id: u64, client_id: u64, peer_godot_id: i32, other_peers: Vec<u64>) {
         info!("peer disconnected");
         if let Some(app) = self.apps.get_mut(app_id) {
             if let Some(room) = app.rooms.get_mut(room_id) {
-                room.remove_peer(client_id);
+                room.depart_peer(client_id, Duration::from_millis(self.config.reconnect_grace_ms));
             }
         }
 
         for peer_id in other_peers {
-            self.send_packet(peer_id, &Packet::PeerLeftRoom { peer_id: peer_godot_id }, TransferChannel::Reliable).await;
+            if !self.is_leaving(peer_id) {
+                self.udp.send_packet(peer_id, &Packet::PeerLeftRoom { peer_id: peer_godot_id }, TransferChannel::Reliable).await;
+            }
         }
     }
 
+    /// Removes a spectator from its room. Only the host is told, as it's the only one told about spectators joining.
+    async fn handle_spectator_disconnect(&mut self, app_id: u64, room_id: u64, client_id: u64, godot_id: i32) {
+        info!("spectator disconnected");
+        let Some(room) = self.apps.get_mut(app_id).and_then(|app| app.rooms.get_mut(room_id)) else {
+            return;
+        };
+
+        room.remove_spectator(client_id);
+        let host_id = room.get_host();
+
+        self.udp.send_packet(host_id, &Packet::SpectatorLeft { peer_id: godot_id }, TransferChannel::Reliable).await;
+    }
+
+    /// Closes a room, disconnecting everyone still in it.
+    pub async fn close_room(&mut self, app_id: u64, room_id: u64) {
+        let Some(room) = self.apps.get_mut(app_id).and_then(|app| app.rooms.get(room_id)) else {
+            return;
+        };
+
+        let peers = room.get_clients();
+        info!("closing room {} with {} peers", room.join_code, peers.len());
+        self.handle_host_disconnect(app_id, room_id, peers).await;
+    }
+
+    /// Tells a client it's being disconnected and closes its session.
+    /// Sessions that are already closing were told when they started to, so they're left alone.
     pub async fn force_disconnect(&mut self, target_client: u64) {
-        self.send_packet(
+        if self.is_leaving(target_client) {
+            return;
+        }
+
+        self.udp.send_packet(
             target_client,
             &Packet::ForceDisconnect,
             TransferChannel::Reliable
         ).await;
-        self.udp.remove_client(&target_client);
+        self.udp.disconnect_client(target_client);
     }
 
-    async fn send_packet(&mut self, target_client: u64, packet: &Packet, channel: TransferChannel) {
-        match self.udp.send(
-            target_client,
-            packet.to_bytes(),
-            channel,
-        ).await {
-            Ok(()) => {},
-            Err(e) => warn!("failed to send packet: {}", e)
-        }
+    /// Returns true if the client's session is gone or already closing, so there's no point telling it anything.
+    fn is_leaving(&self, client_id: u64) -> bool {
+        !self.udp.connection_manager.is_open(client_id)
     }
 }
\ No newline at end of file
@@ -0,0 +1,30 @@
+use thiserror::Error;
+
+/// Something a handler couldn't do because the relay's own state didn't line up.
+/// Bad requests from clients are answered with an `Error` packet instead, and aren't reported here.
+#[derive(Debug, Error)]
+pub enum HandlerError {
+    #[error("app {0} doesn't exist")]
+    MissingApp(u64),
+
+    #[error("client {0} doesn't exist")]
+    MissingClient(u64),
+
+    #[error("room {0} doesn't exist")]
+    MissingRoom(u64),
+
+    #[error("client {0} isn't in the room it's indexed under")]
+    NotInOwnRoom(u64),
+
+    #[error("the host of room {0} isn't in it")]
+    HostNotInRoom(u64),
+}
+
+impl HandlerError {
+    /// True if the client's own app or room is gone, so it should be told its state is stale.
+    pub fn is_stale_client_state(&self) -> bool {
+        matches!(self, Self::MissingApp(_) | Self::MissingRoom(_))
+    }
+}
+
+pub type HandlerResult = Result<(), HandlerError>;
@@ -1,17 +1,20 @@
-use tracing::warn;
-use crate::protocol::packet::Packet;
+use tracing::debug;
+use crate::protocol::builder::PacketBuilder;
+use crate::protocol::packet::BROADCAST_PEER;
 use crate::relay::apps::Apps;
+use crate::relay::handlers::error::{HandlerError, HandlerResult};
 use crate::udp::common::TransferChannel;
 use crate::udp::paper_interface::PaperInterface;
+use crate::udp::socket::DatagramSocket;
 
-pub struct GameDataHandler<'a> {
-    udp: &'a mut PaperInterface,
+pub struct GameDataHandler<'a, S> {
+    udp: &'a mut PaperInterface<S>,
     apps: &'a mut Apps,
 }
 
-impl<'a> GameDataHandler<'a> {
+impl<'a, S: DatagramSocket> GameDataHandler<'a, S> {
     pub fn new(
-        udp: &'a mut PaperInterface,
+        udp: &'a mut PaperInterface<S>,
         apps: &'a mut Apps
     ) -> Self {
         Self {
@@ -20,40 +23,56 @@ impl<'a> GameDataHandler<'a> {
         }
     }
 
-    pub async fn route_game_data(&mut self, sender_id: u64, client_app_id: u64, client_room_id: u64, target_peer: i32, data: &[u8], channel: &TransferChannel) {
+    pub async fn route_game_data(&mut self, sender_id: u64, client_app_id: u64, client_room_id: u64, target_peer: i32, data: &[u8], channel: &TransferChannel) -> HandlerResult {
         let Some(app) = self.apps.get_mut(client_app_id) else {
-            warn!("{} has invalid app_id in index", sender_id);
-            return;
+            return Err(HandlerError::MissingApp(client_app_id));
         };
 
-        let Some(room) = app.rooms.get(client_room_id) else {
-            warn!("{} has invalid room_id in index", sender_id);
-            return;
+        let Some(room) = app.rooms.get_mut(client_room_id) else {
+            return Err(HandlerError::MissingRoom(client_room_id));
         };
 
         let Some(sender_godot_id) = room.client_to_gd(sender_id) else {
-            warn!("{} not found in their own room", sender_id);
-            return;
+            if room.spectator_id(sender_id).is_some() {
+                debug!("dropping game data from spectator {}", sender_id);
+                return Ok(());
+            }
+
+            return Err(HandlerError::NotInOwnRoom(sender_id));
         };
 
+        if target_peer == BROADCAST_PEER {
+            let targets: Vec<u64> = room.get_clients()
+                .into_iter()
+                .filter(|&id| id != sender_id)
+                .collect();
+            room.touch();
+
+            let (packet, channel) = PacketBuilder::game_data(sender_godot_id, data)
+                .channel(*channel)
+                .build();
+
+            for target in targets {
+                self.udp.send_packet(target, &packet, channel).await;
+            }
+
+            return Ok(());
+        }
+
         let Some(target_renet_id) = room.gd_to_client(target_peer) else {
-            return;
+            // Usually the target just left, so the sender isn't told. Game data is too frequent to answer each one.
+            debug!("dropping game data from {} for unknown peer {}", sender_id, target_peer);
+            return Ok(());
         };
 
-        self.send_packet(
-            target_renet_id,
-            &Packet::GameData {
-                from_peer: sender_godot_id,
-                data: data.to_vec(),
-            },
-            *channel,
-        ).await;
-    }
+        room.touch();
 
-    // TODO: get rid of duplicates
-    async fn send_packet(&mut self, target: u64, packet: &Packet, channel: TransferChannel) {
-        if let Err(e) = self.udp.send(target, packet.to_bytes(), channel).await {
-            warn!("failed to send packet: {}", e);
-        }
+        let (packet, channel) = PacketBuilder::game_data(sender_godot_id, data)
+            .channel(*channel)
+            .build();
+
+        self.udp.send_packet(target_renet_id, &packet, channel).await;
+
+        Ok(())
     }
 }
@@ -1,4 +1,5 @@
 pub mod auth;
 pub mod room;
 pub mod game_data;
-pub mod disconnect;
\ No newline at end of file
+pub mod disconnect;
+pub mod error;
\ No newline at end of file
@@ -1,177 +1,684 @@
-use tracing::warn;
-use crate::protocol::packet::{Packet, RoomInfo};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use crate::config::loader::Config;
+use crate::metrics::METRICS;
+use crate::protocol::error_code::ErrorCode;
+use crate::protocol::packet::{metadata_len, Packet, RoomInfo, RoomMetadata};
 use crate::relay::apps::Apps;
-use crate::relay::clients::{ClientState, Clients};
+use crate::relay::handlers::error::{HandlerError, HandlerResult};
+use crate::registry::client::{RegistryClient, RoomLookup};
+use crate::relay::clients::{ClientState, Clients, PendingJoin};
+use crate::relay::rooms::{public_room_id, Room};
 use crate::udp::common::TransferChannel;
 use crate::udp::paper_interface::PaperInterface;
+use crate::udp::socket::DatagramSocket;
 
-pub struct RoomHandler<'a> {
-    udp: &'a mut PaperInterface,
+/// The most rooms returned in a single page of `GetRooms`.
+const MAX_ROOMS_PER_PAGE: u32 = 50;
+
+pub struct RoomHandler<'a, S> {
+    udp: &'a mut PaperInterface<S>,
     apps: &'a mut Apps,
     clients: &'a mut Clients,
+    registry: &'a RegistryClient,
+    config: &'a Config,
 }
 
-impl<'a> RoomHandler<'a> {
+impl<'a, S: DatagramSocket> RoomHandler<'a, S> {
     pub fn new(
-        udp: &'a mut PaperInterface,
+        udp: &'a mut PaperInterface<S>,
         apps: &'a mut Apps,
         clients: &'a mut Clients,
+        registry: &'a RegistryClient,
+        config: &'a Config,
     ) -> Self {
         Self {
             udp,
             apps,
-            clients
+            clients,
+            registry,
+            config,
         }
     }
 
-    pub async fn create_room(&mut self, sender_id: u64, app_id: u64, is_public: bool, metadata: &str) {
+    pub async fn create_room(&mut self, sender_id: u64, app_id: u64, is_public: bool, metadata: &RoomMetadata, max_players: i32, max_join_rtt_ms: u32) -> HandlerResult {
+        if !self.check_metadata_size(sender_id, metadata).await {
+            return Ok(());
+        }
+
         let Some(app) = self.apps.get_mut(app_id) else {
-            warn!("attempted to create a room for a missing app: {}", app_id);
-            return;
+            return Err(HandlerError::MissingApp(app_id));
         };
 
         let Some(client) = self.clients.get_mut(sender_id) else {
-            warn!("attempted to create a room for a missing client: {}", sender_id);
-            return;
+            return Err(HandlerError::MissingClient(sender_id));
         };
 
-        let room = app.rooms.create(sender_id, is_public, metadata.to_string());
+        // A client can only host one room at a time (it has to be out of a room to create one),
+        // so only the app needs a cap.
+        if app.rooms.len() >= self.config.max_rooms_per_app {
+            warn!("app {} hit the room limit of {}", app.token, self.config.max_rooms_per_app);
+            self.udp.send_err(sender_id, ErrorCode::RateLimited, "Too many rooms open for this app").await;
+            return Ok(());
+        }
+
+        let room = app.rooms.create(sender_id, is_public, metadata.clone(), max_players.max(0));
+        room.max_join_rtt_ms = max_join_rtt_ms;
+        METRICS.room_opened();
         let join_code = room.join_code.clone();
         let peer_id = room.add_peer(sender_id);
 
         client.state = ClientState::InRoom { app_id, room_id: room.id };
+        self.registry.spawn_register_room(&app.token, &join_code);
 
-        self.send_packet(
+        self.udp.send_packet(
             sender_id,
-            &Packet::ConnectedToRoom {
-                room_id: join_code,
+            &Packet::RoomCreated {
+                room_id: public_room_id(&self.config.relay_id, &join_code),
+                join_code,
                 peer_id,
             },
             TransferChannel::Reliable,
         ).await;
+
+        Ok(())
     }
 
-    pub async fn send_rooms(&mut self, target: u64, app_id: u64) {
+    /// Sends a page of the public room list, along with the total number of public rooms.
+    /// `limit` is capped at `MAX_ROOMS_PER_PAGE`, and a limit of 0 means the cap.
+    /// A page bigger than `room_list_max_bytes` is split over several `GetRooms` packets.
+    pub async fn send_rooms(&mut self, target: u64, app_id: u64, offset: u32, limit: u32, filter: &str) -> HandlerResult {
         let Some(app) = self.apps.get_mut(app_id) else {
-            warn!("attempted to list rooms for a missing app: {}", app_id);
-            return;
+            return Err(HandlerError::MissingApp(app_id));
         };
 
-        let public_rooms: Vec<RoomInfo> = app.rooms.iter_mut()
-            .filter(|room| room.is_public)
-            .map(|room| room.to_info())
-            .collect();
+        let limit = if limit == 0 { MAX_ROOMS_PER_PAGE } else { limit.min(MAX_ROOMS_PER_PAGE) };
+        let public_rooms = app.rooms.public_rooms_matching(filter);
+        let total = u32::try_from(public_rooms.len()).unwrap_or(u32::MAX);
 
-        self.send_packet(
-            target,
-            &Packet::GetRooms {
-                rooms: public_rooms
-            },
-            TransferChannel::Reliable,
-        ).await;
+        let rooms = public_rooms.into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(Room::to_info);
+
+        let mut chunks = chunk_rooms(rooms, self.config.room_list_max_bytes);
+        if chunks.is_empty() {
+            chunks.push(Vec::new());
+        }
+
+        self.send_chunks(target, chunks, total).await;
+
+        Ok(())
     }
 
-    pub async fn update_room(&mut self, sender_id: u64, app_id: u64, room_id: u64, metadata: &str) {
-        let app = self.apps.get_mut(app_id).expect("App exists");
+    /// Sends the public room list split over several `GetRooms` packets.
+    /// Each chunk fits within `room_list_max_bytes`, and an empty chunk marks the end of the list.
+    pub async fn stream_rooms(&mut self, target: u64, app_id: u64, filter: &str) -> HandlerResult {
+        let Some(app) = self.apps.get_mut(app_id) else {
+            return Err(HandlerError::MissingApp(app_id));
+        };
+
+        let public_rooms = app.rooms.public_rooms_matching(filter);
+        let total = u32::try_from(public_rooms.len()).unwrap_or(u32::MAX);
+
+        let mut chunks = chunk_rooms(public_rooms.into_iter().map(Room::to_info), self.config.room_list_max_bytes);
+
+        // Terminator
+        chunks.push(Vec::new());
+
+        self.send_chunks(target, chunks, total).await;
+
+        Ok(())
+    }
+
+    pub async fn update_room(&mut self, sender_id: u64, app_id: u64, room_id: u64, metadata: &RoomMetadata) -> HandlerResult {
+        if !self.check_metadata_size(sender_id, metadata).await {
+            return Ok(());
+        }
+
+        let Some(app) = self.apps.get_mut(app_id) else {
+            return Err(HandlerError::MissingApp(app_id));
+        };
         let Some(room) = app.rooms.get_mut(room_id) else {
-            self.send_err(sender_id, "Room not found").await;
-            return;
+            self.udp.send_err(sender_id, ErrorCode::NotFound, "Room not found").await;
+            return Ok(());
         };
 
-        room.metadata = metadata.to_string();
+        room.metadata.clone_from(metadata);
+        room.touch();
+
+        Ok(())
+    }
+
+    pub async fn set_allowlist(&mut self, sender_id: u64, app_id: u64, room_id: u64, ids: &[String]) -> HandlerResult {
+        let Some(room) = self.apps.get_mut(app_id).and_then(|app| app.rooms.get_mut(room_id)) else {
+            self.udp.send_err(sender_id, ErrorCode::NotFound, "Room not found").await;
+            return Ok(());
+        };
+
+        if room.get_host() != sender_id {
+            self.udp.send_err(sender_id, ErrorCode::Forbidden, "Only the host can set the room allowlist").await;
+            return Ok(());
+        }
+
+        room.set_allowlist(ids);
+
+        Ok(())
+    }
+
+    /// Hands the room to another peer at the host's request. The old host stays in the room as a peer.
+    /// Join requests already sent to the old host can no longer be answered, and time out.
+    pub async fn transfer_host(&mut self, sender_id: u64, app_id: u64, room_id: u64, peer_id: i32) -> HandlerResult {
+        let Some(room) = self.apps.get_mut(app_id).and_then(|app| app.rooms.get_mut(room_id)) else {
+            self.udp.send_err(sender_id, ErrorCode::NotFound, "Room not found").await;
+            return Ok(());
+        };
+
+        if room.get_host() != sender_id {
+            self.udp.send_err(sender_id, ErrorCode::Forbidden, "Only the host can transfer the room").await;
+            return Ok(());
+        }
+
+        let Some(new_host_id) = room.gd_to_client(peer_id) else {
+            self.udp.send_err(sender_id, ErrorCode::NotFound, "Peer not in room").await;
+            return Ok(());
+        };
+
+        if new_host_id == sender_id {
+            return Ok(());
+        }
+
+        room.set_host(new_host_id);
+        room.touch();
+        let clients = room.get_clients();
+
+        info!("host {} transferred the room to {}", sender_id, new_host_id);
+
+        for client_id in clients {
+            if client_id == new_host_id {
+                self.udp.send_packet(client_id, &Packet::BecameHost, TransferChannel::Reliable).await;
+            } else {
+                self.udp.send_packet(client_id, &Packet::HostChanged { peer_id }, TransferChannel::Reliable).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Tells a peer who the room's host currently is, so it can resync after a missed `HostChanged`.
+    pub async fn send_host_info(&mut self, sender_id: u64, app_id: u64, room_id: u64) -> HandlerResult {
+        let Some(room) = self.apps.get(app_id).and_then(|app| app.rooms.get(room_id)) else {
+            self.udp.send_err(sender_id, ErrorCode::NotFound, "Room not found").await;
+            return Ok(());
+        };
+
+        let Some(peer_id) = room.client_to_gd(room.get_host()) else {
+            return Err(HandlerError::HostNotInRoom(room_id));
+        };
+
+        self.udp.send_packet(sender_id, &Packet::HostInfo { peer_id }, TransferChannel::Reliable).await;
+
+        Ok(())
+    }
+
+    /// Sends a client the full list of peers in its room.
+    /// Clients can use this to resync if they missed a join or leave.
+    pub async fn send_roster(&mut self, sender_id: u64, app_id: u64, room_id: u64) -> HandlerResult {
+        let Some(room) = self.apps.get(app_id).and_then(|app| app.rooms.get(room_id)) else {
+            self.udp.send_err(sender_id, ErrorCode::NotFound, "Room not found").await;
+            return Ok(());
+        };
+
+        let Some(host_peer_id) = room.client_to_gd(room.get_host()) else {
+            return Err(HandlerError::HostNotInRoom(room_id));
+        };
+
+        let roster = Packet::Roster {
+            peers: room.peer_ids(),
+            host_peer_id,
+        };
+
+        self.udp.send_packet(sender_id, &roster, TransferChannel::Reliable).await;
+
+        Ok(())
+    }
+
+    /// Locks or unlocks a room. Only the host can do this.
+    pub async fn set_locked(&mut self, sender_id: u64, app_id: u64, room_id: u64, locked: bool) -> HandlerResult {
+        let Some(room) = self.apps.get_mut(app_id).and_then(|app| app.rooms.get_mut(room_id)) else {
+            self.udp.send_err(sender_id, ErrorCode::NotFound, "Room not found").await;
+            return Ok(());
+        };
+
+        if room.get_host() != sender_id {
+            self.udp.send_err(sender_id, ErrorCode::Forbidden, "Only the host can lock the room").await;
+            return Ok(());
+        }
+
+        room.locked = locked;
+        room.touch();
+
+        Ok(())
     }
 
     pub fn remove_room(&mut self, app_id: u64, room_id: u64) {
         if let Some(app) = self.apps.get_mut(app_id) {
-            app.rooms.remove(room_id);
+            if let Some(room) = app.rooms.remove(room_id) {
+                METRICS.room_closed();
+                self.registry.spawn_deregister_room(&app.token, &room.join_code);
+            }
         }
+
+        self.apps.remove_if_unused(app_id);
     }
 
-    pub(crate) async fn recv_join_req(&mut self, sender_id: u64, app_id: u64, room_id: &str, metadata: &str) {
-        let host_id = {
+    /// Passes a join request on to the room's host.
+    /// Spectators go through the same checks, except that a full room still lets them in.
+    pub(crate) async fn recv_join_req(&mut self, sender_id: u64, app_id: u64, room_id: &str, metadata: &str, spectator: bool) -> HandlerResult {
+        // The metadata is passed on to the host as-is, so it's held to the same limit as room metadata.
+        let max = self.config.max_metadata_bytes;
+        if metadata.len() > max {
+            let msg = format!("Join metadata is too large ({} bytes, max {max})", metadata.len());
+            self.udp.send_err(sender_id, ErrorCode::TooLarge, &msg).await;
+            return Ok(());
+        }
+
+        let (host_id, app_token) = {
             let Some(app) = self.apps.get_mut(app_id) else {
-                warn!("attempted to handle join request for a missing app: {}", app_id);
-                return;
+                return Err(HandlerError::MissingApp(app_id));
             };
 
-            let Some(room) = app.rooms.get_by_jc(room_id) else {
-                self.send_err(sender_id, "Room not found").await;
-                return;
-            };
+            let client = self.clients.get(sender_id);
+            let stable_id = client.and_then(|c| c.stable_id.as_deref());
+            let rtt = client.and_then(|c| c.rtt);
+            let room = app.rooms.get_by_jc(room_id);
+
+            if room.is_some_and(|room| !room.is_allowed(stable_id)) {
+                self.udp.send_err(sender_id, ErrorCode::Forbidden, "Not allowed to join this room").await;
+                return Ok(());
+            }
+
+            if room.is_some_and(Room::is_awaiting_host) {
+                self.udp.send_err(sender_id, ErrorCode::ServerFull, "Room is being restored, try again shortly").await;
+                return Ok(());
+            }
+
+            if room.is_some_and(|room| room.locked) {
+                self.udp.send_err(sender_id, ErrorCode::Locked, "Room locked").await;
+                return Ok(());
+            }
+
+            if !spectator && room.is_some_and(Room::is_full) {
+                self.udp.send_err(sender_id, ErrorCode::Conflict, "Room is full").await;
+                return Ok(());
+            }
+
+            if spectator && room.is_some_and(|room| room.spectator_count() >= self.config.max_spectators) {
+                self.udp.send_err(sender_id, ErrorCode::Conflict, "Room has no space for more spectators").await;
+                return Ok(());
+            }
 
-            room.get_host()
+            if room.is_some_and(|room| !room.accepts_rtt(rtt)) {
+                self.send_rtt_err(sender_id, rtt).await;
+                return Ok(());
+            }
+
+            (room.map(|room| (room.id, room.get_host())), app.token.clone())
+        };
+
+        let Some((target_room_id, host_id)) = host_id else {
+            self.look_up_remote_room(sender_id, &app_token, room_id).await;
+            return Ok(());
         };
 
-        self.send_packet(
+        let (total, in_room) = self.clients.pending_join_counts(app_id, target_room_id, sender_id);
+        if total >= self.config.max_pending_joins || in_room >= self.config.max_pending_joins_per_room {
+            warn!("too many pending join requests, rejecting {}", sender_id);
+            self.udp.send_err(sender_id, ErrorCode::RateLimited, "Too many join requests, try again shortly").await;
+            return Ok(());
+        }
+
+        if let Some(client) = self.clients.get_mut(sender_id) {
+            client.pending_join = Some(PendingJoin {
+                app_id,
+                room_id: target_room_id,
+                metadata: metadata.to_string(),
+                spectator,
+                expires_at: Instant::now() + Duration::from_millis(self.config.join_request_timeout_ms),
+            });
+        }
+
+        self.udp.send_packet(
             host_id,
             &Packet::PeerJoinAttempt {
                 target_id: sender_id,
-                metadata: metadata.to_string()
+                metadata: metadata.to_string(),
+                spectator,
             },
             TransferChannel::Reliable
         ).await;
+
+        Ok(())
     }
 
-    pub(crate) async fn recv_join_res(&mut self, app_id: u64, target_id: u64, room_id: u64, allowed: &bool) {
-        if *allowed {
-            let Some(client) = self.clients.get_mut(target_id) else {
-                warn!("attempted to handle join response for a missing client: {}", target_id);
-                return;
+    /// Handles a host's answer to a join request.
+    /// Only the host of the room can answer, and only for a client that asked to join that room.
+    pub(crate) async fn recv_join_res(&mut self, sender_id: u64, app_id: u64, target_id: u64, room_id: u64, allowed: &bool) -> HandlerResult {
+        let is_host = self.apps.get(app_id)
+            .and_then(|app| app.rooms.get(room_id))
+            .is_some_and(|room| room.get_host() == sender_id);
+
+        if !is_host {
+            warn!("{} answered a join request without being the host", sender_id);
+            self.udp.send_err(sender_id, ErrorCode::Forbidden, "Only the host can answer join requests").await;
+            return Ok(());
+        }
+
+        let Some(client) = self.clients.get_mut(target_id) else {
+            return Err(HandlerError::MissingClient(target_id));
+        };
+
+        let is_waiting = client.pending_join.as_ref().is_some_and(|join| join.app_id == app_id && join.room_id == room_id && !join.is_expired())
+            && matches!(client.state, ClientState::Authenticated { app_id: id } if id == app_id);
+
+        if !is_waiting {
+            warn!("{} answered a join request {} never made", sender_id, target_id);
+            self.udp.send_err(sender_id, ErrorCode::Conflict, "That client hasn't asked to join this room").await;
+            return Ok(());
+        }
+
+        let (metadata, spectator) = client.pending_join.take()
+            .map(|join| (join.metadata, join.spectator))
+            .unwrap_or_default();
+
+        if !*allowed {
+            self.udp.send_err(target_id, ErrorCode::Forbidden, "Room host denied entry").await;
+            return Ok(());
+        }
+
+        let rtt = client.rtt;
+
+        let (peer_id, host_id, join_code, existing_peers, reconnect_token) = {
+            let Some(room) = self.apps.get_mut(app_id).and_then(|app| app.rooms.get_mut(room_id)) else {
+                self.udp.send_err(target_id, ErrorCode::NotFound, "Room not found").await;
+                return Ok(());
             };
 
-            let (peer_id, host_id, join_code) = {
-                let app = self.apps.get_mut(app_id).expect("App exists");
-                let Some(room) = app.rooms.get_mut(room_id) else {
-                    self.send_err(target_id, "Room not found").await;
-                    return;
-                };
+            // The room may have filled up while the host was deciding
+            if !spectator && room.is_full() {
+                self.udp.send_err(target_id, ErrorCode::Conflict, "Room is full").await;
+                return Ok(());
+            }
 
-                let peer_id = room.add_peer(target_id);
-                let host_id = room.get_host();
+            if spectator && room.spectator_count() >= self.config.max_spectators {
+                self.udp.send_err(target_id, ErrorCode::Conflict, "Room has no space for more spectators").await;
+                return Ok(());
+            }
 
-                (peer_id, host_id, room.join_code.clone())
+            if !room.accepts_rtt(rtt) {
+                self.send_rtt_err(target_id, rtt).await;
+                return Ok(());
+            }
+
+            // Spectators can't reconnect into a slot, since they don't hold one.
+            let (peer_id, reconnect_token) = if spectator {
+                (room.add_spectator(target_id), String::new())
+            } else {
+                let peer_id = room.add_peer(target_id);
+                room.set_peer_metadata(peer_id, metadata);
+                (peer_id, room.issue_reconnect_token(target_id))
             };
+            let host_id = room.get_host();
+            let existing_peers = room.get_peers_except(target_id);
+
+            (peer_id, host_id, room.join_code.clone(), existing_peers, reconnect_token)
+        };
+
+        client.state = ClientState::InRoom { app_id, room_id };
+
+        self.udp.send_packet(
+            target_id,
+            &Packet::ConnectedToRoom {
+                room_id: public_room_id(&self.config.relay_id, &join_code),
+                peer_id,
+                existing_peers,
+                reconnect_token,
+            },
+            TransferChannel::Reliable,
+        ).await;
+
+        let joined = if spectator {
+            Packet::SpectatorJoined { peer_id }
+        } else {
+            Packet::PeerJoinedRoom { peer_id }
+        };
+
+        self.udp.send_packet(host_id, &joined, TransferChannel::Reliable).await;
+
+        Ok(())
+    }
+
+    /// Puts a client back into the room slot it dropped out of, keeping its Godot ID.
+    pub async fn reconnect(&mut self, sender_id: u64, app_id: u64, token: &str) -> HandlerResult {
+        let Some(client) = self.clients.get_mut(sender_id) else {
+            return Err(HandlerError::MissingClient(sender_id));
+        };
+
+        let Some(room) = self.apps.get_mut(app_id)
+            .and_then(|app| app.rooms.find_by_reconnect_token(token))
+            .filter(|room| room.has_departed_slot(token)) else {
+            self.udp.send_err(sender_id, ErrorCode::Gone, "Reconnect token is invalid or expired").await;
+            return Ok(());
+        };
+
+        if room.is_full() {
+            self.udp.send_err(sender_id, ErrorCode::Conflict, "Room is full").await;
+            return Ok(());
+        }
+
+        let Some(peer_id) = room.reclaim_slot(token, sender_id) else {
+            self.udp.send_err(sender_id, ErrorCode::Gone, "Reconnect token is invalid or expired").await;
+            return Ok(());
+        };
+
+        let room_id = room.id;
+        let host_id = room.get_host();
+        let join_code = room.join_code.clone();
+        let existing_peers = room.get_peers_except(sender_id);
+        let reconnect_token = room.issue_reconnect_token(sender_id);
+        let snapshot = Packet::RoomSnapshot {
+            peers: room.peer_list(),
+            host: room.client_to_gd(host_id).unwrap_or_default(),
+            metadata: room.metadata.clone(),
+        };
+
+        client.state = ClientState::InRoom { app_id, room_id };
+        info!("client {} reconnected to room {} as peer {}", sender_id, join_code, peer_id);
+
+        self.udp.send_packet(
+            sender_id,
+            &Packet::ConnectedToRoom {
+                room_id: public_room_id(&self.config.relay_id, &join_code),
+                peer_id,
+                existing_peers,
+                reconnect_token,
+            },
+            TransferChannel::Reliable,
+        ).await;
+
+        // Sent on its own so the client doesn't have to replay the events it missed
+        self.udp.send_packet(sender_id, &snapshot, TransferChannel::Reliable).await;
+
+        self.udp.send_packet(
+            host_id,
+            &Packet::PeerJoinedRoom { peer_id },
+            TransferChannel::Reliable,
+        ).await;
 
-            client.state = ClientState::InRoom { app_id, room_id };
-
-            self.send_packet(
-                target_id,
-                &Packet::ConnectedToRoom {
-                    room_id: join_code,
-                    peer_id,
-                },
-                TransferChannel::Reliable,
-            ).await;
-
-            self.send_packet(
-                host_id,
-                &Packet::PeerJoinedRoom {
-                    peer_id,
-                },
-                TransferChannel::Reliable
-            ).await;
+        Ok(())
+    }
 
+    /// Checks the registry for a room that isn't hosted on this relay.
+    /// The lookup runs in the background and is answered by `finish_room_lookup`.
+    async fn look_up_remote_room(&mut self, sender_id: u64, app_token: &str, room_id: &str) {
+        if !self.registry.is_enabled() {
+            self.udp.send_err(sender_id, ErrorCode::NotFound, "Room not found").await;
             return;
         }
 
-        self.send_err(target_id, "Room host denied entry").await;
+        self.registry.spawn_lookup_room(sender_id, app_token, room_id);
     }
 
-    async fn send_packet(&mut self, target: u64, packet: &Packet, channel: TransferChannel) {
-        if let Err(e) = self.udp.send(target, packet.to_bytes(), channel).await {
-            warn!("failed to send packet: {}", e);
+    /// Answers a join request once the registry lookup for its room is back.
+    /// If another relay owns the room, the client is redirected there.
+    pub async fn finish_room_lookup(&mut self, lookup: RoomLookup) {
+        let RoomLookup { client_id, join_code, result } = lookup;
+
+        // The client may have left or joined another room while the lookup was running.
+        if !self.clients.get(client_id).is_some_and(|client| matches!(client.state, ClientState::Authenticated { .. })) {
+            debug!("dropping registry lookup of {} for {}, it moved on", join_code, client_id);
+            return;
+        }
+
+        match result {
+            Ok(Some(room)) if !self.registry.is_local(&room) => {
+                self.udp.send_packet(
+                    client_id,
+                    &Packet::Redirect { address: room.relay_address },
+                    TransferChannel::Reliable,
+                ).await;
+            }
+            Ok(_) => self.udp.send_err(client_id, ErrorCode::NotFound, "Room not found").await,
+            Err(e) => {
+                warn!("failed to look up room {} in registry: {}", join_code, e);
+                self.udp.send_err(client_id, ErrorCode::NotFound, "Room not found").await;
+            }
         }
     }
 
-    async fn send_err(&mut self, target: u64, msg: &str) {
-        self.send_packet(
-            target,
-            &Packet::Error {
-                error_code: 401,
-                error_message: msg.to_string(),
-            },
-            TransferChannel::Reliable,
-        )
+    /// Sends each chunk of a room list as its own `GetRooms`, marking the last one.
+    async fn send_chunks(&mut self, target: u64, chunks: Vec<Vec<RoomInfo>>, total: u32) {
+        let count = chunks.len();
+
+        for (page, rooms) in chunks.into_iter().enumerate() {
+            let packet = Packet::GetRooms {
+                rooms,
+                total,
+                page: u32::try_from(page).unwrap_or(u32::MAX),
+                last: page + 1 == count,
+            };
+
+            self.udp.send_packet(target, &packet, TransferChannel::Reliable).await;
+        }
+    }
+
+    /// Rejects room metadata over `max_metadata_bytes`, counting every key and value as serialized.
+    /// Returns false if the metadata was rejected.
+    async fn check_metadata_size(&mut self, sender_id: u64, metadata: &RoomMetadata) -> bool {
+        let max = self.config.max_metadata_bytes;
+        let len = metadata_len(metadata);
+        if len <= max {
+            return true;
+        }
+
+        let msg = format!("Room metadata is too large ({len} bytes, max {max})");
+        self.udp.send_err(sender_id, ErrorCode::TooLarge, &msg).await;
+        false
+    }
+
+    /// Tells a client it can't join a room because of its round-trip time to the relay.
+    async fn send_rtt_err(&mut self, target: u64, rtt: Option<Duration>) {
+        let msg = match rtt {
+            Some(rtt) => format!("Too far from the relay to join this room ({}ms)", rtt.as_millis()),
+            None => "Round-trip time not measured yet, try again shortly".to_string(),
+        };
+
+        self.udp.send_err(target, ErrorCode::Forbidden, &msg).await;
+    }
+}
+
+/// Splits a room list into chunks whose serialized rooms fit within `max_bytes`.
+/// A room too big to fit on its own still gets a chunk to itself.
+fn chunk_rooms(rooms: impl Iterator<Item = RoomInfo>, max_bytes: usize) -> Vec<Vec<RoomInfo>> {
+    let mut chunks: Vec<Vec<RoomInfo>> = Vec::new();
+    let mut chunk: Vec<RoomInfo> = Vec::new();
+    let mut chunk_len = 0;
+
+    for info in rooms {
+        let len = info.encoded_len();
+
+        if !chunk.is_empty() && chunk_len + len > max_bytes {
+            chunks.push(std::mem::take(&mut chunk));
+            chunk_len = 0;
+        }
+
+        chunk_len += len;
+        chunk.push(info);
+    }
+
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use crate::relay::rooms::JoinCodeFormat;
+    use crate::relay::testing;
+    use crate::udp::socket::memory::MemorySocket;
+    use super::*;
+
+    fn room(join_code: &str) -> RoomInfo {
+        RoomInfo {
+            join_code: join_code.to_string(),
+            metadata: RoomMetadata::new(),
+            player_count: 1,
+            max_players: 4,
+            locked: false,
+        }
+    }
+
+    #[test]
+    fn rooms_are_chunked_within_the_limit() {
+        let rooms: Vec<RoomInfo> = ["AAAAA", "BBBBB", "CCCCC"].into_iter().map(room).collect();
+        let len = rooms[0].encoded_len();
+
+        let chunks = chunk_rooms(rooms.clone().into_iter(), len * 2);
+        assert_eq!(chunks, vec![rooms[..2].to_vec(), rooms[2..].to_vec()]);
+
+        let chunks = chunk_rooms(rooms.clone().into_iter(), len * 3);
+        assert_eq!(chunks, vec![rooms]);
+    }
+
+    #[test]
+    fn an_oversized_room_gets_a_chunk_of_its_own() {
+        let rooms: Vec<RoomInfo> = ["AAAAA", "BBBBB"].into_iter().map(room).collect();
+
+        let chunks = chunk_rooms(rooms.clone().into_iter(), 1);
+        assert_eq!(chunks, vec![rooms[..1].to_vec(), rooms[1..].to_vec()]);
+    }
+
+    #[test]
+    fn no_rooms_means_no_chunks() {
+        assert!(chunk_rooms(std::iter::empty(), 100).is_empty());
+    }
+
+    #[tokio::test]
+    async fn updating_a_room_in_a_missing_app_is_an_error() {
+        let config = testing::config();
+        let (socket, _) = MemorySocket::pair();
+        let mut udp = PaperInterface::new(vec![socket], 64);
+        let mut apps = Apps::new(JoinCodeFormat::from_config(&config));
+        let mut clients = Clients::new();
+        let (lookups, _) = mpsc::channel(1);
+        let registry = RegistryClient::new(reqwest::Client::new(), &config, None, lookups).unwrap();
+
+        let result = RoomHandler::new(&mut udp, &mut apps, &mut clients, &registry, &config)
+            .update_room(1, 7, 1, &RoomMetadata::new())
             .await;
+        assert!(matches!(result, Err(HandlerError::MissingApp(7))));
     }
 }
@@ -2,4 +2,9 @@ mod rooms;
 mod apps;
 mod clients;
 pub mod server;
-mod handlers;
\ No newline at end of file
+mod handlers;
+pub mod rate_limit;
+mod store;
+mod secret;
+#[cfg(test)]
+pub mod testing;
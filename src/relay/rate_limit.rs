@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    started: Instant,
+    count: u32,
+}
+
+/// Counts events per key within a fixed time window.
+/// Callers decide what to do once a key goes over the limit.
+pub struct RateLimiter<K> {
+    limit: u32,
+    window: Duration,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// The number of events allowed per window.
+    pub fn limit(&self) -> u32 {
+        self.limit
+    }
+
+    /// Changes the limit and window. Existing buckets are kept.
+    pub fn set_limits(&mut self, limit: u32, window: Duration) {
+        self.limit = limit;
+        self.window = window;
+    }
+
+    /// Records an event for a key.
+    /// Returns the number of events recorded for that key in the current window, including this one.
+    pub fn hit(&mut self, key: K) -> u32 {
+        let now = Instant::now();
+        let bucket = self.buckets.entry(key).or_insert(Bucket { started: now, count: 0 });
+
+        if now.duration_since(bucket.started) >= self.window {
+            bucket.started = now;
+            bucket.count = 0;
+        }
+
+        bucket.count = bucket.count.saturating_add(1);
+        bucket.count
+    }
+
+    /// Forgets a key entirely.
+    pub fn remove(&mut self, key: &K) {
+        self.buckets.remove(key);
+    }
+
+    /// Forgets every key `matches` returns true for.
+    pub fn remove_where(&mut self, matches: impl Fn(&K) -> bool) {
+        self.buckets.retain(|key, _| !matches(key));
+    }
+
+    /// Returns true if no key has any events recorded.
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// Removes buckets whose window has ended.
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        let window = self.window;
+        self.buckets.retain(|_, bucket| now.duration_since(bucket.started) < window);
+    }
+}
+
+/// How long a bucket has to go without turning bytes away before it stops counting as throttled.
+const THROTTLE_RECOVERY: Duration = Duration::from_secs(1);
+
+/// Lets through a steady number of bytes per second, with bursts of up to a set size.
+/// The limits are passed on each call, so a config reload applies to existing buckets.
+#[derive(Default)]
+pub struct ByteBucket {
+    available: u64,
+    refilled: Option<Instant>,
+    throttled_since: Option<Instant>,
+    last_throttled: Option<Instant>,
+}
+
+impl ByteBucket {
+    /// Takes `bytes` from the bucket if there are enough, refilling it at `per_sec` up to `burst`.
+    /// Returns false, taking nothing, if there aren't.
+    pub fn take(&mut self, bytes: usize, per_sec: u64, burst: u64) -> bool {
+        let now = Instant::now();
+
+        let Some(refilled) = self.refilled else {
+            self.available = burst;
+            self.refilled = Some(now);
+            return self.spend(bytes, now);
+        };
+
+        let earned = now.duration_since(refilled).as_nanos() * u128::from(per_sec) / 1_000_000_000;
+        // Time that hasn't earned a whole byte yet is carried over to the next call.
+        if earned > 0 {
+            let earned = u64::try_from(earned).unwrap_or(u64::MAX);
+            self.available = self.available.saturating_add(earned).min(burst);
+            self.refilled = Some(now);
+        }
+
+        self.spend(bytes, now)
+    }
+
+    fn spend(&mut self, bytes: usize, now: Instant) -> bool {
+        let bytes = bytes as u64;
+        if bytes <= self.available {
+            self.available -= bytes;
+
+            if self.last_throttled.is_some_and(|last| now.duration_since(last) >= THROTTLE_RECOVERY) {
+                self.throttled_since = None;
+                self.last_throttled = None;
+            }
+
+            return true;
+        }
+
+        self.throttled_since.get_or_insert(now);
+        self.last_throttled = Some(now);
+        false
+    }
+
+    /// How long the bucket has been turning bytes away without a second's break, if it currently is.
+    pub fn throttled_for(&self) -> Option<Duration> {
+        self.throttled_since.map(|since| since.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_buckets_let_a_burst_through_then_throttle() {
+        let mut bucket = ByteBucket::default();
+        assert!(bucket.take(600, 1000, 1000));
+        assert!(bucket.take(400, 1000, 1000));
+        assert_eq!(bucket.throttled_for(), None);
+
+        assert!(!bucket.take(100, 1000, 1000));
+        assert!(bucket.throttled_for().is_some());
+    }
+
+    #[test]
+    fn byte_buckets_refill_at_the_steady_rate() {
+        let mut bucket = ByteBucket::default();
+        assert!(bucket.take(1000, 10_000, 1000));
+        assert!(!bucket.take(500, 10_000, 1000));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(bucket.take(500, 10_000, 1000));
+    }
+
+    #[test]
+    fn byte_buckets_never_fit_more_than_the_burst() {
+        let mut bucket = ByteBucket::default();
+        assert!(!bucket.take(1001, 1_000_000, 1000));
+    }
+}
@@ -1,27 +1,58 @@
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 use rand::{rng, Rng};
-use crate::protocol::packet::RoomInfo;
+use crate::config::loader::Config;
+use crate::protocol::packet::{RoomInfo, RoomMetadata};
+use crate::relay::secret;
+use crate::relay::store::StoredRoom;
 
-const ID_CHARS: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ123456789";
-const ID_LENGTH: usize = 5;
+/// The room ID sent to clients in `RoomCreated` and `ConnectedToRoom`: the relay ID followed by the join code,
+/// so a client can tell which relay a room lives on.
+pub fn public_room_id(relay_id: &str, join_code: &str) -> String {
+    format!("{relay_id}{join_code}")
+}
+
+/// The length and characters of generated join codes.
+#[derive(Debug, Clone)]
+pub struct JoinCodeFormat {
+    length: usize,
+    alphabet: Vec<char>,
+}
+
+impl JoinCodeFormat {
+    /// Reads the format from the config.
+    /// `Config::validate` makes sure the alphabet is big enough for the length.
+    pub fn from_config(config: &Config) -> Self {
+        let mut alphabet: Vec<char> = config.join_code_alphabet.chars().collect();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+
+        Self {
+            length: config.join_code_length,
+            alphabet,
+        }
+    }
+}
 
-#[derive(Default)]
+/// Hands out join codes for one app's rooms.
+/// Each app has its own, so two apps can use the same code for different rooms.
 pub struct RoomIds {
-    used: HashSet<String>
+    used: HashSet<String>,
+    format: JoinCodeFormat,
 }
 
 impl RoomIds {
-    pub fn new() -> Self {
-        Self { used: HashSet::new() }
+    pub fn new(format: JoinCodeFormat) -> Self {
+        Self { used: HashSet::new(), format }
     }
 
     pub fn generate(&mut self) -> String {
         loop {
             let mut rng = rng();
-            let id: String = (0..ID_LENGTH)
+            let id: String = (0..self.format.length)
                 .map(|_| {
-                    let idx = rng.random_range(0..ID_CHARS.len());
-                    ID_CHARS[idx] as char
+                    let idx = rng.random_range(0..self.format.alphabet.len());
+                    self.format.alphabet[idx]
                 })
                 .collect();
 
@@ -31,45 +62,137 @@ impl RoomIds {
         }
     }
 
+    /// Marks a specific ID as used.
+    /// Returns false if it was already taken.
+    pub fn reserve(&mut self, id: &str) -> bool {
+        self.used.insert(id.to_string())
+    }
+
     pub fn free(&mut self, id: &str) {
         self.used.remove(id);
     }
 }
 
+/// A slot left behind by a peer that dropped out, kept so it can reconnect.
+#[derive(Debug)]
+struct DepartedSlot {
+    godot_id: i32,
+    metadata: Option<String>,
+    expires: Instant,
+}
+
 #[derive(Debug)]
 pub struct Room {
     pub id: u64,
     pub join_code: String,
     pub is_public: bool,
-    pub metadata: String,
+    pub metadata: RoomMetadata,
+    /// The most peers allowed in the room at once, including the host.
+    /// 0 means there's no limit.
+    pub max_players: i32,
+    /// The highest round-trip time a client can have to the relay and still join.
+    /// 0 means there's no limit.
+    pub max_join_rtt_ms: u32,
+    /// Set by the host to stop new joins without hiding the room from the list.
+    pub locked: bool,
+    /// The last time anything happened in this room.
+    /// See: `Room::touch`
+    pub last_activity: Instant,
     host_id: u64,
+    /// Stable client IDs allowed to join, set by the host.
+    /// When `None`, anyone with the join code can request to join.
+    allowlist: Option<HashSet<String>>,
+    /// Set on rooms restored from the room store until their host comes back.
+    pending_host: Option<PendingHost>,
+    /// Reconnect tokens handed out to the peers currently in the room.
+    reconnect_tokens: HashMap<u64, String>,
+    /// Slots of peers that dropped out, keyed by their reconnect token.
+    departed: HashMap<String, DepartedSlot>,
+    /// The metadata each peer joined with, keyed by Godot ID.
+    peer_metadata: HashMap<i32, String>,
     client_to_godot: HashMap<u64, i32>,
     godot_to_client: HashMap<i32, u64>,
+    /// Spectators mapped to their Godot IDs. They aren't peers, so they don't count toward
+    /// `max_players` and can't be sent to directly, only through broadcasts.
+    spectators: HashMap<u64, i32>,
     next_godot_id: i32,
 }
 
+/// The host a restored room is waiting on.
+#[derive(Debug)]
+struct PendingHost {
+    stable_id: String,
+    /// The resume token the host had when the room was saved. It has to present it to reclaim the room.
+    secret: String,
+    /// When the room is given up on.
+    deadline: Instant,
+}
+
 impl Room {
-    pub fn new(id: u64, join_code: String, host_id: u64, is_public: bool, metadata: String) -> Self {
+    pub fn new(id: u64, join_code: String, host_id: u64, is_public: bool, metadata: RoomMetadata, max_players: i32) -> Self {
         Self {
             id,
             join_code,
             is_public,
             metadata,
+            max_players,
+            max_join_rtt_ms: 0,
+            locked: false,
+            last_activity: Instant::now(),
             host_id,
+            allowlist: None,
+            pending_host: None,
+            reconnect_tokens: HashMap::new(),
+            departed: HashMap::new(),
+            peer_metadata: HashMap::new(),
             client_to_godot: HashMap::new(),
             godot_to_client: HashMap::new(),
+            spectators: HashMap::new(),
             next_godot_id: 1,
         }
     }
 
+    /// Checks the room's metadata against a room list filter.
+    /// `gamemode=ctf` matches rooms with that entry, and `gamemode` matches rooms with the key set to anything.
+    /// Empty matches everything.
+    pub fn matches_filter(&self, filter: &str) -> bool {
+        if filter.is_empty() {
+            return true;
+        }
+
+        match filter.split_once('=') {
+            Some((key, value)) => self.metadata.get(key).is_some_and(|v| v == value),
+            None => self.metadata.contains_key(filter),
+        }
+    }
+
     pub fn to_info(&self) -> RoomInfo {
         RoomInfo {
             join_code: self.join_code.clone(),
             metadata: self.metadata.clone(),
+            player_count: i32::try_from(self.peer_count()).unwrap_or(i32::MAX),
+            max_players: self.max_players,
+            locked: self.locked,
         }
     }
 
+    /// Returns the number of peers in the room, including the host.
+    pub fn peer_count(&self) -> usize {
+        self.client_to_godot.len()
+    }
+
+    /// Returns true if the room has a player limit and has reached it.
+    pub fn is_full(&self) -> bool {
+        usize::try_from(self.max_players).is_ok_and(|max| max > 0 && self.peer_count() >= max)
+    }
+
+    /// Marks the room as active, resetting its idle timer.
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
     pub fn add_peer(&mut self, client_id: u64) -> i32 {
+        self.touch();
         let godot_pid = self.next_godot_id;
         self.client_to_godot.insert(client_id, godot_pid);
         self.godot_to_client.insert(godot_pid, client_id);
@@ -78,8 +201,50 @@ impl Room {
         godot_pid
     }
 
+    /// Adds a spectator to the room. Spectators get a Godot ID from the same range as peers.
+    pub fn add_spectator(&mut self, client_id: u64) -> i32 {
+        self.touch();
+        let godot_pid = self.next_godot_id;
+        self.spectators.insert(client_id, godot_pid);
+        self.next_godot_id += 1;
+
+        godot_pid
+    }
+
+    /// Removes a spectator, returning its Godot ID if it was in the room.
+    pub fn remove_spectator(&mut self, client_id: u64) -> Option<i32> {
+        self.spectators.remove(&client_id)
+    }
+
+    pub fn spectator_count(&self) -> usize {
+        self.spectators.len()
+    }
+
+    pub fn spectator_id(&self, client_id: u64) -> Option<i32> {
+        self.spectators.get(&client_id).copied()
+    }
+
+    /// Gets every client in the room, spectators included.
     pub fn get_clients(&self) -> Vec<u64> {
-        self.client_to_godot.keys().copied().collect()
+        self.client_to_godot.keys()
+            .chain(self.spectators.keys())
+            .copied()
+            .collect()
+    }
+
+    /// Gets the Godot IDs of every peer in the room except the given client.
+    pub fn get_peers_except(&self, client_id: u64) -> Vec<i32> {
+        self.client_to_godot.iter()
+            .filter(|(id, _)| **id != client_id)
+            .map(|(_, godot_id)| *godot_id)
+            .collect()
+    }
+
+    /// Gets the Godot IDs of every peer in the room, sorted.
+    pub fn peer_ids(&self) -> Vec<i32> {
+        let mut peers: Vec<i32> = self.godot_to_client.keys().copied().collect();
+        peers.sort_unstable();
+        peers
     }
 
     pub fn client_to_gd(&self, client_id: u64) -> Option<i32> {
@@ -94,16 +259,160 @@ impl Room {
         self.host_id
     }
 
+    pub fn set_host(&mut self, client_id: u64) {
+        self.host_id = client_id;
+    }
+
+    /// Picks the peer with the lowest Godot ID, out of those `eligible` accepts, as the next host.
+    /// Returns the client ID and Godot ID of that peer, if there is one.
+    pub fn next_host(&self, eligible: impl Fn(u64) -> bool) -> Option<(u64, i32)> {
+        self.godot_to_client.iter()
+            .filter(|(_, client_id)| eligible(**client_id))
+            .min_by_key(|(godot_id, _)| **godot_id)
+            .map(|(&godot_id, &client_id)| (client_id, godot_id))
+    }
+
+    /// Moves a peer's slot over to a new client ID, keeping its Godot ID.
+    /// Returns the Godot ID of the slot, if the old client was in the room.
+    pub fn replace_peer(&mut self, old_client_id: u64, new_client_id: u64) -> Option<i32> {
+        let godot_id = self.client_to_godot.remove(&old_client_id)?;
+        self.client_to_godot.insert(new_client_id, godot_id);
+        self.godot_to_client.insert(godot_id, new_client_id);
+
+        if let Some(token) = self.reconnect_tokens.remove(&old_client_id) {
+            self.reconnect_tokens.insert(new_client_id, token);
+        }
+
+        if self.host_id == old_client_id {
+            self.host_id = new_client_id;
+        }
+
+        Some(godot_id)
+    }
+
+    /// Returns true if this room was restored after a restart and its host hasn't reclaimed it yet.
+    pub fn is_awaiting_host(&self) -> bool {
+        self.pending_host.is_some()
+    }
+
+    /// Gets the stable ID and secret of the host that may reclaim this room, if it's awaiting one.
+    pub fn pending_host(&self) -> Option<(&str, &str)> {
+        self.pending_host.as_ref().map(|host| (host.stable_id.as_str(), host.secret.as_str()))
+    }
+
+    /// Hands a restored room back to its host.
+    /// Returns the host's Godot ID.
+    pub fn claim(&mut self, client_id: u64) -> i32 {
+        self.pending_host = None;
+        self.host_id = client_id;
+        self.add_peer(client_id)
+    }
+
+    /// Checks whether a client with the given round-trip time may join.
+    /// Clients that haven't been measured yet can't join a room with a limit.
+    pub fn accepts_rtt(&self, rtt: Option<Duration>) -> bool {
+        if self.max_join_rtt_ms == 0 {
+            return true;
+        }
+
+        rtt.is_some_and(|rtt| rtt <= Duration::from_millis(u64::from(self.max_join_rtt_ms)))
+    }
+
+    /// Restricts the room to the given stable client IDs.
+    /// An empty list removes the restriction.
+    pub fn set_allowlist(&mut self, ids: &[String]) {
+        self.allowlist = if ids.is_empty() {
+            None
+        } else {
+            Some(ids.iter().cloned().collect())
+        };
+    }
+
+    /// Checks whether a client with the given stable ID may join.
+    pub fn is_allowed(&self, stable_id: Option<&str>) -> bool {
+        match &self.allowlist {
+            None => true,
+            Some(allowlist) => stable_id.is_some_and(|id| allowlist.contains(id)),
+        }
+    }
+
     pub fn remove_peer(&mut self, renet_id: u64) {
         let Some(peer_id) = self.client_to_godot.remove(&renet_id) else {
             return;
         };
 
         self.godot_to_client.remove(&peer_id);
+        self.reconnect_tokens.remove(&renet_id);
+        self.peer_metadata.remove(&peer_id);
+    }
+
+    /// Stores the metadata a peer joined with.
+    pub fn set_peer_metadata(&mut self, godot_id: i32, metadata: String) {
+        self.peer_metadata.insert(godot_id, metadata);
+    }
+
+    /// Gets every peer's Godot ID and join metadata, ordered by Godot ID.
+    pub fn peer_list(&self) -> Vec<(i32, String)> {
+        let mut peers: Vec<(i32, String)> = self.godot_to_client.keys()
+            .map(|&godot_id| (godot_id, self.peer_metadata.get(&godot_id).cloned().unwrap_or_default()))
+            .collect();
+        peers.sort_by_key(|(godot_id, _)| *godot_id);
+        peers
+    }
+
+    /// Hands out a new reconnect token for a peer in the room, replacing any previous one.
+    pub fn issue_reconnect_token(&mut self, client_id: u64) -> String {
+        let token = secret::generate();
+        self.reconnect_tokens.insert(client_id, token.clone());
+        token
+    }
+
+    /// Gets the reconnect token of a peer in the room, if it was given one.
+    pub fn reconnect_token(&self, client_id: u64) -> Option<&str> {
+        self.reconnect_tokens.get(&client_id).map(String::as_str)
+    }
+
+    /// Removes a peer but keeps its slot for `grace`, so it can come back with its reconnect token.
+    pub fn depart_peer(&mut self, client_id: u64, grace: Duration) {
+        let Some(godot_id) = self.client_to_godot.get(&client_id).copied() else {
+            return;
+        };
+        let token = self.reconnect_tokens.get(&client_id).cloned();
+        let metadata = self.peer_metadata.get(&godot_id).cloned();
+
+        self.remove_peer(client_id);
+
+        let now = Instant::now();
+        self.departed.retain(|_, slot| slot.expires > now);
+
+        if let Some(token) = token {
+            self.departed.insert(token, DepartedSlot { godot_id, metadata, expires: now + grace });
+        }
+    }
+
+    /// Returns true if the given token belongs to a slot that can still be reclaimed.
+    pub fn has_departed_slot(&self, token: &str) -> bool {
+        self.departed.get(token).is_some_and(|slot| slot.expires > Instant::now())
+    }
+
+    /// Puts a client back into the slot it dropped out of.
+    /// Returns the slot's Godot ID, or `None` if the token is unknown or expired.
+    pub fn reclaim_slot(&mut self, token: &str, client_id: u64) -> Option<i32> {
+        let slot = self.departed.remove(token)?;
+        if slot.expires <= Instant::now() {
+            return None;
+        }
+
+        self.touch();
+        self.client_to_godot.insert(client_id, slot.godot_id);
+        self.godot_to_client.insert(slot.godot_id, client_id);
+        if let Some(metadata) = slot.metadata {
+            self.peer_metadata.insert(slot.godot_id, metadata);
+        }
+        Some(slot.godot_id)
     }
 }
 
-#[derive(Default)]
 pub struct Rooms {
     by_id: HashMap<u64, Room>,
     jc_to_id: HashMap<String, u64>,
@@ -112,22 +421,90 @@ pub struct Rooms {
 }
 
 impl Rooms {
-    pub fn new() -> Self {
-        Self::default()
+    pub fn new(join_code_format: JoinCodeFormat) -> Self {
+        Self {
+            by_id: HashMap::new(),
+            jc_to_id: HashMap::new(),
+            next_id: 0,
+            join_codes: RoomIds::new(join_code_format),
+        }
     }
 
     /// Creates a new room based on the given parameters.
     /// Returns a mutable reference to the new `Room`.
-    pub fn create(&mut self, host_id: u64, is_public: bool, metadata: String) -> &mut Room {
+    pub fn create(&mut self, host_id: u64, is_public: bool, metadata: RoomMetadata, max_players: i32) -> &mut Room {
         let room_id = self.next_id;
         self.next_id += 1;
 
         let join_code = self.join_codes.generate();
-        let room = Room::new(room_id, join_code.clone(), host_id, is_public, metadata);
+        let room = Room::new(room_id, join_code.clone(), host_id, is_public, metadata, max_players);
         self.jc_to_id.insert(join_code, room_id);
         self.by_id.entry(room_id).or_insert(room)
     }
 
+    /// Recreates a room from the room store, keeping its join code.
+    /// The room waits for its host to reclaim it until `deadline`.
+    /// Returns `None` if the join code is already in use.
+    pub fn restore(&mut self, stored: &StoredRoom, deadline: Instant) -> Option<&mut Room> {
+        if !self.join_codes.reserve(&stored.join_code) {
+            return None;
+        }
+
+        let room_id = self.next_id;
+        self.next_id += 1;
+
+        let mut room = Room::new(
+            room_id,
+            stored.join_code.clone(),
+            0,
+            stored.is_public,
+            stored.metadata.clone(),
+            stored.max_players,
+        );
+        room.max_join_rtt_ms = stored.max_join_rtt_ms;
+        room.pending_host = Some(PendingHost {
+            stable_id: stored.host_stable_id.clone(),
+            secret: stored.host_secret.clone(),
+            deadline,
+        });
+
+        self.jc_to_id.insert(stored.join_code.clone(), room_id);
+        Some(self.by_id.entry(room_id).or_insert(room))
+    }
+
+    /// Finds a restored room waiting on the host with the given stable ID, if `host_secret` is the one it was saved with.
+    pub fn find_restored(&mut self, stable_id: &str, host_secret: &str) -> Option<&mut Room> {
+        self.by_id.values_mut()
+            .find(|room| room.pending_host.as_ref().is_some_and(|host| {
+                host.stable_id == stable_id && secret::matches(&host.secret, host_secret)
+            }))
+    }
+
+    /// Finds the room holding a departed slot for the given reconnect token.
+    pub fn find_by_reconnect_token(&mut self, token: &str) -> Option<&mut Room> {
+        self.by_id.values_mut()
+            .find(|room| room.departed.contains_key(token))
+    }
+
+    /// Gets the IDs of restored rooms whose host didn't come back in time.
+    pub fn expired_restores(&self) -> Vec<u64> {
+        let now = Instant::now();
+        self.by_id.values()
+            .filter(|room| room.pending_host.as_ref().is_some_and(|host| now >= host.deadline))
+            .map(|room| room.id)
+            .collect()
+    }
+
+    /// Returns the number of rooms stored.
+    pub fn len(&self) -> usize {
+        self.by_id.len()
+    }
+
+    /// Returns true if no rooms are stored.
+    pub fn is_empty(&self) -> bool {
+        self.by_id.is_empty()
+    }
+
     /// Gets an iterator for all `Room`'s stored.
     pub fn iter(&self) -> impl Iterator<Item = &Room> {
         self.by_id.values()
@@ -138,6 +515,17 @@ impl Rooms {
         self.by_id.values_mut()
     }
 
+    /// Gets the public rooms whose metadata matches `filter`, oldest first.
+    /// The order is stable so the list can be paged through.
+    /// Restored rooms are left out until their host is back.
+    pub fn public_rooms_matching(&self, filter: &str) -> Vec<&Room> {
+        let mut rooms: Vec<&Room> = self.by_id.values()
+            .filter(|room| room.is_public && !room.is_awaiting_host() && room.matches_filter(filter))
+            .collect();
+        rooms.sort_by_key(|room| room.id);
+        rooms
+    }
+
     /// Gets a reference to a room by an ID
     pub fn get(&self, id: u64) -> Option<&Room> {
         self.by_id.get(&id)
@@ -149,6 +537,7 @@ impl Rooms {
     }
 
     /// Gets a reference to a room by a join code.
+    /// Only this app's rooms are searched, a code from another app never matches.
     /// Prefer `get` whenever possible as this requires 2 lookups.
     pub fn get_by_jc(&self, jc: &str) -> Option<&Room> {
         let id = self.jc_to_id.get(jc)?;
@@ -162,6 +551,15 @@ impl Rooms {
         self.by_id.get_mut(id)
     }
 
+    /// Gets the IDs of rooms that have been idle for longer than `timeout`.
+    pub fn idle_rooms(&self, timeout: Duration) -> Vec<u64> {
+        let now = Instant::now();
+        self.by_id.values()
+            .filter(|room| now.duration_since(room.last_activity) > timeout)
+            .map(|room| room.id)
+            .collect()
+    }
+
     /// Removes a room under an ID.
     /// Also frees the join code from the generator.
     pub fn remove(&mut self, id: u64) -> Option<Room> {
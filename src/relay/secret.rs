@@ -0,0 +1,21 @@
+use std::hint::black_box;
+use rand::{rng, Rng};
+
+/// Generates a random secret for a client to prove who it is later, as 32 hex characters.
+pub fn generate() -> String {
+    format!("{:032x}", rng().random::<u128>())
+}
+
+/// Compares a secret the client sent with the one on record, in time that doesn't depend on where they differ.
+/// An empty secret never matches, so a client that was never given one can't match one that wasn't set.
+pub fn matches(expected: &str, given: &str) -> bool {
+    if expected.is_empty() || expected.len() != given.len() {
+        return false;
+    }
+
+    let diff = expected.bytes()
+        .zip(given.bytes())
+        .fold(0u8, |diff, (a, b)| black_box(diff | (a ^ b)));
+
+    diff == 0
+}
@@ -1,47 +1,281 @@
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::time::Duration;
-use tracing::{debug, info, warn};
-use crate::config::loader::Config;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use crate::admin::{AdminCommand, AdminRequest};
+use crate::config::loader::{load_config, Config, CONFIG_PATH};
+use crate::health::stats::StatsSnapshot;
+use crate::metrics::METRICS;
+use crate::protocol::builder::PacketBuilder;
+use crate::protocol::error_code::ErrorCode;
 use crate::protocol::packet::Packet;
+use crate::protocol::version::WIRE_VERSION;
+use crate::registry::client::{HeartbeatRoom, RegistryClient, RoomLookup};
+use crate::registry::tls;
 use crate::relay::apps::Apps;
 use crate::relay::clients::{ClientState, Clients};
-use crate::relay::handlers::auth::AuthHandler;
+use crate::relay::handlers::auth::{AuthCheck, AuthHandler};
 use crate::relay::handlers::disconnect::DisconnectHandler;
+use crate::relay::handlers::error::{HandlerError, HandlerResult};
 use crate::relay::handlers::game_data::GameDataHandler;
 use crate::relay::handlers::room::RoomHandler;
-use crate::udp::common::{TransferChannel, ServerEvent};
+use crate::relay::rate_limit::RateLimiter;
+use crate::relay::rooms::JoinCodeFormat;
+use crate::relay::store::{FileRoomStore, RoomStore, StoredRoom};
+use crate::udp::common::{DisconnectReason, TransferChannel, ServerEvent};
+use crate::udp::log_addr;
 use crate::udp::paper_interface::PaperInterface;
+use crate::udp::socket::DatagramSocket;
 
-pub struct RelayServer {
-    udp: PaperInterface,
+/// How late a timer tick can fire before the loop is considered overloaded.
+const TICK_DELAY_WARNING: Duration = Duration::from_millis(250);
+/// How often a single client can request version info before authenticating.
+const VERSION_INFO_COOLDOWN: Duration = Duration::from_secs(1);
+/// How often a client sending `GameData` outside of a room is told about it.
+const NOT_IN_ROOM_ERROR_COOLDOWN: Duration = Duration::from_secs(5);
+/// How many admin commands can be waiting on the server loop at once.
+const ADMIN_QUEUE_LEN: usize = 16;
+/// How many finished registry lookups can be waiting on the server loop at once.
+const LOOKUP_QUEUE_LEN: usize = 64;
+/// How many finished remote whitelist checks can be waiting on the server loop at once.
+const AUTH_CHECK_QUEUE_LEN: usize = 64;
+/// How long an outgoing HTTP request can take before it's abandoned.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct RelayServer<S = UdpSocket> {
+    udp: PaperInterface<S>,
     http_client: reqwest::Client,
+    registry: RegistryClient,
 
     config: Config,
     apps: Apps,
     clients: Clients,
+    /// Sessions turned away at `max_clients`, until their session closes.
+    rejected: HashSet<u64>,
+    stats: watch::Sender<StatsSnapshot>,
+    auth_limiter: RateLimiter<IpAddr>,
+    not_in_room_limiter: RateLimiter<u64>,
+    /// Join requests per client and join code, so a joiner can't keep pestering the same host.
+    join_limiter: RateLimiter<(u64, String)>,
+    room_store: Option<Box<dyn RoomStore>>,
+    /// The rooms as of the last save, so the store is only written when something changed.
+    saved_rooms: Vec<StoredRoom>,
+    last_registry_heartbeat: Instant,
+    /// Commands from the admin listener. The server keeps a sender so the channel never closes.
+    admin_tx: mpsc::Sender<AdminRequest>,
+    admin_rx: mpsc::Receiver<AdminRequest>,
+    /// Registry lookups for join requests, answered once they finish.
+    room_lookups: mpsc::Receiver<RoomLookup>,
+    /// Remote whitelist checks for authenticating clients. The server keeps a sender to hand to `AuthHandler`.
+    auth_checks_tx: mpsc::Sender<AuthCheck>,
+    auth_checks: mpsc::Receiver<AuthCheck>,
 }
 
-impl RelayServer {
-    pub fn new(transport: PaperInterface, config: Config) -> Self {
-        Self {
+impl<S: DatagramSocket> RelayServer<S> {
+    /// Fails if the registry's TLS setup can't be loaded.
+    pub fn new(mut transport: PaperInterface<S>, config: Config) -> Result<Self, Box<dyn Error>> {
+        transport.set_new_connection_limit(
+            config.new_connection_limit,
+            Duration::from_millis(config.new_connection_window_ms),
+        );
+        transport.set_cookie_limit(
+            config.cookie_limit_per_ip,
+            Duration::from_millis(config.new_connection_window_ms),
+        );
+        transport.set_denylist(config.denied_networks());
+
+        let http_client = reqwest::Client::builder().timeout(HTTP_TIMEOUT).build()?;
+        let registry_tls = tls::client_config(&config)
+            .map_err(|e| format!("failed to set up TLS for the registry: {e}"))?;
+        let (lookup_tx, room_lookups) = mpsc::channel(LOOKUP_QUEUE_LEN);
+        let registry = RegistryClient::new(http_client.clone(), &config, registry_tls, lookup_tx)?;
+        let auth_limiter = RateLimiter::new(
+            config.auth_attempt_limit,
+            Duration::from_millis(config.auth_attempt_window_ms),
+        );
+        let join_limiter = RateLimiter::new(
+            config.join_attempt_limit,
+            Duration::from_millis(config.join_attempt_window_ms),
+        );
+
+        let room_store: Option<Box<dyn RoomStore>> = if config.room_store_path.is_empty() {
+            None
+        } else {
+            Some(Box::new(FileRoomStore::new(&config.room_store_path)))
+        };
+
+        let join_code_format = JoinCodeFormat::from_config(&config);
+        let (admin_tx, admin_rx) = mpsc::channel(ADMIN_QUEUE_LEN);
+        let (auth_checks_tx, auth_checks) = mpsc::channel(AUTH_CHECK_QUEUE_LEN);
+
+        let mut server = Self {
             udp: transport,
-            http_client: reqwest::Client::new(),
+            http_client,
+            registry,
             config,
-            apps: Apps::new(),
+            apps: Apps::new(join_code_format),
             clients: Clients::new(),
+            rejected: HashSet::new(),
+            stats: watch::Sender::new(StatsSnapshot::default()),
+            auth_limiter,
+            not_in_room_limiter: RateLimiter::new(1, NOT_IN_ROOM_ERROR_COOLDOWN),
+            join_limiter,
+            room_store,
+            saved_rooms: Vec::new(),
+            last_registry_heartbeat: Instant::now(),
+            admin_tx,
+            admin_rx,
+            room_lookups,
+            auth_checks_tx,
+            auth_checks,
+        };
+
+        server.restore_rooms();
+        Ok(server)
+    }
+
+    /// Recreates the rooms saved in the room store, so their hosts can reclaim them.
+    fn restore_rooms(&mut self) {
+        let Some(store) = self.room_store.as_mut() else {
+            return;
+        };
+
+        let stored = match store.load() {
+            Ok(rooms) => rooms,
+            Err(e) => {
+                error!("failed to load rooms from the room store: {}", e);
+                return;
+            }
+        };
+
+        let deadline = Instant::now() + Duration::from_millis(self.config.room_restore_grace_ms);
+        let mut restored = 0;
+
+        for room in &stored {
+            let app_id = match self.apps.get_by_token(&room.app_token) {
+                Some(app) => app.id,
+                None => self.apps.create(room.app_token.clone()),
+            };
+
+            let Some(app) = self.apps.get_mut(app_id) else {
+                continue;
+            };
+
+            if app.rooms.restore(room, deadline).is_none() {
+                warn!("skipping restored room {} with a duplicate join code", room.join_code);
+                continue;
+            }
+
+            METRICS.room_opened();
+            self.registry.spawn_register_room(&room.app_token, &room.join_code);
+            restored += 1;
+        }
+
+        self.saved_rooms = stored;
+        info!("restored {} rooms from the room store", restored);
+    }
+
+    /// Saves the current rooms to the room store if they changed since the last save.
+    /// Rooms whose host has no stable ID can't be reclaimed, so they aren't saved.
+    fn save_rooms(&mut self) {
+        let Some(store) = self.room_store.as_mut() else {
+            return;
+        };
+
+        let mut rooms: Vec<StoredRoom> = Vec::new();
+        for app in self.apps.iter() {
+            for room in app.rooms.iter() {
+                let host = match room.pending_host() {
+                    Some(host) => Some(host),
+                    None => self.clients.get(room.get_host())
+                        .and_then(|c| Some((c.stable_id.as_deref()?, c.resume_token.as_deref()?))),
+                };
+
+                let Some((host_stable_id, host_secret)) = host else {
+                    continue;
+                };
+
+                rooms.push(StoredRoom {
+                    app_token: app.token.clone(),
+                    join_code: room.join_code.clone(),
+                    host_stable_id: host_stable_id.to_string(),
+                    host_secret: host_secret.to_string(),
+                    is_public: room.is_public,
+                    metadata: room.metadata.clone(),
+                    max_players: room.max_players,
+                    max_join_rtt_ms: room.max_join_rtt_ms,
+                });
+            }
+        }
+
+        rooms.sort_by(|a, b| (&a.app_token, &a.join_code).cmp(&(&b.app_token, &b.join_code)));
+        if rooms == self.saved_rooms {
+            return;
+        }
+
+        if let Err(e) = store.save(&rooms) {
+            error!("failed to save rooms to the room store: {}", e);
+            return;
+        }
+
+        self.saved_rooms = rooms;
+    }
+
+    /// Touches every open room in the registry once per `registry_heartbeat_interval_ms`.
+    fn registry_heartbeat(&mut self) {
+        let interval = Duration::from_millis(self.config.registry_heartbeat_interval_ms);
+        if interval.is_zero() || self.last_registry_heartbeat.elapsed() < interval {
+            return;
         }
+        self.last_registry_heartbeat = Instant::now();
+
+        let rooms: Vec<HeartbeatRoom> = self.apps.iter()
+            .flat_map(|app| app.rooms.iter().map(|room| HeartbeatRoom {
+                app_id: app.token.clone(),
+                join_code: room.join_code.clone(),
+            }))
+            .collect();
+
+        if !rooms.is_empty() {
+            self.registry.spawn_heartbeat(rooms);
+        }
+    }
+
+    /// Returns a receiver for the stats snapshots published by the server loop.
+    pub fn stats(&self) -> watch::Receiver<StatsSnapshot> {
+        self.stats.subscribe()
+    }
+
+    /// Returns a sender for commands from the admin listener, run by the server loop.
+    pub fn admin(&self) -> mpsc::Sender<AdminRequest> {
+        self.admin_tx.clone()
     }
 
     /// Starts the server loop.
     pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
-        // TODO: remove magic numbers
-        let mut cleanup = tokio::time::interval(Duration::from_secs(1));
-        // TODO: remove magic numbers
-        let mut resend  = tokio::time::interval(Duration::from_millis(50));
+        let timing = self.config.timing.clone();
+        let session_timeout = Duration::from_millis(timing.session_timeout_ms);
+        let resend_window = Duration::from_millis(timing.resend_window_ms);
+        let room_idle_timeout = Duration::from_millis(timing.room_idle_timeout_ms);
+        let keepalive_interval = Duration::from_millis(timing.keepalive_interval_ms);
+        let heartbeat = Packet::Heartbeat.to_bytes();
+
+        let mut cleanup = tokio::time::interval(Duration::from_millis(timing.cleanup_interval_ms));
+        let mut resend  = tokio::time::interval(Duration::from_millis(timing.resend_interval_ms));
 
-        cleanup.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        // Cleanup only needs to happen roughly once per interval, so after a stall
+        // the schedule is pushed back rather than sweeping several times in a row.
+        cleanup.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // Resends must stay on their fixed cadence, but catching up on missed ticks
+        // would just burst duplicate resends, so those are skipped.
         resend.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
 
+        let mut hangup = signal(SignalKind::hangup())?;
+
         loop {
             tokio::select! {
                 result = self.udp.recv_events() => {
@@ -51,18 +285,288 @@ impl RelayServer {
                     }
                 }
 
-                _ = cleanup.tick() => {
-                    // TODO: remove magic numbers
-                    for client_id in self.udp.connection_manager.cleanup_sessions(Duration::from_secs(5)) {
-                        self.handle_event(ServerEvent::ClientDisconnected { client_id }).await;
+                scheduled = cleanup.tick() => {
+                    warn_if_late("cleanup", scheduled);
+
+                    for client_id in self.udp.connection_manager.cleanup_sessions(session_timeout) {
+                        self.handle_event(ServerEvent::ClientDisconnected {
+                            client_id,
+                            reason: DisconnectReason::Timeout,
+                        }).await;
+                    }
+
+                    if !keepalive_interval.is_zero() {
+                        self.udp.send_keepalives(keepalive_interval, &heartbeat).await;
+                    }
+
+                    if !room_idle_timeout.is_zero() {
+                        self.close_idle_rooms(room_idle_timeout).await;
+                    }
+
+                    self.close_unclaimed_rooms().await;
+                    self.save_rooms();
+                    self.expire_join_requests().await;
+                    self.registry_heartbeat();
+
+                    self.probe_rtt().await;
+                    self.auth_limiter.prune();
+                    self.not_in_room_limiter.prune();
+                    self.join_limiter.prune();
+                    self.udp.prune_cookie_replies();
+                    self.publish_stats();
+                }
+
+                scheduled = resend.tick() => {
+                    warn_if_late("resend", scheduled);
+
+                    self.udp.do_resends(resend_window).await;
+                    self.drop_saturated_clients().await;
+                }
+
+                _ = hangup.recv() => {
+                    self.reload_config().await;
+                }
+
+                Some(request) = self.admin_rx.recv() => {
+                    let reply = self.run_admin_command(request.command).await;
+                    // The admin connection may have closed while waiting, so there's no one to tell.
+                    let _ = request.reply.send(reply);
+                }
+
+                Some(lookup) = self.room_lookups.recv() => {
+                    RoomHandler::new(
+                        &mut self.udp,
+                        &mut self.apps,
+                        &mut self.clients,
+                        &self.registry,
+                        &self.config,
+                    ).finish_room_lookup(lookup).await;
+                }
+
+                Some(check) = self.auth_checks.recv() => {
+                    let result = AuthHandler::new(
+                        &mut self.udp,
+                        &self.http_client,
+                        &self.auth_checks_tx,
+                        &mut self.clients,
+                        &mut self.apps,
+                        &self.config
+                    ).finish_authentication(check).await;
+
+                    if let Err(e) = result {
+                        METRICS.record_handler_error();
+                        warn!("failed to finish authentication: {}", e);
                     }
                 }
+            }
+        }
+    }
+
+    /// Closes every room that has been idle for longer than `timeout`.
+    async fn close_idle_rooms(&mut self, timeout: Duration) {
+        let idle: Vec<(u64, u64)> = self.apps.iter()
+            .flat_map(|app| app.rooms.idle_rooms(timeout).into_iter().map(|room_id| (app.id, room_id)))
+            .collect();
+
+        if idle.is_empty() {
+            return;
+        }
+
+        let mut dh = DisconnectHandler::new(
+            &mut self.udp,
+            &mut self.clients,
+            &mut self.apps,
+            &self.registry,
+            &self.config,
+        );
+
+        for (app_id, room_id) in idle {
+            dh.close_room(app_id, room_id).await;
+        }
+    }
+
+    /// Closes restored rooms whose host didn't come back within the grace period.
+    async fn close_unclaimed_rooms(&mut self) {
+        let expired: Vec<(u64, u64)> = self.apps.iter()
+            .flat_map(|app| app.rooms.expired_restores().into_iter().map(|room_id| (app.id, room_id)))
+            .collect();
+
+        let mut dh = DisconnectHandler::new(
+            &mut self.udp,
+            &mut self.clients,
+            &mut self.apps,
+            &self.registry,
+            &self.config,
+        );
+
+        for (app_id, room_id) in expired {
+            dh.close_room(app_id, room_id).await;
+        }
+    }
+
+    /// Disconnects clients that have stopped acking reliable packets.
+    /// Everything sent to them is held for resends, so they'd otherwise grow without bound.
+    async fn drop_saturated_clients(&mut self) {
+        let max = self.config.max_unacked_reliable_bytes;
+        if max == 0 {
+            return;
+        }
+
+        for client_id in self.udp.connection_manager.saturated_sessions(max) {
+            warn!("disconnecting client {}, it has over {} bytes of reliable data unacked", client_id, max);
+            METRICS.record_saturated_disconnect();
+
+            self.handle_disconnect(client_id, DisconnectReason::Saturated).await;
+            // It isn't acking, so nothing more is sent to it.
+            self.udp.remove_client(client_id);
+        }
+    }
+
+    /// Drops join requests the host never answered and lets the clients that sent them know.
+    async fn expire_join_requests(&mut self) {
+        for client_id in self.clients.take_expired_joins() {
+            self.udp.send_err(client_id, ErrorCode::Timeout, "Join request timed out").await;
+        }
+    }
+
+    /// Reloads the config from disk (or the environment) without dropping any clients.
+    /// Settings that can't change while running are left as they were.
+    async fn reload_config(&mut self) {
+        dotenvy::dotenv_override().ok();
+
+        let new_config = match load_config(CONFIG_PATH) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("failed to reload config, keeping the current one: {}", e);
+                return;
+            }
+        };
+
+        for field in self.config.reload_from(new_config) {
+            warn!("config field `{}` changed but requires a restart, ignoring", field);
+        }
+
+        self.auth_limiter.set_limits(
+            self.config.auth_attempt_limit,
+            Duration::from_millis(self.config.auth_attempt_window_ms),
+        );
+        self.join_limiter.set_limits(
+            self.config.join_attempt_limit,
+            Duration::from_millis(self.config.join_attempt_window_ms),
+        );
+        self.udp.set_new_connection_limit(
+            self.config.new_connection_limit,
+            Duration::from_millis(self.config.new_connection_window_ms),
+        );
+        self.udp.set_cookie_limit(
+            self.config.cookie_limit_per_ip,
+            Duration::from_millis(self.config.new_connection_window_ms),
+        );
+        self.udp.set_denylist(self.config.denied_networks());
+        log_addr::set_anonymize(self.config.anonymize_log_addresses);
+        self.kick_denied_clients().await;
+
+        info!("config reloaded");
+    }
+
+    /// Disconnects every client whose address is in the denylist.
+    async fn kick_denied_clients(&mut self) {
+        for client_id in self.udp.connection_manager.denied_sessions() {
+            info!("disconnecting client {}, its address is denied", client_id);
+            self.kick_client(client_id).await;
+        }
+    }
+
+    /// Disconnects a client straight away, cleaning up after it and telling it why.
+    async fn kick_client(&mut self, client_id: u64) {
+        self.handle_disconnect(client_id, DisconnectReason::Kicked).await;
+        DisconnectHandler::new(
+            &mut self.udp,
+            &mut self.clients,
+            &mut self.apps,
+            &self.registry,
+            &self.config,
+        ).force_disconnect(client_id).await;
+    }
+
+    /// Runs a command from the admin listener and returns the text to reply with.
+    async fn run_admin_command(&mut self, command: AdminCommand) -> String {
+        match command {
+            AdminCommand::ListRooms => {
+                let rooms: Vec<String> = self.apps.iter()
+                    .flat_map(|app| app.rooms.iter().map(move |room| format!(
+                        "{} {} players={}/{} host={}{}",
+                        app.token,
+                        room.join_code,
+                        room.peer_count(),
+                        room.max_players,
+                        room.get_host(),
+                        if room.locked { " locked" } else { "" },
+                    )))
+                    .collect();
+
+                if rooms.is_empty() {
+                    "no rooms".to_string()
+                } else {
+                    rooms.join("\n")
+                }
+            }
+            AdminCommand::CloseRoom { join_code, app } => {
+                let matches: Vec<(u64, u64)> = self.apps.iter()
+                    .filter(|candidate| app.as_ref().is_none_or(|token| *token == candidate.token))
+                    .filter_map(|app| app.rooms.get_by_jc(&join_code).map(|room| (app.id, room.id)))
+                    .collect();
+
+                let (app_id, room_id) = match matches.as_slice() {
+                    [] => return format!("error: no room with join code {join_code}"),
+                    [found] => *found,
+                    _ => return format!(
+                        "error: {} apps have a room with join code {join_code}, use close-room {join_code} <app>",
+                        matches.len(),
+                    ),
+                };
+
+                DisconnectHandler::new(
+                    &mut self.udp,
+                    &mut self.clients,
+                    &mut self.apps,
+                    &self.registry,
+                    &self.config,
+                ).close_room(app_id, room_id).await;
 
-                _ = resend.tick() => {
-                    // TODO: remove magic numbers
-                    self.udp.do_resends(Duration::from_millis(100)).await;
+                format!("closed room {join_code}")
+            }
+            AdminCommand::Kick { client_id } => {
+                if self.clients.get(client_id).is_none() {
+                    return format!("error: no client {client_id}");
                 }
+
+                self.kick_client(client_id).await;
+                format!("kicked client {client_id}")
             }
+            AdminCommand::Stats => {
+                let stats = self.stats_snapshot();
+                format!("clients={} apps={} rooms={}", stats.clients, stats.apps, stats.rooms)
+            }
+        }
+    }
+
+    /// Publishes a fresh stats snapshot for the health server.
+    fn publish_stats(&self) {
+        self.stats.send_replace(self.stats_snapshot());
+    }
+
+    fn stats_snapshot(&self) -> StatsSnapshot {
+        // App tokens double as credentials, so apps are listed by the ID they're logged with instead.
+        let rooms_per_app: HashMap<String, usize> = self.apps.iter()
+            .map(|app| (app.id.to_string(), app.rooms.len()))
+            .collect();
+
+        StatsSnapshot {
+            clients: self.clients.len(),
+            apps: self.apps.len(),
+            rooms: rooms_per_app.values().sum(),
+            rooms_per_app,
         }
     }
 
@@ -70,25 +574,52 @@ impl RelayServer {
     async fn handle_event(&mut self, event: ServerEvent) {
         match event {
             ServerEvent::ClientConnected { client_id } => {
+                if self.clients.len() >= self.config.max_clients {
+                    self.reject_full(client_id).await;
+                    return;
+                }
+
                 self.clients.create(client_id);
             }
-            ServerEvent::ClientDisconnected { client_id } => {
-                DisconnectHandler::new(
-                    &mut self.udp,
-                    &mut self.clients,
-                    &mut self.apps,
-                ).handle_disconnect(client_id).await;
+            ServerEvent::ClientDisconnected { client_id, reason } => {
+                self.handle_disconnect(client_id, reason)
+                    .instrument(info_span!("client", client_id))
+                    .await;
             }
             ServerEvent::PacketReceived { client_id, data, channel } => {
-                debug!("got packet: {:?}", data);
-                self.handle_packet(client_id, data, channel).await;
+                // Everything logged while handling the packet is scoped to the client,
+                // and further to its app/room once it has one.
+                async {
+                    if self.config.opaque_forwarding {
+                        debug!("got packet of {} bytes", data.len());
+                    } else {
+                        debug!("got packet: {:?}", data);
+                    }
+                    self.handle_packet(client_id, data, channel).await;
+                }
+                    .instrument(info_span!("client", client_id))
+                    .await;
             }
         }
     }
 
+    /// Turns away a new connection because the relay is at `max_clients`.
+    /// The session is closed gracefully so the error has a chance to arrive.
+    async fn reject_full(&mut self, client_id: u64) {
+        warn!("rejecting client {}, server is full ({} clients)", client_id, self.clients.len());
+        self.rejected.insert(client_id);
+        self.udp.send_err(client_id, ErrorCode::ServerFull, "Server full").await;
+        self.udp.disconnect_client(client_id);
+    }
+
     /// Handles a packet received from `PaperUDP`.
     /// This checks the state of the client and routes packets based on the state.
     async fn handle_packet(&mut self, from_client_id: u64, data: Vec<u8>, channel: TransferChannel) {
+        if self.rejected.contains(&from_client_id) {
+            debug!("ignoring packet from {}, it was rejected because the server is full", from_client_id);
+            return;
+        }
+
         let Some(client) = self.clients.get(from_client_id) else {
             // This means that the client is not in the list of connected clients.
             // Likely a bug in the client or a malicious client.
@@ -101,116 +632,1733 @@ impl RelayServer {
             return;
         };
 
-        match client.state {
+        // Clients can leave from any state
+        if let Packet::Disconnect = packet {
+            self.handle_client_leave(from_client_id).await;
+            return;
+        }
+
+        // Receiving a heartbeat already kept the session alive, there's nothing else to do
+        if let Packet::Heartbeat = packet {
+            return;
+        }
+
+        if self.config.require_reliable_control && channel != TransferChannel::Reliable && packet.is_control() {
+            warn!("rejecting control packet from {} sent over the unreliable channel: {:?}.", from_client_id, packet);
+            self.udp.send_err(from_client_id, ErrorCode::BadRequest, "Control packets must be sent reliably").await;
+            return;
+        }
+
+        let result = match client.state {
             ClientState::Connected => self.handle_unauthenticated_packet(from_client_id, &packet).await,
-            ClientState::Authenticated { app_id } => self.handle_authenticated_packet(from_client_id, app_id, &packet).await,
-            ClientState::InRoom { app_id, room_id } => self.handle_in_room_packet(from_client_id, app_id, room_id, &packet, &channel).await
+            ClientState::Authenticating => {
+                self.handle_authenticating_packet(from_client_id, &packet).await;
+                Ok(())
+            }
+            ClientState::Authenticated { app_id } => {
+                self.handle_authenticated_packet(from_client_id, app_id, &packet)
+                    .instrument(info_span!("app", app_id))
+                    .await
+            }
+            ClientState::InRoom { app_id, room_id } => {
+                self.handle_in_room_packet(from_client_id, app_id, room_id, &packet, &channel)
+                    .instrument(info_span!("room", app_id, room_id))
+                    .await
+            }
+        };
+
+        // These are bugs in the relay rather than the client. The client is only told when the app or room
+        // it's in has gone, since nothing it sends there can succeed.
+        if let Err(e) = result {
+            METRICS.record_handler_error();
+            warn!("failed to handle packet from {}: {}", from_client_id, e);
+
+            if e.is_stale_client_state() {
+                self.udp.send_err(from_client_id, ErrorCode::Gone, "Your room no longer exists").await;
+                self.clear_stale_state(from_client_id);
+            }
+        }
+    }
+
+    /// Forgets the room a client was in after it turned out to be gone, so the client is only told once.
+    /// It goes back to the lobby of its app, or has to authenticate again if the app is gone too.
+    fn clear_stale_state(&mut self, client_id: u64) {
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return;
+        };
+
+        client.state = match client.state.app_id() {
+            Some(app_id) if self.apps.get(app_id).is_some() => ClientState::Authenticated { app_id },
+            _ => ClientState::Connected,
+        };
+    }
+
+    /// Runs the disconnect path for a client and clears every piece of per-client state.
+    /// Per-client state lives in:
+    /// - `Clients` and the app/room it belongs to (cleared by `DisconnectHandler`)
+    /// - the UDP session (already gone, or removed by the caller)
+    /// - `not_in_room_limiter` and `join_limiter`, keyed by client ID
+    ///
+    /// `auth_limiter` is keyed by address on purpose, so it outlives the client.
+    async fn handle_disconnect(&mut self, client_id: u64, reason: DisconnectReason) {
+        if self.rejected.remove(&client_id) {
+            info!("closed connection {} rejected because the server was full: {:?}", client_id, reason);
+            return;
         }
+
+        info!("client {} disconnected: {:?}", client_id, reason);
+        METRICS.record_disconnect(reason);
+
+        DisconnectHandler::new(
+            &mut self.udp,
+            &mut self.clients,
+            &mut self.apps,
+            &self.registry,
+            &self.config,
+        ).handle_disconnect(client_id).await;
+
+        self.not_in_room_limiter.remove(&client_id);
+        self.join_limiter.remove_where(|(id, _)| *id == client_id);
+    }
+
+    /// Handles a client that told us it's leaving.
+    /// This runs the normal disconnect path straight away instead of waiting for the session to time out.
+    async fn handle_client_leave(&mut self, client_id: u64) {
+        self.handle_disconnect(client_id, DisconnectReason::Graceful).await;
+
+        // The client is gone, so there's no point resending anything to it.
+        self.udp.remove_client(client_id);
     }
 
     /// Delegates packets to various handlers when the client has yet to authenticate.
-    async fn handle_unauthenticated_packet(&mut self, from_client_id: u64, packet: &Packet) {
+    async fn handle_unauthenticated_packet(&mut self, from_client_id: u64, packet: &Packet) -> HandlerResult {
         match packet {
-            Packet::Authenticate { app_id, version } => {
+            Packet::Connect { protocol_version, .. } => {
+                self.accept_connect(from_client_id, *protocol_version).await;
+                Ok(())
+            }
+            Packet::Authenticate { app_id, version, stable_id, resume_token } => {
+                if !self.check_auth_rate(from_client_id).await {
+                    return Ok(());
+                }
+
                 AuthHandler::new(
                     &mut self.udp,
                     &self.http_client,
+                    &self.auth_checks_tx,
                     &mut self.clients,
                     &mut self.apps,
                     &self.config
-                ).authenticate_client(from_client_id, app_id, version).await;
+                ).authenticate_client(from_client_id, app_id, version, stable_id, resume_token).await
+            }
+            Packet::ReqVersionInfo => {
+                self.send_version_info(from_client_id).await;
+                Ok(())
             }
             _ => {
                 // TODO: should probably alert the client that they need to authenticate first!
                 warn!("unexpected packet type from {} in un-authenticated state: {:?}.", from_client_id, packet);
+                Ok(())
             }
         }
     }
 
+    /// Handles packets from a client whose app is still being checked.
+    /// A second `Authenticate` is turned away rather than starting another check.
+    async fn handle_authenticating_packet(&mut self, from_client_id: u64, packet: &Packet) {
+        if let Packet::Authenticate { .. } = packet {
+            warn!("{} tried to authenticate while already authenticating", from_client_id);
+            self.udp.send_err(from_client_id, ErrorCode::Conflict, "Authentication already in progress").await;
+        } else {
+            warn!("ignoring packet from {} while authentication is in progress: {:?}.", from_client_id, packet);
+        }
+    }
+
     /// Delegates packets to various handlers when the client is authenticated, but not in a room.
-    async fn handle_authenticated_packet(&mut self, from_client_id: u64, client_app_id: u64, packet: &Packet) {
+    async fn handle_authenticated_packet(&mut self, from_client_id: u64, client_app_id: u64, packet: &Packet) -> HandlerResult {
+        let throttled = match packet {
+            Packet::ReqJoin { room_id, .. } => !self.check_join_rate(from_client_id, room_id).await,
+            _ => false,
+        };
+
+        if throttled {
+            return Ok(());
+        }
+
         let mut rh = RoomHandler::new(
             &mut self.udp,
             &mut self.apps,
             &mut self.clients,
+            &self.registry,
+            &self.config,
         );
 
         match packet {
-            Packet::CreateRoom { is_public, metadata } =>
-                rh.create_room(from_client_id, client_app_id, *is_public, metadata).await,
-            Packet::ReqJoin { room_id, metadata } =>
-                rh.recv_join_req(from_client_id, client_app_id, room_id, metadata).await,
-            Packet::ReqRooms =>
-                rh.send_rooms(from_client_id, client_app_id).await,
+            Packet::CreateRoom { is_public, metadata, max_players, max_join_rtt_ms } =>
+                rh.create_room(from_client_id, client_app_id, *is_public, metadata, *max_players, *max_join_rtt_ms).await,
+            Packet::ReqJoin { room_id, metadata, spectator } =>
+                rh.recv_join_req(from_client_id, client_app_id, room_id, metadata, *spectator).await,
+            Packet::ReqRooms { stream: false, offset, limit, filter } =>
+                rh.send_rooms(from_client_id, client_app_id, *offset, *limit, filter).await,
+            Packet::ReqRooms { stream: true, filter, .. } =>
+                rh.stream_rooms(from_client_id, client_app_id, filter).await,
+            Packet::Reconnect { token } =>
+                rh.reconnect(from_client_id, client_app_id, token).await,
+            Packet::Authenticate { .. } => {
+                self.reject_reauth(from_client_id).await;
+                Ok(())
+            }
+            Packet::Ping { nonce } => {
+                self.send_pong(from_client_id, *nonce).await;
+                Ok(())
+            }
+            Packet::Pong { nonce } => {
+                self.recv_pong(from_client_id, *nonce);
+                Ok(())
+            }
+            Packet::GameData { .. } | Packet::GameDataAuto { .. } => {
+                METRICS.record_game_data_outside_room();
+                if self.not_in_room_limiter.hit(from_client_id) == 1 {
+                    self.udp.send_err(from_client_id, ErrorCode::NotFound, "Not in a room").await;
+                }
+                Ok(())
+            }
             _ => {
                 // TODO: should probably alert the client that they are in an unexpected state?
                 warn!("unexpected packet type from {} in authenticated state: {:?}.", from_client_id, packet);
+                Ok(())
             }
         }
     }
 
     /// Delegates packets to various handlers when the client is in a room.
-    async fn handle_in_room_packet(&mut self, from_client_id: u64, client_app_id: u64, client_room_id: u64, packet: &Packet, channel: &TransferChannel) {
+    async fn handle_in_room_packet(&mut self, from_client_id: u64, client_app_id: u64, client_room_id: u64, packet: &Packet, channel: &TransferChannel) -> HandlerResult {
         match packet {
             Packet::UpdateRoom { metadata, room_id: _room_id } => {
                 RoomHandler::new(
                     &mut self.udp,
                     &mut self.apps,
                     &mut self.clients,
-                ).update_room(from_client_id, client_app_id, client_room_id, metadata).await;
+                    &self.registry,
+                    &self.config,
+                ).update_room(from_client_id, client_app_id, client_room_id, metadata).await
             }
             Packet::JoinRes { target_id, allowed, room_id: _room_id } =>
                 RoomHandler::new(
                     &mut self.udp,
                     &mut self.apps,
                     &mut self.clients,
-                ).recv_join_res(client_app_id, *target_id, client_room_id, allowed).await,
-            Packet::GameData { from_peer, data } => {
-                GameDataHandler::new(
+                    &self.registry,
+                    &self.config,
+                ).recv_join_res(from_client_id, client_app_id, *target_id, client_room_id, allowed).await,
+            Packet::GameDataAuto { from_peer, data } => {
+                let Some(app) = self.apps.get(client_app_id) else {
+                    return Err(HandlerError::MissingApp(client_app_id));
+                };
+
+                let channel = self.config.game_data_channel(&app.token);
+                self.route_game_data(from_client_id, client_app_id, client_room_id, *from_peer, data, &channel).await
+            }
+            Packet::SetRoomAllowlist { ids } => {
+                RoomHandler::new(
+                    &mut self.udp,
+                    &mut self.apps,
+                    &mut self.clients,
+                    &self.registry,
+                    &self.config,
+                ).set_allowlist(from_client_id, client_app_id, client_room_id, ids).await
+            }
+            Packet::SetRoomLocked { locked } => {
+                RoomHandler::new(
+                    &mut self.udp,
+                    &mut self.apps,
+                    &mut self.clients,
+                    &self.registry,
+                    &self.config,
+                ).set_locked(from_client_id, client_app_id, client_room_id, *locked).await
+            }
+            Packet::ReqRoster => {
+                RoomHandler::new(
+                    &mut self.udp,
+                    &mut self.apps,
+                    &mut self.clients,
+                    &self.registry,
+                    &self.config,
+                ).send_roster(from_client_id, client_app_id, client_room_id).await
+            }
+            Packet::TransferHost { peer_id } => {
+                RoomHandler::new(
+                    &mut self.udp,
+                    &mut self.apps,
+                    &mut self.clients,
+                    &self.registry,
+                    &self.config,
+                ).transfer_host(from_client_id, client_app_id, client_room_id, *peer_id).await
+            }
+            Packet::ReqHost => {
+                RoomHandler::new(
                     &mut self.udp,
                     &mut self.apps,
-                ).route_game_data(from_client_id, client_app_id, client_room_id, *from_peer, data, channel).await;
+                    &mut self.clients,
+                    &self.registry,
+                    &self.config,
+                ).send_host_info(from_client_id, client_app_id, client_room_id).await
+            }
+            Packet::GameData { from_peer, data } => {
+                self.route_game_data(from_client_id, client_app_id, client_room_id, *from_peer, data, channel).await
+            }
+            Packet::Authenticate { .. } => {
+                self.reject_reauth(from_client_id).await;
+                Ok(())
+            }
+            Packet::Ping { nonce } => {
+                self.send_pong(from_client_id, *nonce).await;
+                Ok(())
+            }
+            Packet::Pong { nonce } => {
+                self.recv_pong(from_client_id, *nonce);
+                Ok(())
             }
             _ => {
                 // TODO: should probably alert the client that they are in an unexpected state?
                 warn!("unexpected packet type from {} in room state: {:?}.", from_client_id, packet);
+                Ok(())
             }
         }
     }
 
-    /// Forcefully disconnects all clients from the server.
-    /// Should be called when the server shuts down.
-    pub async fn cleanup(&mut self) {
-        let mut disconnects: Vec<u64> = Vec::new();
-        let mut to_remove: Vec<(u64, u64)> = Vec::new();
+    /// Turns away an `Authenticate` from a client that already authenticated.
+    /// The client keeps its current state, so a buggy or replayed packet can't knock it out of its room.
+    async fn reject_reauth(&mut self, client_id: u64) {
+        warn!("{} tried to authenticate again", client_id);
+        self.udp.send_err(client_id, ErrorCode::Conflict, "Already authenticated").await;
+    }
 
-        for app in self.apps.iter() {
-            for room in app.rooms.iter() {
-                disconnects.extend(room.get_clients().iter().copied());
-                to_remove.push((app.id, room.id));
-            }
+    /// Passes game data on to the room once it's been charged against the sender's bandwidth limit.
+    async fn route_game_data(&mut self, from_client_id: u64, app_id: u64, room_id: u64, target_peer: i32, data: &[u8], channel: &TransferChannel) -> HandlerResult {
+        if !self.check_game_data_budget(from_client_id, data.len(), *channel).await {
+            return Ok(());
         }
 
-        info!("disconnecting {} peers", disconnects.len());
-
-        let mut dh = DisconnectHandler::new(
+        GameDataHandler::new(
             &mut self.udp,
-            &mut self.clients,
-            &mut self.apps
-        );
+            &mut self.apps,
+        ).route_game_data(from_client_id, app_id, room_id, target_peer, data, channel).await
+    }
+
+    /// Charges game data against the sender's bandwidth limit.
+    /// Returns false if it shouldn't be passed on.
+    ///
+    /// A packet bigger than the whole burst can never fit, so it's refused with an error.
+    /// Unreliable game data over the limit is dropped. The sender is told the first time it's throttled,
+    /// and disconnected if it stays over the limit for `game_data_throttle_disconnect_ms`.
+    /// Reliable game data can't be dropped without breaking the sender's ordering, so going over
+    /// the limit with it disconnects the sender straight away.
+    async fn check_game_data_budget(&mut self, client_id: u64, bytes: usize, channel: TransferChannel) -> bool {
+        let per_sec = self.config.game_data_bytes_per_sec;
+        if per_sec == 0 {
+            return true;
+        }
+
+        let burst = self.config.game_data_burst_bytes;
+        if bytes as u64 > burst {
+            debug!("refusing {} bytes of game data from {}, it's over the {} byte burst limit", bytes, client_id, burst);
+            self.udp.send_err(client_id, ErrorCode::TooLarge, "Game data packet too large").await;
+            return false;
+        }
+
+        let Some(client) = self.clients.get_mut(client_id) else {
+            return false;
+        };
+
+        let was_throttled = client.game_data_budget.throttled_for().is_some();
+        if client.game_data_budget.take(bytes, per_sec, burst) {
+            return true;
+        }
+
+        METRICS.record_game_data_throttled();
+        let throttled_for = client.game_data_budget.throttled_for().unwrap_or_default();
+
+        if channel == TransferChannel::Reliable {
+            warn!("disconnecting {}, it went over {} bytes/s with reliable game data", client_id, per_sec);
+            self.udp.send_err(client_id, ErrorCode::RateLimited, "Sending reliable game data too fast").await;
+            self.kick_client(client_id).await;
+            return false;
+        }
+
+        if !was_throttled {
+            warn!("throttling game data from {}, it went over {} bytes/s", client_id, per_sec);
+            self.udp.send_err(client_id, ErrorCode::RateLimited, "Sending game data too fast").await;
+        }
+
+        let disconnect_after = self.config.game_data_throttle_disconnect_ms;
+        if disconnect_after > 0 && throttled_for >= Duration::from_millis(disconnect_after) {
+            warn!("disconnecting {}, it stayed over its game data limit for {:?}", client_id, throttled_for);
+            self.kick_client(client_id).await;
+        }
+
+        false
+    }
+
+    /// Counts a join request against the client and the join code it asked for.
+    /// Returns false if the request should be rejected.
+    async fn check_join_rate(&mut self, client_id: u64, join_code: &str) -> bool {
+        let attempts = self.join_limiter.hit((client_id, join_code.to_string()));
+        if attempts <= self.join_limiter.limit() {
+            return true;
+        }
+
+        warn!("rate limited join request from {} for {} ({} attempts)", client_id, join_code, attempts);
+        self.udp.send_err(client_id, ErrorCode::RateLimited, "Too many join requests for this room, try again later").await;
+        false
+    }
+
+    /// Counts an authentication attempt against the client's address.
+    /// Returns false if the attempt should be rejected. Clients that keep going
+    /// well past the limit are disconnected.
+    async fn check_auth_rate(&mut self, client_id: u64) -> bool {
+        let Some(addr) = self.udp.connection_manager.get_addr(client_id) else {
+            return false;
+        };
+
+        let attempts = self.auth_limiter.hit(addr.ip());
+        let limit = self.auth_limiter.limit();
+
+        if attempts <= limit {
+            return true;
+        }
+
+        warn!("rate limited authentication attempt from {} ({} attempts)", client_id, attempts);
+        self.udp.send_err(client_id, ErrorCode::RateLimited, "Too many authentication attempts").await;
+
+        if attempts > limit.saturating_mul(2) {
+            self.kick_client(client_id).await;
+        }
+
+        false
+    }
+
+    /// Answers the handshake a client opened its session with.
+    /// Compatibility is decided when it authenticates, so the wire version is only logged here.
+    async fn accept_connect(&mut self, client_id: u64, protocol_version: u16) {
+        debug!("client {} connected with wire version {}", client_id, protocol_version);
+
+        let packet = Packet::ConnectAccepted { client_id, cookie: Vec::new() };
+        self.udp.send_packet(client_id, &packet, TransferChannel::Reliable).await;
+    }
+
+    /// Tells a client which versions this relay accepts, so it can prompt for an update before authenticating.
+    /// Requests are rate limited per client since they're answered before authentication.
+    async fn send_version_info(&mut self, target: u64) {
+        let Some(client) = self.clients.get_mut(target) else {
+            return;
+        };
+
+        let now = Instant::now();
+        if client.last_version_info.is_some_and(|last| now.duration_since(last) < VERSION_INFO_COOLDOWN) {
+            debug!("rate limited version info request from {}", target);
+            return;
+        }
+        client.last_version_info = Some(now);
+
+        let packet = Packet::VersionInfo {
+            allowed_versions: self.config.allowed_versions.clone(),
+            protocol_version: WIRE_VERSION,
+        };
+
+        self.udp.send_packet(target, &packet, TransferChannel::Reliable).await;
+    }
 
-        for id in disconnects {
-            dh.force_disconnect(id).await;
+    /// Pings every client in the lobby to measure its round-trip time to the relay.
+    /// Rooms can use this to turn away clients that are too far away.
+    async fn probe_rtt(&mut self) {
+        let probes: Vec<(u64, u64)> = self.clients.iter_mut()
+            .filter(|(_, client)| matches!(client.state, ClientState::Authenticated { .. }))
+            .map(|(id, client)| (id, client.start_rtt_probe()))
+            .collect();
+
+        for (target, nonce) in probes {
+            let (ping, channel) = PacketBuilder::ping(nonce).unreliable().build();
+            self.udp.send_packet(target, &ping, channel).await;
+        }
+    }
+
+    /// Records the round-trip time of a `Ping` sent by `probe_rtt`.
+    fn recv_pong(&mut self, from_client_id: u64, nonce: u64) {
+        if let Some(client) = self.clients.get_mut(from_client_id) {
+            client.finish_rtt_probe(nonce);
+        }
+    }
+
+    /// Echoes a `Pong` back to the client so it can measure its round-trip time.
+    /// This is sent unreliably, as a resent pong would skew the measurement.
+    async fn send_pong(&mut self, target: u64, nonce: u64) {
+        let (pong, channel) = PacketBuilder::pong(nonce).unreliable().build();
+        self.udp.send_packet(target, &pong, channel).await;
+    }
+
+    /// Forcefully disconnects all clients from the server.
+    /// Should be called when the server shuts down.
+    pub async fn cleanup(&mut self) {
+        // Save before the rooms are torn down, so they can be restored on the next start.
+        self.save_rooms();
+
+        let client_ids = self.clients.ids();
+        info!("disconnecting {} clients", client_ids.len());
+
+        // Every session is closed before anyone is cleaned up, so hosts leaving don't
+        // migrate their rooms to peers that are about to go too.
+        for &client_id in &client_ids {
+            DisconnectHandler::new(
+                &mut self.udp,
+                &mut self.clients,
+                &mut self.apps,
+                &self.registry,
+                &self.config,
+            ).force_disconnect(client_id).await;
+        }
+
+        for client_id in client_ids {
+            // Peers are dropped along with their host's room, so they may already be gone
+            if self.clients.get(client_id).is_none() {
+                continue;
+            }
+
+            self.handle_disconnect(client_id, DisconnectReason::ServerShutdown).await;
         }
 
+        // Only rooms nobody is in are left, like restored rooms still waiting on their host
+        let to_remove: Vec<(u64, u64)> = self.apps.iter()
+            .flat_map(|app| app.rooms.iter().map(|room| (app.id, room.id)))
+            .collect();
+
         let mut rh = RoomHandler::new(
             &mut self.udp,
             &mut self.apps,
             &mut self.clients,
+            &self.registry,
+            &self.config,
         );
 
         for (app_id, room_id) in to_remove {
             rh.remove_room(app_id, room_id);
         }
+
+        // Boxed since reading from the socket needs a large buffer.
+        Box::pin(self.drain()).await;
+    }
+
+    /// Keeps resending and reading acks for `shutdown_drain_ms`,
+    /// so reliable packets sent during shutdown have a chance to arrive.
+    /// Anything else clients send in the meantime is dropped.
+    async fn drain(&mut self) {
+        let window = Duration::from_millis(self.config.timing.shutdown_drain_ms);
+        if window.is_zero() {
+            return;
+        }
+
+        let resend_window = Duration::from_millis(self.config.timing.resend_window_ms);
+        let mut resend = tokio::time::interval(Duration::from_millis(self.config.timing.resend_interval_ms));
+        let deadline = tokio::time::sleep(window);
+        tokio::pin!(deadline);
+
+        loop {
+            tokio::select! {
+                () = &mut deadline => break,
+                _ = resend.tick() => self.udp.do_resends(resend_window).await,
+                result = self.udp.recv_events() => {
+                    if let Err(e) = result {
+                        warn!("stopped draining early: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Warns if a timer tick fired well after it was scheduled,
+/// which means the server loop is falling behind.
+fn warn_if_late(timer: &str, scheduled: tokio::time::Instant) {
+    let delay = scheduled.elapsed();
+    if delay > TICK_DELAY_WARNING {
+        warn!("{} tick ran {:?} late, the relay loop may be overloaded", timer, delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use paperudp::channel::{Channel, DecodeResult};
+    use paperudp::packet::PacketType;
+    use crate::relay::testing::{self, TestClient, TestRelay};
+    use crate::protocol::packet::RoomMetadata;
+    use crate::protocol::version::PROTOCOL_VERSION;
+    use crate::udp::socket::memory::MemorySocket;
+    use super::*;
+
+    /// Opens a room hosted by a new client of `app_id`. Returns the host and the join code.
+    async fn create_room(relay: &mut TestRelay, app_id: &str) -> (TestClient, String) {
+        let (mut host, _) = relay.authenticate(app_id).await;
+        host.send(&Packet::CreateRoom {
+            is_public: true,
+            metadata: HashMap::new(),
+            max_players: 4,
+            max_join_rtt_ms: 0,
+        }).await;
+
+        let Packet::RoomCreated { room_id, join_code, peer_id: 1 } = host.recv().await else {
+            panic!("expected RoomCreated for peer 1");
+        };
+        assert_eq!(room_id, format!("LOCAL{join_code}"));
+
+        (host, join_code)
+    }
+
+    /// Has a new client of `app_id` join the room behind `join_code`, with the host letting it in.
+    /// Returns the joiner and its peer ID.
+    async fn join_room(relay: &mut TestRelay, host: &mut TestClient, app_id: &str, join_code: &str) -> (TestClient, i32) {
+        let (mut joiner, joiner_id) = relay.authenticate(app_id).await;
+        joiner.send(&Packet::ReqJoin {
+            room_id: join_code.to_string(),
+            metadata: String::new(),
+            spectator: false,
+        }).await;
+
+        let Packet::PeerJoinAttempt { target_id, .. } = host.recv().await else {
+            panic!("expected PeerJoinAttempt");
+        };
+        assert_eq!(target_id, joiner_id);
+
+        host.send(&Packet::JoinRes { target_id, room_id: join_code.to_string(), allowed: true }).await;
+
+        let Packet::ConnectedToRoom { room_id, peer_id, existing_peers, .. } = joiner.recv().await else {
+            panic!("expected ConnectedToRoom");
+        };
+        // The same ID the host was given in `RoomCreated`.
+        assert_eq!(room_id, format!("LOCAL{join_code}"));
+        assert!(existing_peers.contains(&1));
+        assert_eq!(host.recv().await, Packet::PeerJoinedRoom { peer_id });
+
+        (joiner, peer_id)
+    }
+
+    /// Reads an unlabelled metric from what `/metrics` would serve.
+    fn metric(name: &str) -> u64 {
+        METRICS.render().lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .and_then(|value| value.parse().ok())
+            .unwrap_or_else(|| panic!("metric {name} wasn't rendered"))
+    }
+
+    #[tokio::test]
+    async fn clients_can_meet_in_a_room_and_exchange_game_data() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, joiner_peer) = join_room(&mut relay, &mut host, "app", &join_code).await;
+
+        joiner.send(&Packet::GameData { from_peer: 1, data: b"to host".to_vec() }).await;
+        assert_eq!(host.recv().await, Packet::GameData { from_peer: joiner_peer, data: b"to host".to_vec() });
+
+        host.send_unreliable(&Packet::GameData { from_peer: joiner_peer, data: b"to joiner".to_vec() }).await;
+        assert_eq!(joiner.recv().await, Packet::GameData { from_peer: 1, data: b"to joiner".to_vec() });
+
+        joiner.send(&Packet::Disconnect).await;
+        assert_eq!(host.recv().await, Packet::PeerLeftRoom { peer_id: joiner_peer });
+        joiner.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn joins_for_rooms_on_other_relays_are_redirected() {
+        use axum::extract::Path;
+        use axum::http::{HeaderMap, StatusCode};
+        use axum::routing::get;
+        use axum::{Json, Router};
+        use crate::registry::client::RegistryRoom;
+
+        async fn lookup(Path(join_code): Path<String>, headers: HeaderMap) -> Result<Json<RegistryRoom>, StatusCode> {
+            if join_code != "REMOTE" || headers.get("X-App-Token").is_none_or(|app| app != "app") {
+                return Err(StatusCode::NOT_FOUND);
+            }
+
+            Ok(Json(RegistryRoom {
+                app_id: "app".to_string(),
+                join_code,
+                relay_id: "other".to_string(),
+                relay_address: "203.0.113.5:7000".to_string(),
+                region: String::new(),
+            }))
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let registry_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, Router::new().route("/rooms/{join_code}", get(lookup))).await.unwrap();
+        });
+
+        let mut config = testing::config();
+        config.registry_endpoint = format!("http://{registry_addr}");
+        config.registry_token = "secret".to_string();
+        config.relay_id = "here".to_string();
+        let mut relay = TestRelay::start(config);
+        let (mut client, _) = relay.authenticate("app").await;
+
+        client.send(&Packet::ReqJoin { room_id: "REMOTE".to_string(), metadata: String::new(), spectator: false }).await;
+        assert_eq!(client.recv().await, Packet::Redirect { address: "203.0.113.5:7000".to_string() });
+
+        client.send(&Packet::ReqJoin { room_id: "NOWHERE".to_string(), metadata: String::new(), spectator: false }).await;
+        let Packet::Error { error_code, .. } = client.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::NotFound as i32);
+    }
+
+    #[tokio::test]
+    async fn sessions_only_resume_with_their_token_once_quiet() {
+        let mut config = testing::config();
+        config.resume_after_silence_ms = 300;
+        let mut relay = TestRelay::start(config);
+        let (mut host, _, token) = relay.authenticate_as("app", "player", "").await;
+        assert_eq!(token.len(), 32);
+
+        host.send(&Packet::CreateRoom { is_public: true, metadata: HashMap::new(), max_players: 4, max_join_rtt_ms: 0 }).await;
+        assert!(matches!(host.recv().await, Packet::RoomCreated { .. }));
+
+        // The host is still talking to the relay, so even the right token can't take its place.
+        let (mut early, _, early_token) = relay.authenticate_as("app", "player", &token).await;
+        assert_ne!(early_token, token);
+        early.expect_nothing().await;
+        early.send(&Packet::Disconnect).await;
+
+        tokio::time::sleep(Duration::from_millis(350)).await;
+
+        let (mut guesser, _, _) = relay.authenticate_as("app", "player", "0123456789abcdef0123456789abcdef").await;
+        guesser.expect_nothing().await;
+        guesser.send(&Packet::Disconnect).await;
+
+        let (mut resumed, _, resumed_token) = relay.authenticate_as("app", "player", &token).await;
+        assert_eq!(resumed_token, token);
+        let Packet::ConnectedToRoom { peer_id: 1, .. } = resumed.recv().await else {
+            panic!("expected to resume as peer 1");
+        };
+    }
+
+    #[tokio::test]
+    async fn stats_leave_out_app_tokens() {
+        let mut config = testing::config();
+        config.timing.cleanup_interval_ms = 10;
+        let mut relay = TestRelay::start(config);
+        let (_host, _) = create_room(&mut relay, "secret-token").await;
+
+        let stats = relay.stats.wait_for(|stats| stats.rooms == 1).await.unwrap().clone();
+        assert_eq!(stats.rooms_per_app.len(), 1);
+        assert!(!stats.rooms_per_app.contains_key("secret-token"));
+    }
+
+    #[tokio::test]
+    async fn idle_rooms_are_closed_while_active_ones_stay() {
+        let mut config = testing::config();
+        config.timing.room_idle_timeout_ms = 150;
+        config.timing.cleanup_interval_ms = 10;
+        let mut relay = TestRelay::start(config);
+        let (mut idle_host, _) = create_room(&mut relay, "app").await;
+        let (mut active_host, _) = create_room(&mut relay, "app").await;
+
+        for _ in 0..6 {
+            active_host.send(&Packet::UpdateRoom { room_id: String::new(), metadata: HashMap::new() }).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(idle_host.recv().await, Packet::ForceDisconnect);
+
+        active_host.send(&Packet::ReqHost).await;
+        assert_eq!(active_host.recv().await, Packet::HostInfo { peer_id: 1 });
+    }
+
+    #[tokio::test]
+    async fn unanswered_join_requests_time_out() {
+        let mut config = testing::config();
+        config.join_request_timeout_ms = 50;
+        config.timing.cleanup_interval_ms = 10;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, joiner_id) = relay.authenticate("app").await;
+
+        joiner.send(&Packet::ReqJoin { room_id: join_code.clone(), metadata: String::new(), spectator: false }).await;
+        assert!(matches!(host.recv().await, Packet::PeerJoinAttempt { target_id, .. } if target_id == joiner_id));
+
+        let Packet::Error { error_code, .. } = joiner.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Timeout as i32);
+
+        // The request is gone, so answering it late doesn't let the joiner in.
+        host.send(&Packet::JoinRes { target_id: joiner_id, room_id: join_code, allowed: true }).await;
+        let Packet::Error { error_code, .. } = host.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Conflict as i32);
+        joiner.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn unreliable_control_packets_are_only_refused_when_configured() {
+        let create = Packet::CreateRoom { is_public: true, metadata: HashMap::new(), max_players: 4, max_join_rtt_ms: 0 };
+
+        let mut relay = TestRelay::start(testing::config());
+        let (mut client, _) = relay.authenticate("app").await;
+        client.send_unreliable(&create).await;
+        assert!(matches!(client.recv().await, Packet::RoomCreated { .. }));
+
+        let mut config = testing::config();
+        config.require_reliable_control = true;
+        let mut relay = TestRelay::start(config);
+        let (mut client, _) = relay.authenticate("app").await;
+        client.send_unreliable(&create).await;
+        let Packet::Error { error_code, .. } = client.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::BadRequest as i32);
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_are_turned_away() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut client, _) = relay.connect().await;
+
+        client.send(&Packet::Authenticate {
+            app_id: "app".to_string(),
+            version: "0.0.1".to_string(),
+            stable_id: String::new(),
+            resume_token: String::new(),
+        }).await;
+
+        let Packet::Error { error_code, .. } = client.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Unauthorized as i32);
+        assert_eq!(client.recv().await, Packet::ForceDisconnect);
+    }
+
+    #[tokio::test]
+    async fn a_second_authenticate_is_refused_while_the_first_is_checked() {
+        use std::sync::Arc;
+        use axum::extract::State;
+        use axum::http::StatusCode;
+        use axum::routing::get;
+        use axum::Router;
+        use tokio::sync::Notify;
+
+        async fn check(State(release): State<Arc<Notify>>) -> StatusCode {
+            release.notified().await;
+            StatusCode::OK
+        }
+
+        let release = Arc::new(Notify::new());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let whitelist_addr = listener.local_addr().unwrap();
+        let router = Router::new().route("/{app}", get(check)).with_state(release.clone());
+        tokio::spawn(async move {
+            axum::serve(listener, router).await.unwrap();
+        });
+
+        let mut config = testing::config();
+        config.remote_whitelist_endpoint = format!("http://{whitelist_addr}");
+        config.remote_whitelist_token = "secret".to_string();
+        let mut relay = TestRelay::start(config);
+        let (mut client, _) = relay.connect().await;
+
+        let authenticate = Packet::Authenticate {
+            app_id: "app".to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            stable_id: String::new(),
+            resume_token: String::new(),
+        };
+        client.send(&authenticate).await;
+        client.send(&authenticate).await;
+
+        // The first is still waiting on the whitelist, which doesn't hold up the relay answering the second.
+        let Packet::Error { error_code, .. } = client.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Conflict as i32);
+
+        release.notify_one();
+        assert!(matches!(client.recv().await, Packet::ClientAuthenticated { .. }));
+        client.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn authenticated_clients_that_authenticate_again_keep_their_state() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, joiner_peer) = join_room(&mut relay, &mut host, "app", &join_code).await;
+        let (mut lobby, _) = relay.authenticate("app").await;
+
+        let authenticate = Packet::Authenticate {
+            app_id: "other".to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            stable_id: String::new(),
+            resume_token: String::new(),
+        };
+        for client in [&mut host, &mut joiner, &mut lobby] {
+            client.send(&authenticate).await;
+            assert_eq!(client.recv().await, Packet::Error {
+                error_code: ErrorCode::Conflict as i32,
+                error_message: "Already authenticated".to_string(),
+            });
+        }
+        host.expect_nothing().await;
+
+        // Everyone is still where they were, and the room still counts both players.
+        joiner.send(&Packet::GameData { from_peer: 1, data: vec![1] }).await;
+        assert_eq!(host.recv().await, Packet::GameData { from_peer: joiner_peer, data: vec![1] });
+
+        lobby.send(&Packet::ReqRooms { stream: false, offset: 0, limit: 10, filter: String::new() }).await;
+        let Packet::GetRooms { rooms, .. } = lobby.recv().await else {
+            panic!("expected GetRooms");
+        };
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[0].player_count, 2);
+    }
+
+    #[tokio::test]
+    async fn authentication_attempts_past_the_limit_are_refused_then_kicked() {
+        let mut relay = TestRelay::start(testing::config());
+        // Every test client shares an address, so these use up the budget.
+        for _ in 0..5 {
+            relay.authenticate("app").await;
+        }
+
+        let (mut client, _) = relay.connect().await;
+        let authenticate = Packet::Authenticate {
+            app_id: "app".to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            stable_id: String::new(),
+            resume_token: String::new(),
+        };
+
+        // Attempts 6 to 10 are refused, but the client can keep trying.
+        for _ in 6..=10 {
+            client.send(&authenticate).await;
+            let Packet::Error { error_code, .. } = client.recv().await else {
+                panic!("expected an error");
+            };
+            assert_eq!(error_code, ErrorCode::RateLimited as i32);
+        }
+
+        // Past twice the limit, it's disconnected.
+        client.send(&authenticate).await;
+        let Packet::Error { error_code, .. } = client.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::RateLimited as i32);
+        assert_eq!(client.recv().await, Packet::ForceDisconnect);
+    }
+
+    #[tokio::test]
+    async fn game_data_before_joining_a_room_is_refused() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut client, _) = relay.authenticate("app").await;
+        let counted_before = metric("relay_game_data_outside_room_total");
+
+        client.send(&Packet::GameData { from_peer: 1, data: vec![1] }).await;
+        let Packet::Error { error_code, .. } = client.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::NotFound as i32);
+
+        // The reminder is rate limited, so a burst only gets one.
+        client.send(&Packet::GameData { from_peer: 1, data: vec![2] }).await;
+        client.expect_nothing().await;
+
+        // Both are counted. Other tests share the metrics, so they may have added to it too.
+        assert!(metric("relay_game_data_outside_room_total") >= counted_before + 2);
+    }
+
+    #[tokio::test]
+    async fn connections_past_max_clients_are_turned_away() {
+        let mut config = testing::config();
+        config.max_clients = 1;
+        let mut relay = TestRelay::start(config);
+        let _first = relay.connect().await;
+
+        let mut second = relay.client();
+        let cookie = second.request_cookie().await;
+        second.send(&Packet::Connect { protocol_version: WIRE_VERSION, cookie }).await;
+        let Packet::Error { error_code, .. } = second.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::ServerFull as i32);
+
+        // Whatever it sends before its session closes is ignored.
+        second.send(&Packet::Authenticate {
+            app_id: "app".to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            stable_id: String::new(),
+            resume_token: String::new(),
+        }).await;
+        second.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn restored_rooms_are_only_reclaimed_with_the_host_secret() {
+        let path = std::env::temp_dir().join(format!("relay-reclaim-{}.toml", std::process::id()));
+        FileRoomStore::new(&path).save(&[StoredRoom {
+            app_token: "app".to_string(),
+            join_code: "ABCDE".to_string(),
+            host_stable_id: "host".to_string(),
+            host_secret: "secret".to_string(),
+            is_public: false,
+            metadata: RoomMetadata::default(),
+            max_players: 0,
+            max_join_rtt_ms: 0,
+        }]).unwrap();
+
+        let mut config = testing::config();
+        config.room_store_path = path.to_string_lossy().into_owned();
+        let mut relay = TestRelay::start(config);
+
+        let (mut impostor, _, _) = relay.authenticate_as("app", "host", "guess").await;
+        impostor.expect_nothing().await;
+        impostor.send(&Packet::Disconnect).await;
+
+        let (mut host, _, _) = relay.authenticate_as("app", "host", "secret").await;
+        let Packet::RoomCreated { join_code, .. } = host.recv().await else {
+            panic!("expected RoomCreated");
+        };
+        assert_eq!(join_code, "ABCDE");
+
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn heartbeats_are_consumed_and_game_data_that_looks_like_one_is_relayed() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, joiner_peer) = join_room(&mut relay, &mut host, "app", &join_code).await;
+
+        // What clients from before wire version 7 send as a heartbeat, and what they send now.
+        joiner.send_bytes(&[3], PacketType::Unreliable).await;
+        joiner.send(&Packet::Heartbeat).await;
+        joiner.expect_nothing().await;
+        host.expect_nothing().await;
+
+        joiner.send(&Packet::GameData { from_peer: 1, data: vec![3] }).await;
+        assert_eq!(host.recv().await, Packet::GameData { from_peer: joiner_peer, data: vec![3] });
+    }
+
+    #[tokio::test]
+    async fn spectators_past_max_spectators_are_turned_away() {
+        let mut config = testing::config();
+        config.max_spectators = 0;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut spectator, _) = relay.authenticate("app").await;
+
+        spectator.send(&Packet::ReqJoin { room_id: join_code, metadata: String::new(), spectator: true }).await;
+        let Packet::Error { error_code, .. } = spectator.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Conflict as i32);
+        host.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn game_data_over_the_bandwidth_limit_is_dropped_or_disconnects() {
+        let mut config = testing::config();
+        config.game_data_bytes_per_sec = 10;
+        config.game_data_burst_bytes = 100;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, joiner_peer) = join_room(&mut relay, &mut host, "app", &join_code).await;
+
+        // Too big to ever fit, so it's refused without using up the budget.
+        joiner.send_unreliable(&Packet::GameData { from_peer: 1, data: vec![0; 101] }).await;
+        let Packet::Error { error_code, .. } = joiner.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::TooLarge as i32);
+
+        joiner.send_unreliable(&Packet::GameData { from_peer: 1, data: vec![1; 80] }).await;
+        assert_eq!(host.recv().await, Packet::GameData { from_peer: joiner_peer, data: vec![1; 80] });
+
+        // Unreliable game data over the limit is dropped.
+        joiner.send_unreliable(&Packet::GameData { from_peer: 1, data: vec![2; 80] }).await;
+        let Packet::Error { error_code, .. } = joiner.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::RateLimited as i32);
+        host.expect_nothing().await;
+
+        // Reliable game data can't be, so the sender is disconnected.
+        joiner.send(&Packet::GameData { from_peer: 1, data: vec![3; 80] }).await;
+        let Packet::Error { error_code, .. } = joiner.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::RateLimited as i32);
+        assert_eq!(joiner.recv().await, Packet::ForceDisconnect);
+    }
+
+    #[tokio::test]
+    async fn opaque_game_data_reaches_the_peer_byte_for_byte() {
+        let mut config = testing::config();
+        config.opaque_forwarding = true;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, joiner_peer) = join_room(&mut relay, &mut host, "app", &join_code).await;
+
+        // Stands in for ciphertext: every byte value, which isn't valid UTF-8 or any packet the relay knows.
+        let payload: Vec<u8> = (0..=u8::MAX).rev().collect();
+
+        joiner.send(&Packet::GameData { from_peer: 1, data: payload.clone() }).await;
+        assert_eq!(host.recv().await, Packet::GameData { from_peer: joiner_peer, data: payload.clone() });
+
+        host.send_unreliable(&Packet::GameData { from_peer: joiner_peer, data: payload.clone() }).await;
+        assert_eq!(joiner.recv().await, Packet::GameData { from_peer: 1, data: payload });
+    }
+
+    /// How many clients have disconnected for `reason`, as served on `/metrics`.
+    fn disconnects(reason: &str) -> u64 {
+        metric(&format!("relay_disconnects_total{{reason=\"{reason}\"}}"))
+    }
+
+    /// Waits for a disconnect for `reason` to be counted past `before`.
+    /// The metrics are shared with every other test, so this only checks the count went up.
+    async fn expect_disconnect_counted(reason: &str, before: u64) {
+        let counted = async {
+            while disconnects(reason) <= before {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        tokio::time::timeout(Duration::from_secs(1), counted).await
+            .unwrap_or_else(|_| panic!("no {reason} disconnect was counted"));
+    }
+
+    #[tokio::test]
+    async fn clients_that_leave_are_counted_as_graceful() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut client, _) = relay.authenticate("app").await;
+        let before = disconnects("graceful");
+
+        client.send(&Packet::Disconnect).await;
+        expect_disconnect_counted("graceful", before).await;
+    }
+
+    #[tokio::test]
+    async fn silent_clients_are_counted_as_timed_out() {
+        let mut config = testing::config();
+        config.timing.session_timeout_ms = 100;
+        config.timing.cleanup_interval_ms = 10;
+        let mut relay = TestRelay::start(config);
+        let before = disconnects("timeout");
+
+        let _client = relay.authenticate("app").await;
+        expect_disconnect_counted("timeout", before).await;
+    }
+
+    #[tokio::test]
+    async fn clients_sending_garbage_are_counted_as_protocol_errors() {
+        let mut relay = TestRelay::start(testing::config());
+        let (client, _) = relay.authenticate("app").await;
+        let before = disconnects("protocol_error");
+
+        for _ in 0..=8 {
+            client.send_datagram(b"garbage").await;
+        }
+        expect_disconnect_counted("protocol_error", before).await;
+    }
+
+    #[tokio::test]
+    async fn kicked_clients_are_counted_as_kicked() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut client, client_id) = relay.authenticate("app").await;
+        let before = disconnects("kicked");
+
+        relay.admin(&format!("kick {client_id}")).await;
+        assert_eq!(client.recv().await, Packet::ForceDisconnect);
+        expect_disconnect_counted("kicked", before).await;
+    }
+
+    #[tokio::test]
+    async fn clients_that_stop_acking_are_counted_as_saturated() {
+        let mut config = testing::config();
+        config.max_unacked_reliable_bytes = 1;
+        let mut relay = TestRelay::start(config);
+        let (mut client, _) = relay.authenticate("app").await;
+        let before = disconnects("saturated");
+
+        // The reply is never read, so it's never acked.
+        client.send(&Packet::ReqRooms { stream: false, offset: 0, limit: 10, filter: String::new() }).await;
+        expect_disconnect_counted("saturated", before).await;
+    }
+
+    #[tokio::test]
+    async fn clients_left_at_shutdown_are_counted_as_server_shutdown() {
+        let (socket, client) = MemorySocket::pair();
+        let mut config = testing::config();
+        config.timing.shutdown_drain_ms = 0;
+        let mut server = RelayServer::new(PaperInterface::new(vec![socket], 64), config).unwrap();
+        let client_id = server.udp.connection_manager.create_session(client.local_addr(), Channel::new()).unwrap().id;
+        server.clients.create(client_id);
+        let before = disconnects("server_shutdown");
+
+        server.cleanup().await;
+        assert!(disconnects("server_shutdown") > before);
+    }
+
+    #[tokio::test]
+    async fn shutdown_keeps_resending_the_final_disconnects_until_the_drain_window_ends() {
+        let (socket, client) = MemorySocket::pair();
+        let mut config = testing::config();
+        config.timing.shutdown_drain_ms = 300;
+        let mut server = RelayServer::new(PaperInterface::new(vec![socket], 64), config).unwrap();
+        let client_id = server.udp.connection_manager.create_session(client.local_addr(), Channel::new()).unwrap().id;
+        server.clients.create(client_id);
+
+        let started = Instant::now();
+        server.cleanup().await;
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // The client never acks, so the `ForceDisconnect` should have gone out again after the resend window.
+        let mut buf = [0u8; 2048];
+        let mut sent = Vec::new();
+        while let Ok((len, _)) = client.try_recv_from(&mut buf) {
+            sent.push(buf[..len].to_vec());
+        }
+        assert!(sent.len() >= 2, "only {} datagrams were sent", sent.len());
+        assert!(sent.windows(2).all(|pair| pair[0] == pair[1]));
+    }
+
+    #[tokio::test]
+    async fn disconnecting_clears_everything_kept_per_client() {
+        let (socket, _) = MemorySocket::pair();
+        let mut server = RelayServer::new(PaperInterface::new(vec![socket], 64), testing::config()).unwrap();
+        let [host, joiner, browser]: [u64; 3] = std::array::from_fn(|port| {
+            let addr = SocketAddr::from(([127, 0, 0, 1], 4000 + u16::try_from(port).unwrap()));
+            server.udp.connection_manager.create_session(addr, Channel::new()).unwrap().id
+        });
+
+        let authenticate = Packet::Authenticate {
+            app_id: "app".to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            stable_id: String::new(),
+            resume_token: String::new(),
+        };
+        for client_id in [host, joiner, browser] {
+            server.handle_event(ServerEvent::ClientConnected { client_id }).await;
+            server.handle_packet(client_id, authenticate.to_bytes(), TransferChannel::Reliable).await;
+        }
+
+        let create = Packet::CreateRoom { is_public: true, metadata: RoomMetadata::new(), max_players: 4, max_join_rtt_ms: 0 };
+        server.handle_packet(host, create.to_bytes(), TransferChannel::Reliable).await;
+        let app_id = server.apps.get_by_token("app").unwrap().id;
+        let join_code = server.apps.get(app_id).unwrap().rooms.iter().next().unwrap().join_code.clone();
+
+        // A join, a pending join request and game data sent from outside a room each leave something behind.
+        let join = Packet::ReqJoin { room_id: join_code.clone(), metadata: String::new(), spectator: false };
+        server.handle_packet(joiner, join.to_bytes(), TransferChannel::Reliable).await;
+        let allow = Packet::JoinRes { target_id: joiner, room_id: join_code.clone(), allowed: true };
+        server.handle_packet(host, allow.to_bytes(), TransferChannel::Reliable).await;
+        server.handle_packet(browser, join.to_bytes(), TransferChannel::Reliable).await;
+        let game_data = Packet::GameData { from_peer: 1, data: vec![1] };
+        server.handle_packet(browser, game_data.to_bytes(), TransferChannel::Reliable).await;
+
+        assert!(matches!(server.clients.get(joiner).unwrap().state, ClientState::InRoom { .. }));
+        assert!(!server.join_limiter.is_empty());
+        assert!(!server.not_in_room_limiter.is_empty());
+
+        for client_id in [browser, joiner, host] {
+            server.udp.remove_client(client_id);
+            server.handle_disconnect(client_id, DisconnectReason::Timeout).await;
+        }
+
+        assert_eq!(server.clients.len(), 0);
+        assert_eq!(server.apps.len(), 0);
+        assert!(server.rejected.is_empty());
+        assert!(server.join_limiter.is_empty());
+        assert!(server.not_in_room_limiter.is_empty());
+        for client_id in [host, joiner, browser] {
+            assert!(server.udp.connection_manager.get_addr(client_id).is_none());
+        }
+    }
+
+    /// A config where every app's first room gets the join code `A`.
+    fn single_join_code_config() -> Config {
+        let mut config = testing::config();
+        config.join_code_length = 1;
+        config.join_code_alphabet = "A".to_string();
+        config
+    }
+
+    #[tokio::test]
+    async fn two_apps_can_use_the_same_join_code_for_different_rooms() {
+        let mut relay = TestRelay::start(single_join_code_config());
+        let (mut first_host, first_code) = create_room(&mut relay, "first").await;
+        let (mut second_host, second_code) = create_room(&mut relay, "second").await;
+        assert_eq!(first_code, second_code);
+
+        let (mut joiner, _) = join_room(&mut relay, &mut second_host, "second", &second_code).await;
+        first_host.expect_nothing().await;
+
+        joiner.send(&Packet::GameData { from_peer: 1, data: vec![1, 2, 3] }).await;
+        assert!(matches!(second_host.recv().await, Packet::GameData { data, .. } if data == vec![1, 2, 3]));
+        first_host.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn pending_join_limits_are_counted_per_app() {
+        let mut config = single_join_code_config();
+        config.max_pending_joins_per_room = 1;
+        let mut relay = TestRelay::start(config);
+        let (mut first_host, join_code) = create_room(&mut relay, "first").await;
+        let (mut second_host, _) = create_room(&mut relay, "second").await;
+
+        let (mut first_joiner, _) = relay.authenticate("first").await;
+        first_joiner.send(&Packet::ReqJoin { room_id: join_code.clone(), metadata: String::new(), spectator: false }).await;
+        assert!(matches!(first_host.recv().await, Packet::PeerJoinAttempt { .. }));
+
+        // Both rooms have room ID 0, but the first app's request doesn't count against the second app's room.
+        let (mut second_joiner, _) = relay.authenticate("second").await;
+        second_joiner.send(&Packet::ReqJoin { room_id: join_code.clone(), metadata: String::new(), spectator: false }).await;
+        assert!(matches!(second_host.recv().await, Packet::PeerJoinAttempt { .. }));
+
+        // But a second request in the first app is over its limit.
+        let (mut late_joiner, _) = relay.authenticate("first").await;
+        late_joiner.send(&Packet::ReqJoin { room_id: join_code, metadata: String::new(), spectator: false }).await;
+        let Packet::Error { error_code, .. } = late_joiner.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::RateLimited as i32);
+        first_host.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn join_metadata_over_the_limit_never_reaches_the_host() {
+        let mut config = testing::config();
+        config.max_metadata_bytes = 16;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, _) = relay.authenticate("app").await;
+
+        joiner.send(&Packet::ReqJoin { room_id: join_code.clone(), metadata: "x".repeat(17), spectator: false }).await;
+        let Packet::Error { error_code, .. } = joiner.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::TooLarge as i32);
+        host.expect_nothing().await;
+
+        joiner.send(&Packet::ReqJoin { room_id: join_code, metadata: "x".repeat(16), spectator: false }).await;
+        assert!(matches!(host.recv().await, Packet::PeerJoinAttempt { metadata, .. } if metadata == "x".repeat(16)));
+    }
+
+    #[tokio::test]
+    async fn repeated_join_requests_for_one_room_are_throttled() {
+        let mut config = testing::config();
+        config.join_attempt_limit = 2;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, joiner_id) = relay.authenticate("app").await;
+        let request = Packet::ReqJoin { room_id: join_code.clone(), metadata: String::new(), spectator: false };
+
+        for _ in 0..2 {
+            joiner.send(&request).await;
+            assert!(matches!(host.recv().await, Packet::PeerJoinAttempt { .. }));
+            host.send(&Packet::JoinRes { target_id: joiner_id, room_id: join_code.clone(), allowed: false }).await;
+            let Packet::Error { error_code, .. } = joiner.recv().await else {
+                panic!("expected an error");
+            };
+            assert_eq!(error_code, ErrorCode::Forbidden as i32);
+        }
+
+        joiner.send(&request).await;
+        let Packet::Error { error_code, .. } = joiner.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::RateLimited as i32);
+        host.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn hosts_cannot_answer_join_requests_made_in_another_app() {
+        let mut relay = TestRelay::start(single_join_code_config());
+        let (mut first_host, join_code) = create_room(&mut relay, "first").await;
+        let (mut second_host, _) = create_room(&mut relay, "second").await;
+
+        let (mut joiner, joiner_id) = relay.authenticate("first").await;
+        joiner.send(&Packet::ReqJoin { room_id: join_code.clone(), metadata: String::new(), spectator: false }).await;
+        assert!(matches!(first_host.recv().await, Packet::PeerJoinAttempt { target_id, .. } if target_id == joiner_id));
+
+        // Both rooms have the same join code and room ID, but the request was made in the first app.
+        second_host.send(&Packet::JoinRes { target_id: joiner_id, room_id: join_code, allowed: true }).await;
+        let Packet::Error { error_code, .. } = second_host.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Conflict as i32);
+        joiner.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn close_room_removes_the_room_and_disconnects_its_peers() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, _) = join_room(&mut relay, &mut host, "app", &join_code).await;
+
+        assert_eq!(relay.admin(&format!("close-room {join_code}")).await, format!("closed room {join_code}"));
+        assert_eq!(host.recv().await, Packet::ForceDisconnect);
+        assert_eq!(joiner.recv().await, Packet::ForceDisconnect);
+
+        assert_eq!(relay.admin("list-rooms").await, "no rooms");
+        assert_eq!(
+            relay.admin(&format!("close-room {join_code}")).await,
+            format!("error: no room with join code {join_code}")
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_host_can_transfer_the_room() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut joiner, joiner_peer) = join_room(&mut relay, &mut host, "app", &join_code).await;
+
+        joiner.send(&Packet::TransferHost { peer_id: joiner_peer }).await;
+        let Packet::Error { error_code, .. } = joiner.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Forbidden as i32);
+        host.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn the_room_can_only_be_transferred_to_a_peer_in_it() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut host, _) = create_room(&mut relay, "app").await;
+
+        host.send(&Packet::TransferHost { peer_id: 99 }).await;
+        let Packet::Error { error_code, .. } = host.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::NotFound as i32);
+
+        // It's still the host.
+        host.send(&Packet::ReqHost).await;
+        assert_eq!(host.recv().await, Packet::HostInfo { peer_id: 1 });
+    }
+
+    #[tokio::test]
+    async fn peers_can_reconnect_to_their_slot_until_the_grace_period_ends() {
+        let mut config = testing::config();
+        config.reconnect_grace_ms = 100;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+
+        let (mut joiner, joiner_id) = relay.authenticate("app").await;
+        joiner.send(&Packet::ReqJoin { room_id: join_code.clone(), metadata: String::new(), spectator: false }).await;
+        assert!(matches!(host.recv().await, Packet::PeerJoinAttempt { .. }));
+        host.send(&Packet::JoinRes { target_id: joiner_id, room_id: join_code, allowed: true }).await;
+        let Packet::ConnectedToRoom { peer_id, reconnect_token: token, .. } = joiner.recv().await else {
+            panic!("expected ConnectedToRoom");
+        };
+        assert_eq!(host.recv().await, Packet::PeerJoinedRoom { peer_id });
+
+        joiner.send(&Packet::Disconnect).await;
+        assert_eq!(host.recv().await, Packet::PeerLeftRoom { peer_id });
+
+        // Inside the grace period it gets its old peer ID back, with a new token.
+        let (mut back, _) = relay.authenticate("app").await;
+        back.send(&Packet::Reconnect { token: token.clone() }).await;
+        let Packet::ConnectedToRoom { peer_id: same_peer, reconnect_token: new_token, .. } = back.recv().await else {
+            panic!("expected ConnectedToRoom");
+        };
+        assert_eq!(same_peer, peer_id);
+        assert_ne!(new_token, token);
+        assert!(matches!(back.recv().await, Packet::RoomSnapshot { .. }));
+        assert_eq!(host.recv().await, Packet::PeerJoinedRoom { peer_id });
+
+        back.send(&Packet::Disconnect).await;
+        assert_eq!(host.recv().await, Packet::PeerLeftRoom { peer_id });
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        let (mut late, _) = relay.authenticate("app").await;
+        late.send(&Packet::Reconnect { token: new_token }).await;
+        let Packet::Error { error_code, .. } = late.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Gone as i32);
+        host.expect_nothing().await;
+    }
+
+    #[tokio::test]
+    async fn reconnecting_peers_get_a_snapshot_of_a_full_room() {
+        let mut relay = TestRelay::start(testing::config());
+        let metadata = RoomMetadata::from([("mode".to_string(), "ctf".to_string())]);
+        let (mut host, _) = relay.authenticate("app").await;
+        host.send(&Packet::CreateRoom { is_public: true, metadata: metadata.clone(), max_players: 4, max_join_rtt_ms: 0 }).await;
+        let Packet::RoomCreated { join_code, .. } = host.recv().await else {
+            panic!("expected RoomCreated");
+        };
+
+        let mut peers = Vec::new();
+        for name in ["red", "blue"] {
+            let (mut joiner, joiner_id) = relay.authenticate("app").await;
+            joiner.send(&Packet::ReqJoin { room_id: join_code.clone(), metadata: name.to_string(), spectator: false }).await;
+            assert!(matches!(host.recv().await, Packet::PeerJoinAttempt { .. }));
+            host.send(&Packet::JoinRes { target_id: joiner_id, room_id: join_code.clone(), allowed: true }).await;
+            let Packet::ConnectedToRoom { peer_id, reconnect_token, .. } = joiner.recv().await else {
+                panic!("expected ConnectedToRoom");
+            };
+            assert_eq!(host.recv().await, Packet::PeerJoinedRoom { peer_id });
+            peers.push((joiner, peer_id, reconnect_token));
+        }
+
+        let (mut blue, blue_peer, blue_token) = peers.pop().unwrap();
+        let (mut red, red_peer, _) = peers.pop().unwrap();
+
+        red.send(&Packet::ReqRoster).await;
+        assert_eq!(red.recv().await, Packet::Roster { peers: vec![1, red_peer, blue_peer], host_peer_id: 1 });
+
+        blue.send(&Packet::Disconnect).await;
+        assert_eq!(host.recv().await, Packet::PeerLeftRoom { peer_id: blue_peer });
+        assert_eq!(red.recv().await, Packet::PeerLeftRoom { peer_id: blue_peer });
+
+        let (mut back, _) = relay.authenticate("app").await;
+        back.send(&Packet::Reconnect { token: blue_token }).await;
+        assert!(matches!(back.recv().await, Packet::ConnectedToRoom { peer_id, .. } if peer_id == blue_peer));
+        assert_eq!(back.recv().await, Packet::RoomSnapshot {
+            peers: vec![(1, String::new()), (red_peer, "red".to_string()), (blue_peer, "blue".to_string())],
+            host: 1,
+            metadata,
+        });
+    }
+
+    #[tokio::test]
+    async fn room_metadata_is_limited_to_max_metadata_bytes() {
+        // A single entry takes 13 bytes plus its value once serialized.
+        let metadata = |len: usize| RoomMetadata::from([("k".to_string(), "v".repeat(len - 13))]);
+        let mut config = testing::config();
+        config.max_metadata_bytes = 32;
+        let mut relay = TestRelay::start(config);
+        let (mut host, _) = relay.authenticate("app").await;
+
+        host.send(&Packet::CreateRoom { is_public: true, metadata: metadata(33), max_players: 4, max_join_rtt_ms: 0 }).await;
+        let Packet::Error { error_code, .. } = host.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::TooLarge as i32);
+
+        host.send(&Packet::CreateRoom { is_public: true, metadata: metadata(32), max_players: 4, max_join_rtt_ms: 0 }).await;
+        let Packet::RoomCreated { room_id, .. } = host.recv().await else {
+            panic!("expected RoomCreated");
+        };
+
+        host.send(&Packet::UpdateRoom { room_id, metadata: metadata(33) }).await;
+        let Packet::Error { error_code, .. } = host.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::TooLarge as i32);
+
+        // The room kept the metadata it had.
+        let (mut browser, _) = relay.authenticate("app").await;
+        browser.send(&Packet::ReqRooms { stream: false, offset: 0, limit: 10, filter: String::new() }).await;
+        let Packet::GetRooms { rooms, .. } = browser.recv().await else {
+            panic!("expected GetRooms");
+        };
+        assert_eq!(rooms[0].metadata, metadata(32));
+    }
+
+    #[tokio::test]
+    async fn room_lists_are_filtered_by_metadata() {
+        let mut relay = TestRelay::start(testing::config());
+        let mut hosts = Vec::new();
+        for (is_public, gamemode) in [(true, Some("ctf")), (true, Some("dm")), (true, None), (false, Some("ctf"))] {
+            let metadata: RoomMetadata = gamemode.iter().map(|mode| ("gamemode".to_string(), mode.to_string())).collect();
+            let (mut host, _) = relay.authenticate("app").await;
+            host.send(&Packet::CreateRoom { is_public, metadata, max_players: 4, max_join_rtt_ms: 0 }).await;
+            assert!(matches!(host.recv().await, Packet::RoomCreated { .. }));
+            hosts.push(host);
+        }
+
+        let (mut browser, _) = relay.authenticate("app").await;
+        for (filter, expected) in [("gamemode=ctf", vec!["ctf"]), ("gamemode", vec!["ctf", "dm"]), ("", vec!["", "ctf", "dm"]), ("gamemode=koth", vec![])] {
+            browser.send(&Packet::ReqRooms { stream: false, offset: 0, limit: 10, filter: filter.to_string() }).await;
+            let Packet::GetRooms { rooms, total, .. } = browser.recv().await else {
+                panic!("expected GetRooms");
+            };
+
+            let mut gamemodes: Vec<&str> = rooms.iter()
+                .map(|room| room.metadata.get("gamemode").map_or("", String::as_str))
+                .collect();
+            gamemodes.sort_unstable();
+            assert_eq!(gamemodes, expected, "filter {filter:?}");
+            assert_eq!(total as usize, expected.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn locked_rooms_turn_away_joins_until_unlocked() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        host.send(&Packet::SetRoomLocked { locked: true }).await;
+
+        let (mut turned_away, _) = relay.authenticate("app").await;
+        turned_away.send(&Packet::ReqJoin { room_id: join_code.clone(), metadata: String::new(), spectator: false }).await;
+        let Packet::Error { error_code, .. } = turned_away.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Locked as i32);
+        host.expect_nothing().await;
+
+        host.send(&Packet::SetRoomLocked { locked: false }).await;
+        join_room(&mut relay, &mut host, "app", &join_code).await;
+    }
+
+    #[tokio::test]
+    async fn the_new_host_gets_what_the_host_would() {
+        let mut relay = TestRelay::start(testing::config());
+        let (mut old_host, join_code) = create_room(&mut relay, "app").await;
+        let (mut new_host, new_host_peer) = join_room(&mut relay, &mut old_host, "app", &join_code).await;
+
+        old_host.send(&Packet::TransferHost { peer_id: new_host_peer }).await;
+        assert_eq!(new_host.recv().await, Packet::BecameHost);
+        assert_eq!(old_host.recv().await, Packet::HostChanged { peer_id: new_host_peer });
+
+        // Join requests and the peers that join now go to the new host, and the old one hears about neither.
+        join_room(&mut relay, &mut new_host, "app", &join_code).await;
+        old_host.expect_nothing().await;
+
+        old_host.send(&Packet::JoinRes { target_id: 0, room_id: join_code, allowed: true }).await;
+        let Packet::Error { error_code, .. } = old_host.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(error_code, ErrorCode::Forbidden as i32);
+    }
+
+    #[tokio::test]
+    async fn host_migration_hands_the_room_to_the_lowest_peer() {
+        let mut config = testing::config();
+        config.host_migration = true;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut first, first_peer) = join_room(&mut relay, &mut host, "app", &join_code).await;
+        let (mut second, _) = join_room(&mut relay, &mut host, "app", &join_code).await;
+
+        host.send(&Packet::Disconnect).await;
+        assert_eq!(first.recv().await, Packet::PeerLeftRoom { peer_id: 1 });
+        assert_eq!(first.recv().await, Packet::BecameHost);
+        assert_eq!(second.recv().await, Packet::PeerLeftRoom { peer_id: 1 });
+        assert_eq!(second.recv().await, Packet::HostChanged { peer_id: first_peer });
+    }
+
+    #[tokio::test]
+    async fn req_host_names_the_host_after_migration() {
+        let mut config = testing::config();
+        config.host_migration = true;
+        let mut relay = TestRelay::start(config);
+        let (mut host, join_code) = create_room(&mut relay, "app").await;
+        let (mut new_host, new_host_peer) = join_room(&mut relay, &mut host, "app", &join_code).await;
+        let (mut peer, _) = join_room(&mut relay, &mut host, "app", &join_code).await;
+
+        host.send(&Packet::Disconnect).await;
+        assert_eq!(new_host.recv().await, Packet::PeerLeftRoom { peer_id: 1 });
+        assert_eq!(new_host.recv().await, Packet::BecameHost);
+        assert_eq!(peer.recv().await, Packet::PeerLeftRoom { peer_id: 1 });
+        assert_eq!(peer.recv().await, Packet::HostChanged { peer_id: new_host_peer });
+
+        // Everyone left agrees on who the host is, the new host included.
+        for client in [&mut new_host, &mut peer] {
+            client.send(&Packet::ReqHost).await;
+            assert_eq!(client.recv().await, Packet::HostInfo { peer_id: new_host_peer });
+        }
+    }
+
+    /// Sets up a relay with host migration on and a room holding `peers` clients, the first of them hosting.
+    /// Returns the server, the app and room IDs, and the clients in the order they joined.
+    fn migration_room(peers: u16) -> (RelayServer<MemorySocket>, u64, u64, Vec<u64>) {
+        let (socket, _) = MemorySocket::pair();
+        let mut config = testing::config();
+        config.host_migration = true;
+        let mut server = RelayServer::new(PaperInterface::new(vec![socket], 64), config).unwrap();
+        let app_id = server.apps.create("app".to_string());
+
+        let client_ids: Vec<u64> = (0..peers)
+            .map(|port| {
+                let addr = SocketAddr::from(([127, 0, 0, 1], 4000 + port));
+                let client_id = server.udp.connection_manager.create_session(addr, Channel::new()).unwrap().id;
+                server.clients.create(client_id);
+                server.apps.add_client(app_id);
+                client_id
+            })
+            .collect();
+
+        let rooms = &mut server.apps.get_mut(app_id).unwrap().rooms;
+        let room = rooms.create(client_ids[0], true, RoomMetadata::new(), 4);
+        for &client_id in &client_ids {
+            room.add_peer(client_id);
+        }
+        let room_id = room.id;
+
+        for &client_id in &client_ids {
+            server.clients.get_mut(client_id).unwrap().state = ClientState::InRoom { app_id, room_id };
+        }
+
+        (server, app_id, room_id, client_ids)
+    }
+
+    #[tokio::test]
+    async fn host_migration_skips_peers_that_are_leaving() {
+        let (mut server, app_id, room_id, clients) = migration_room(3);
+        server.udp.connection_manager.close_session(clients[1], Duration::from_secs(1));
+
+        server.handle_disconnect(clients[0], DisconnectReason::Graceful).await;
+
+        let room = server.apps.get(app_id).unwrap().rooms.get(room_id).expect("the room should be kept");
+        assert_eq!(room.get_host(), clients[2]);
+    }
+
+    #[tokio::test]
+    async fn rooms_with_no_one_left_to_host_are_closed() {
+        let (mut server, app_id, room_id, clients) = migration_room(2);
+        server.udp.connection_manager.close_session(clients[1], Duration::from_secs(1));
+
+        server.handle_disconnect(clients[0], DisconnectReason::Graceful).await;
+
+        assert!(server.apps.get(app_id).and_then(|app| app.rooms.get(room_id)).is_none());
+        assert!(server.clients.get(clients[1]).is_none());
+    }
+
+    #[tokio::test]
+    async fn clients_left_in_a_missing_room_are_told_once_and_moved_to_the_lobby() {
+        let (socket, client) = MemorySocket::pair();
+        let mut server = RelayServer::new(PaperInterface::new(vec![socket], 64), testing::config()).unwrap();
+        let client_id = server.udp.connection_manager.create_session(client.local_addr(), Channel::new()).unwrap().id;
+        server.clients.create(client_id);
+        let app_id = server.apps.create("app".to_string());
+
+        // The room was removed without the client's state catching up.
+        server.clients.get_mut(client_id).unwrap().state = ClientState::InRoom { app_id, room_id: 7 };
+
+        let mut channel = Channel::new();
+        let mut next_error = || {
+            let mut buf = [0u8; 2048];
+            let (len, _) = client.try_recv_from(&mut buf).expect("the relay should reply");
+            let DecodeResult::Reliable { payload, .. } = channel.decode(&buf[..len]) else {
+                panic!("expected a reliable packet");
+            };
+            let Ok(Packet::Error { error_code, .. }) = Packet::from_bytes(&payload[0]) else {
+                panic!("expected an error");
+            };
+            error_code
+        };
+
+        let data = Packet::GameData { from_peer: 1, data: vec![1] }.to_bytes();
+        server.handle_packet(client_id, data.clone(), TransferChannel::Reliable).await;
+        assert_eq!(next_error(), ErrorCode::Gone as i32);
+        assert!(matches!(server.clients.get(client_id).unwrap().state, ClientState::Authenticated { app_id: id } if id == app_id));
+
+        // From the lobby, the same packet gets the usual reply for game data sent outside a room.
+        server.handle_packet(client_id, data, TransferChannel::Reliable).await;
+        assert_eq!(next_error(), ErrorCode::NotFound as i32);
     }
 }
@@ -0,0 +1,179 @@
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::protocol::packet::RoomMetadata;
+
+/// The version of the snapshots `FileRoomStore` writes.
+/// Version 1 had no version field, and kept room metadata as a single string.
+const SNAPSHOT_VERSION: i64 = 2;
+/// The metadata key a version 1 room's metadata string is kept under.
+const LEGACY_METADATA_KEY: &str = "metadata";
+
+/// A room saved so its join code can be reclaimed after a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoredRoom {
+    pub app_token: String,
+    pub join_code: String,
+    /// The stable ID of the host, which is the only client allowed to reclaim the room.
+    pub host_stable_id: String,
+    /// The resume token the host had when the room was saved, which it has to present to reclaim the room.
+    /// Rooms saved before this was kept have none, so they can't be reclaimed.
+    #[serde(default)]
+    pub host_secret: String,
+    pub is_public: bool,
+    pub metadata: RoomMetadata,
+    pub max_players: i32,
+    pub max_join_rtt_ms: u32,
+}
+
+/// Somewhere to keep rooms between restarts.
+pub trait RoomStore: Send {
+    /// Replaces everything in the store with the given rooms.
+    fn save(&mut self, rooms: &[StoredRoom]) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    /// Loads the rooms from the last save.
+    fn load(&mut self) -> Result<Vec<StoredRoom>, Box<dyn Error + Send + Sync>>;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: i64,
+    rooms: Vec<StoredRoom>,
+}
+
+/// Stores rooms in a TOML file.
+pub struct FileRoomStore {
+    path: PathBuf,
+}
+
+impl FileRoomStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl RoomStore for FileRoomStore {
+    fn save(&mut self, rooms: &[StoredRoom]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let contents = toml::to_string(&Snapshot { version: SNAPSHOT_VERSION, rooms: rooms.to_vec() })?;
+
+        // Write to a temporary file first so a crash mid-write can't leave a corrupt snapshot.
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    fn load(&mut self) -> Result<Vec<StoredRoom>, Box<dyn Error + Send + Sync>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let contents = fs::read_to_string(&self.path)?;
+        let mut snapshot: toml::Table = toml::from_str(&contents)?;
+
+        match snapshot.get("version").and_then(toml::Value::as_integer).unwrap_or(1) {
+            1 => migrate_v1(&mut snapshot),
+            SNAPSHOT_VERSION => {}
+            version => return Err(format!("room store has unknown version {version}").into()),
+        }
+        snapshot.insert("version".to_string(), SNAPSHOT_VERSION.into());
+
+        let snapshot: Snapshot = toml::Value::Table(snapshot).try_into()?;
+        Ok(snapshot.rooms)
+    }
+}
+
+/// Moves each room's metadata string under `LEGACY_METADATA_KEY`, since rooms keep a map now.
+/// Empty strings become empty maps.
+fn migrate_v1(snapshot: &mut toml::Table) {
+    let Some(rooms) = snapshot.get_mut("rooms").and_then(toml::Value::as_array_mut) else {
+        return;
+    };
+
+    for room in rooms.iter_mut().filter_map(toml::Value::as_table_mut) {
+        let Some(toml::Value::String(metadata)) = room.remove("metadata") else {
+            continue;
+        };
+
+        let mut map = toml::Table::new();
+        if !metadata.is_empty() {
+            map.insert(LEGACY_METADATA_KEY.to_string(), metadata.into());
+        }
+        room.insert("metadata".to_string(), map.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str) -> FileRoomStore {
+        let path = std::env::temp_dir().join(format!("relay-store-{}-{name}.toml", std::process::id()));
+        let _ = fs::remove_file(&path);
+        FileRoomStore::new(path)
+    }
+
+    fn room(metadata: RoomMetadata) -> StoredRoom {
+        StoredRoom {
+            app_token: "app".to_string(),
+            join_code: "ABCDE".to_string(),
+            host_stable_id: "host".to_string(),
+            host_secret: "secret".to_string(),
+            is_public: true,
+            metadata,
+            max_players: 4,
+            max_join_rtt_ms: 0,
+        }
+    }
+
+    #[test]
+    fn rooms_survive_a_save_and_load() {
+        let mut store = store("round-trip");
+        let rooms = vec![room(RoomMetadata::from([("map".to_string(), "dust".to_string())]))];
+
+        store.save(&rooms).unwrap();
+        assert_eq!(store.load().unwrap(), rooms);
+        let _ = fs::remove_file(&store.path);
+    }
+
+    #[test]
+    fn version_1_metadata_strings_are_migrated() {
+        let mut store = store("v1");
+        fs::write(&store.path, r#"
+            [[rooms]]
+            app_token = "app"
+            join_code = "ABCDE"
+            host_stable_id = "host"
+            host_secret = "secret"
+            is_public = true
+            metadata = "gamemode=ctf"
+            max_players = 4
+            max_join_rtt_ms = 0
+
+            [[rooms]]
+            app_token = "app"
+            join_code = "FGHIJ"
+            host_stable_id = "host"
+            is_public = true
+            metadata = ""
+            max_players = 4
+            max_join_rtt_ms = 0
+        "#).unwrap();
+
+        let rooms = store.load().unwrap();
+        assert_eq!(rooms[0], room(RoomMetadata::from([(LEGACY_METADATA_KEY.to_string(), "gamemode=ctf".to_string())])));
+        assert!(rooms[1].metadata.is_empty());
+        let _ = fs::remove_file(&store.path);
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let mut store = store("future");
+        fs::write(&store.path, "version = 99\nrooms = []\n").unwrap();
+
+        assert!(store.load().is_err());
+        let _ = fs::remove_file(&store.path);
+    }
+}
@@ -0,0 +1,200 @@
+//! Runs a relay over in-memory sockets, so tests can drive it the way real clients would.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, watch};
+use paperudp::channel::{Channel, DecodeResult};
+use paperudp::packet::PacketType;
+use crate::admin::{AdminCommand, AdminRequest};
+use crate::config::loader::Config;
+use crate::health::stats::StatsSnapshot;
+use crate::protocol::packet::{Packet, CONNECT_PADDING};
+use crate::protocol::version::{PROTOCOL_VERSION, WIRE_VERSION};
+use crate::relay::server::RelayServer;
+use crate::udp::paper_interface::PaperInterface;
+use crate::udp::socket::DatagramSocket;
+use crate::udp::socket::memory::{MemoryNetwork, MemorySocket};
+
+/// How long `TestClient::recv` waits before deciding nothing is coming.
+const RECV_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long `TestClient::expect_nothing` listens for.
+const QUIET_PERIOD: Duration = Duration::from_millis(100);
+
+/// A config that accepts the current client version, with the relay ID from `.env.example`,
+/// and otherwise uses the defaults.
+pub fn config() -> Config {
+    let mut config: Config = toml::from_str("").unwrap();
+    config.allowed_versions = vec![PROTOCOL_VERSION.to_string()];
+    config.relay_id = "LOCAL".to_string();
+    config
+}
+
+/// A relay running in the background of a test.
+pub struct TestRelay {
+    network: MemoryNetwork,
+    addr: SocketAddr,
+    next_port: u16,
+    pub stats: watch::Receiver<StatsSnapshot>,
+    admin: mpsc::Sender<AdminRequest>,
+}
+
+impl TestRelay {
+    /// Starts a relay on its own in-memory network. It runs until the test's runtime shuts down.
+    pub fn start(config: Config) -> Self {
+        let network = MemoryNetwork::new();
+        let addr = "127.0.0.1:7000".parse().unwrap();
+        let transport = PaperInterface::new(vec![network.bind(addr)], 64);
+        let mut server = RelayServer::new(transport, config).unwrap();
+        let stats = server.stats();
+        let admin = server.admin();
+
+        tokio::spawn(async move {
+            let _ = Box::pin(server.run()).await;
+        });
+
+        Self { network, addr, next_port: 40000, stats, admin }
+    }
+
+    /// Runs an admin command the way the admin listener would, returning the reply.
+    pub async fn admin(&self, line: &str) -> String {
+        let command = AdminCommand::parse(line).unwrap();
+        let (reply, response) = oneshot::channel();
+        self.admin.send(AdminRequest { command, reply }).await.unwrap();
+        response.await.unwrap()
+    }
+
+    /// Binds a new client that hasn't sent anything yet.
+    pub fn client(&mut self) -> TestClient {
+        self.next_port += 1;
+        TestClient {
+            socket: self.network.bind(SocketAddr::from(([127, 0, 0, 1], self.next_port))),
+            relay: self.addr,
+            channel: Channel::new(),
+            received: VecDeque::new(),
+        }
+    }
+
+    /// Opens a session for a new client. Returns the client and the ID the relay gave it.
+    pub async fn connect(&mut self) -> (TestClient, u64) {
+        let mut client = self.client();
+
+        let cookie = client.request_cookie().await;
+        client.send(&Packet::Connect { protocol_version: WIRE_VERSION, cookie }).await;
+        let Packet::ConnectAccepted { client_id, .. } = client.recv().await else {
+            panic!("expected ConnectAccepted");
+        };
+
+        (client, client_id)
+    }
+
+    /// Connects a client and authenticates it with `app_id`.
+    pub async fn authenticate(&mut self, app_id: &str) -> (TestClient, u64) {
+        let (client, client_id, _) = self.authenticate_as(app_id, "", "").await;
+        (client, client_id)
+    }
+
+    /// Connects a client and authenticates it with a stable ID, and the resume token from a previous session.
+    /// Returns the client, its ID and the resume token it was given.
+    pub async fn authenticate_as(&mut self, app_id: &str, stable_id: &str, resume_token: &str) -> (TestClient, u64, String) {
+        let (mut client, client_id) = self.connect().await;
+
+        client.send(&Packet::Authenticate {
+            app_id: app_id.to_string(),
+            version: PROTOCOL_VERSION.to_string(),
+            stable_id: stable_id.to_string(),
+            resume_token: resume_token.to_string(),
+        }).await;
+        let Packet::ClientAuthenticated { resume_token, .. } = client.recv().await else {
+            panic!("expected ClientAuthenticated");
+        };
+
+        (client, client_id, resume_token)
+    }
+}
+
+/// A client talking to a `TestRelay`, with the reliability layer handled for it.
+pub struct TestClient {
+    socket: MemorySocket,
+    relay: SocketAddr,
+    channel: Channel,
+    /// Packets already decoded but not yet returned by `recv`.
+    received: VecDeque<Packet>,
+}
+
+impl TestClient {
+    pub async fn send(&mut self, packet: &Packet) {
+        self.send_bytes(&packet.to_bytes(), PacketType::ReliableOrdered).await;
+    }
+
+    pub async fn send_unreliable(&mut self, packet: &Packet) {
+        self.send_bytes(&packet.to_bytes(), PacketType::Unreliable).await;
+    }
+
+    /// Sends the first half of the handshake and returns the cookie the relay answers with.
+    pub async fn request_cookie(&mut self) -> Vec<u8> {
+        self.send_unreliable(&Packet::Connect { protocol_version: WIRE_VERSION, cookie: vec![0; CONNECT_PADDING] }).await;
+        let Packet::ConnectAccepted { client_id: 0, cookie } = self.recv().await else {
+            panic!("expected ConnectAccepted with a cookie");
+        };
+
+        cookie
+    }
+
+    pub async fn send_bytes(&mut self, data: &[u8], packet_type: PacketType) {
+        let datagram = self.channel.encode(data, packet_type);
+        self.socket.send_to(&datagram, self.relay).await.unwrap();
+    }
+
+    /// Sends a datagram as-is, without going through the reliability layer.
+    pub async fn send_datagram(&self, datagram: &[u8]) {
+        self.socket.send_to(datagram, self.relay).await.unwrap();
+    }
+
+    /// Returns the next packet from the relay, panicking if none arrives in time.
+    /// Heartbeats and pings are skipped, since they're sent on a timer.
+    pub async fn recv(&mut self) -> Packet {
+        tokio::time::timeout(RECV_TIMEOUT, self.next_packet()).await
+            .expect("timed out waiting for a packet from the relay")
+    }
+
+    /// Panics if the relay sends anything within a short period.
+    pub async fn expect_nothing(&mut self) {
+        if let Ok(packet) = tokio::time::timeout(QUIET_PERIOD, self.next_packet()).await {
+            panic!("expected nothing from the relay, got {packet:?}");
+        }
+    }
+
+    async fn next_packet(&mut self) -> Packet {
+        let mut buf = vec![0u8; 65535];
+
+        loop {
+            if let Some(packet) = self.received.pop_front() {
+                return packet;
+            }
+
+            self.socket.readable().await.unwrap();
+            let Ok((len, _)) = self.socket.try_recv_from(&mut buf) else {
+                continue;
+            };
+
+            let payload = match self.channel.decode(&buf[..len]) {
+                DecodeResult::Reliable { payload, ack_packet, .. } => {
+                    if let Some(ack) = ack_packet {
+                        self.socket.send_to(&ack, self.relay).await.unwrap();
+                    }
+                    payload
+                }
+                DecodeResult::Unreliable { payload } => payload,
+                DecodeResult::Ack { .. } | DecodeResult::None => continue,
+            };
+
+            for data in payload {
+                let packet = Packet::from_bytes(&data).unwrap();
+                if !matches!(packet, Packet::Heartbeat | Packet::Ping { .. }) {
+                    self.received.push_back(packet);
+                }
+            }
+        }
+    }
+}
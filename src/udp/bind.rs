@@ -0,0 +1,118 @@
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+use crate::udp::error::UdpError;
+
+/// Which kind of address to bind to when a host name resolves to several.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressFamily {
+    /// Whatever the host name resolves to first.
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => addr.is_ipv4(),
+            AddressFamily::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressFamily::Any => write!(f, "any"),
+            AddressFamily::Ipv4 => write!(f, "IPv4"),
+            AddressFamily::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Resolves a bind address, picking the first result in the preferred family.
+pub fn resolve(address: &str, family: AddressFamily) -> Result<SocketAddr, UdpError> {
+    let resolved = address.to_socket_addrs()
+        .map_err(|e| UdpError::ResolveError { address: address.to_string(), reason: e.to_string() })?;
+
+    let mut found_any = false;
+    for addr in resolved {
+        if family.matches(&addr) {
+            return Ok(addr);
+        }
+        found_any = true;
+    }
+
+    let reason = if found_any {
+        format!("it has no {family} address")
+    } else {
+        "it has no addresses".to_string()
+    };
+
+    Err(UdpError::ResolveError { address: address.to_string(), reason })
+}
+
+/// How sockets are set up before binding.
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// Lets an IPv6 socket accept IPv4 traffic too, so binding `[::]` serves both stacks.
+    pub dual_stack: bool,
+    /// The receive buffer size to ask the OS for, in bytes. 0 keeps the OS default.
+    pub recv_buffer_bytes: usize,
+    /// The send buffer size to ask the OS for, in bytes. 0 keeps the OS default.
+    pub send_buffer_bytes: usize,
+}
+
+/// Binds a non-blocking UDP socket.
+pub fn bind_socket(addr: SocketAddr, options: SocketOptions) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(!options.dual_stack)?;
+    }
+
+    if options.recv_buffer_bytes > 0 {
+        socket.set_recv_buffer_size(options.recv_buffer_bytes)?;
+        log_buffer_size(addr, "receive", options.recv_buffer_bytes, socket.recv_buffer_size()?);
+    }
+
+    if options.send_buffer_bytes > 0 {
+        socket.set_send_buffer_size(options.send_buffer_bytes)?;
+        log_buffer_size(addr, "send", options.send_buffer_bytes, socket.send_buffer_size()?);
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+/// The OS quietly clamps buffer sizes (e.g. to `net.core.rmem_max` on Linux), so what was granted is logged.
+fn log_buffer_size(addr: SocketAddr, kind: &str, requested: usize, reported: usize) {
+    let granted = usable_buffer_size(reported);
+    if granted < requested {
+        warn!("{} buffer for {} is {} bytes, less than the {} requested. The OS limit may need raising", kind, addr, granted, requested);
+    } else {
+        info!("{} buffer for {} is {} bytes", kind, addr, granted);
+    }
+}
+
+/// Linux doubles the size it's asked for to leave room for its own bookkeeping,
+/// and reports the doubled value back, so only half of it holds datagrams.
+#[cfg(target_os = "linux")]
+fn usable_buffer_size(reported: usize) -> usize {
+    reported / 2
+}
+
+#[cfg(not(target_os = "linux"))]
+fn usable_buffer_size(reported: usize) -> usize {
+    reported
+}
@@ -1,12 +1,34 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum TransferChannel {
     Reliable,
     Unreliable,
+    /// Unreliable, but packets older than the newest one received are dropped.
+    UnreliableSequenced,
+}
+
+/// Why a client left the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Nothing was heard from the client for too long.
+    Timeout,
+    /// The client said it was leaving.
+    Graceful,
+    /// The client kept sending data that couldn't be decoded.
+    ProtocolError,
+    /// The relay dropped the client, e.g. for going over a rate limit.
+    Kicked,
+    /// The client stopped acking reliable data and left too much of it waiting to be resent.
+    Saturated,
+    /// The relay is shutting down.
+    ServerShutdown,
 }
 
 #[derive(Debug, Clone)]
 pub enum ServerEvent {
     ClientConnected { client_id: u64 },
-    ClientDisconnected { client_id: u64 },
+    ClientDisconnected { client_id: u64, reason: DisconnectReason },
     PacketReceived { client_id: u64, data: Vec<u8>, channel: TransferChannel },
 }
\ No newline at end of file
@@ -0,0 +1,78 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use rand::{rng, Rng};
+use ring::hmac;
+
+/// How long each cookie key period lasts. A cookie is accepted in the period it was issued and the one after.
+const BUCKET: Duration = Duration::from_secs(30);
+
+/// Issues and checks the cookies that prove a client can receive at the address it's connecting from.
+/// Cookies are an HMAC of the address and the current time period, so nothing is stored until one comes back.
+pub struct CookieJar {
+    key: hmac::Key,
+    started: Instant,
+}
+
+impl CookieJar {
+    /// Creates a jar with a random key, so cookies from a previous run of the relay aren't accepted.
+    pub fn new() -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, &rng().random::<[u8; 32]>()),
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns the cookie for `addr` in the current time period.
+    pub fn issue(&self, addr: SocketAddr) -> Vec<u8> {
+        hmac::sign(&self.key, &Self::message(addr, self.bucket())).as_ref().to_vec()
+    }
+
+    /// Returns true if `cookie` was issued to `addr` in this time period or the one before.
+    pub fn verify(&self, addr: SocketAddr, cookie: &[u8]) -> bool {
+        let bucket = self.bucket();
+
+        [Some(bucket), bucket.checked_sub(1)].into_iter()
+            .flatten()
+            .any(|bucket| hmac::verify(&self.key, &Self::message(addr, bucket), cookie).is_ok())
+    }
+
+    fn bucket(&self) -> u64 {
+        self.started.elapsed().as_secs() / BUCKET.as_secs()
+    }
+
+    fn message(addr: SocketAddr, bucket: u64) -> Vec<u8> {
+        let mut message = addr.to_string().into_bytes();
+        message.extend(bucket.to_be_bytes());
+        message
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_only_match_the_address_they_were_issued_to() {
+        let jar = CookieJar::new();
+        let addr = "127.0.0.1:4000".parse().unwrap();
+        let cookie = jar.issue(addr);
+
+        assert!(jar.verify(addr, &cookie));
+        assert!(!jar.verify("127.0.0.1:4001".parse().unwrap(), &cookie));
+        assert!(!jar.verify(addr, &cookie[1..]));
+        assert!(!CookieJar::new().verify(addr, &cookie));
+    }
+
+    #[test]
+    fn cookies_expire_after_two_periods() {
+        let mut jar = CookieJar::new();
+        let addr = "[::1]:4000".parse().unwrap();
+        let cookie = jar.issue(addr);
+
+        jar.started = Instant::now().checked_sub(BUCKET).unwrap();
+        assert!(jar.verify(addr, &cookie));
+
+        jar.started = Instant::now().checked_sub(BUCKET * 2).unwrap();
+        assert!(!jar.verify(addr, &cookie));
+    }
+}
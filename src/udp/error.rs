@@ -2,6 +2,9 @@ use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum UdpError {
+    #[error("failed to resolve bind address {address}: {reason}")]
+    ResolveError { address: String, reason: String },
+
     #[error("failed to bind UDP socket: {0}")]
     BindError(std::io::Error),
 
@@ -11,9 +14,20 @@ pub enum UdpError {
     #[error("failed to recv packet: {0}")]
     RecvError(std::io::Error),
 
-    #[error("clock may have gone backwards: {0}")]
-    ClockError(#[from] std::time::SystemTimeError),
-
     #[error("failed to create Netcode server udp: {0}")]
     NetcodeCreationFailed(std::io::Error),
-}
\ No newline at end of file
+}
+#[derive(Debug, Error)]
+pub enum SendError {
+    /// The target has no session, usually because it disconnected while a broadcast was going out.
+    /// Callers can safely skip the target.
+    #[error("client {0} is not connected")]
+    NotConnected(u64),
+
+    /// The packet is reliable and needs more fragments than a client will reassemble.
+    #[error("packet of {0} bytes is too large to send")]
+    TooLarge(usize),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Marks a reliable payload as one fragment of a larger message.
+/// It's followed by a u32 message ID, a u16 fragment index and a u16 fragment count.
+/// Protocol packet IDs never reach this value, so it can't be mistaken for a packet.
+pub const FRAGMENT_MARKER: u8 = 0xFE;
+const HEADER_LEN: usize = 9;
+/// Payloads longer than this are split so each datagram stays under a typical MTU.
+pub const MAX_FRAGMENT_PAYLOAD: usize = 1100;
+/// The most fragments a message can be split into.
+const MAX_FRAGMENTS: u16 = 64;
+/// The largest message that can be reassembled.
+const MAX_MESSAGE_LEN: usize = MAX_FRAGMENT_PAYLOAD * MAX_FRAGMENTS as usize;
+/// The most messages a session can have partly received at once.
+const MAX_PENDING_MESSAGES: usize = 8;
+/// How long a partly received message is kept waiting for the rest of its fragments.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Error)]
+pub enum FragmentError {
+    #[error("fragment header is truncated")]
+    Truncated,
+
+    #[error("fragment {index} of {count} is out of range")]
+    BadIndex { index: u16, count: u16 },
+
+    #[error("fragment count {count} doesn't match the {expected} already seen for message {message_id}")]
+    CountMismatch { message_id: u32, count: u16, expected: u16 },
+
+    #[error("message {0} is too large to reassemble")]
+    TooLarge(u32),
+
+    #[error("too many messages are waiting on fragments")]
+    TooManyPending,
+}
+
+/// Returns true if a reliable payload is a fragment rather than a whole packet.
+pub fn is_fragment(payload: &[u8]) -> bool {
+    payload.first() == Some(&FRAGMENT_MARKER)
+}
+
+/// Splits `data` into fragments of at most `MAX_FRAGMENT_PAYLOAD` bytes, each with a header.
+/// Data that already fits is returned as it is. Returns `None` if it needs more than `MAX_FRAGMENTS`.
+pub fn split(message_id: u32, data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if data.len() <= MAX_FRAGMENT_PAYLOAD {
+        return Some(vec![data.to_vec()]);
+    }
+
+    let count = u16::try_from(data.len().div_ceil(MAX_FRAGMENT_PAYLOAD)).ok()
+        .filter(|&count| count <= MAX_FRAGMENTS)?;
+
+    let fragments = data.chunks(MAX_FRAGMENT_PAYLOAD)
+        .zip(0u16..)
+        .map(|(chunk, index)| {
+            let mut fragment = Vec::with_capacity(HEADER_LEN + chunk.len());
+            fragment.push(FRAGMENT_MARKER);
+            fragment.extend(message_id.to_be_bytes());
+            fragment.extend(index.to_be_bytes());
+            fragment.extend(count.to_be_bytes());
+            fragment.extend(chunk);
+            fragment
+        })
+        .collect();
+
+    Some(fragments)
+}
+
+struct PendingMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: u16,
+    len: usize,
+    started: Instant,
+}
+
+/// Collects fragments from one client until each message is complete.
+/// Fragments can arrive in any order, a message is held until every one of them is in.
+#[derive(Default)]
+pub struct Reassembler {
+    pending: HashMap<u32, PendingMessage>,
+}
+
+impl Reassembler {
+    /// Adds a fragment, returning the whole message once its last fragment arrives.
+    /// A fragment that breaks the limits throws away whatever was received of its message.
+    pub fn insert(&mut self, fragment: &[u8]) -> Result<Option<Vec<u8>>, FragmentError> {
+        if fragment.len() < HEADER_LEN {
+            return Err(FragmentError::Truncated);
+        }
+
+        let message_id = u32::from_be_bytes([fragment[1], fragment[2], fragment[3], fragment[4]]);
+        let index = u16::from_be_bytes([fragment[5], fragment[6]]);
+        let count = u16::from_be_bytes([fragment[7], fragment[8]]);
+        let chunk = &fragment[HEADER_LEN..];
+
+        if count == 0 || count > MAX_FRAGMENTS || index >= count {
+            self.pending.remove(&message_id);
+            return Err(FragmentError::BadIndex { index, count });
+        }
+
+        self.expire();
+
+        if !self.pending.contains_key(&message_id) && self.pending.len() >= MAX_PENDING_MESSAGES {
+            return Err(FragmentError::TooManyPending);
+        }
+
+        let message = self.pending.entry(message_id).or_insert_with(|| PendingMessage {
+            fragments: vec![None; usize::from(count)],
+            received: 0,
+            len: 0,
+            started: Instant::now(),
+        });
+
+        if message.fragments.len() != usize::from(count) {
+            let expected = u16::try_from(message.fragments.len()).unwrap_or(u16::MAX);
+            self.pending.remove(&message_id);
+            return Err(FragmentError::CountMismatch { message_id, count, expected });
+        }
+
+        let slot = &mut message.fragments[usize::from(index)];
+        if slot.is_some() {
+            // A duplicate, the first copy is kept.
+            return Ok(None);
+        }
+
+        message.len += chunk.len();
+        if message.len > MAX_MESSAGE_LEN {
+            self.pending.remove(&message_id);
+            return Err(FragmentError::TooLarge(message_id));
+        }
+
+        *slot = Some(chunk.to_vec());
+        message.received += 1;
+
+        if message.received < count {
+            return Ok(None);
+        }
+
+        let Some(message) = self.pending.remove(&message_id) else {
+            return Ok(None);
+        };
+
+        let mut data = Vec::with_capacity(message.len);
+        for chunk in message.fragments.into_iter().flatten() {
+            data.extend(chunk);
+        }
+
+        Ok(Some(data))
+    }
+
+    /// Drops messages that have waited too long for their missing fragments.
+    fn expire(&mut self) {
+        self.pending.retain(|_, message| message.started.elapsed() < PENDING_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(message_id: u32, index: u16, count: u16, chunk: &[u8]) -> Vec<u8> {
+        let mut fragment = vec![FRAGMENT_MARKER];
+        fragment.extend(message_id.to_be_bytes());
+        fragment.extend(index.to_be_bytes());
+        fragment.extend(count.to_be_bytes());
+        fragment.extend(chunk);
+        fragment
+    }
+
+    #[test]
+    fn five_fragments_reassemble_in_any_order() {
+        let data: Vec<u8> = (0..=MAX_FRAGMENT_PAYLOAD * 4).map(|i| u8::try_from(i % 251).unwrap()).collect();
+        let fragments = split(7, &data).unwrap();
+        assert_eq!(fragments.len(), 5);
+        assert!(fragments.iter().all(|f| is_fragment(f)));
+
+        let mut reassembler = Reassembler::default();
+        for index in [3, 0, 4, 1] {
+            assert!(reassembler.insert(&fragments[index]).unwrap().is_none());
+        }
+        // A duplicate is ignored rather than counted twice.
+        assert!(reassembler.insert(&fragments[0]).unwrap().is_none());
+
+        assert_eq!(reassembler.insert(&fragments[2]).unwrap(), Some(data));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn small_payloads_are_not_fragmented() {
+        let fragments = split(1, b"hello").unwrap();
+        assert_eq!(fragments, vec![b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn a_message_missing_a_middle_fragment_expires() {
+        let data = vec![1; MAX_FRAGMENT_PAYLOAD * 4 + 1];
+        let fragments = split(3, &data).unwrap();
+
+        let mut reassembler = Reassembler::default();
+        for index in [0, 1, 3, 4] {
+            assert!(reassembler.insert(&fragments[index]).unwrap().is_none());
+        }
+
+        let message = reassembler.pending.get_mut(&3).unwrap();
+        message.started = Instant::now().checked_sub(PENDING_TIMEOUT).unwrap();
+
+        // The late fragment starts a new message instead of completing the expired one.
+        assert!(reassembler.insert(&fragments[2]).unwrap().is_none());
+        assert_eq!(reassembler.pending[&3].received, 1);
+    }
+
+    #[test]
+    fn messages_needing_too_many_fragments_are_not_split() {
+        assert_eq!(split(1, &vec![0; MAX_MESSAGE_LEN]).unwrap().len(), usize::from(MAX_FRAGMENTS));
+        assert!(split(1, &vec![0; MAX_MESSAGE_LEN + 1]).is_none());
+    }
+
+    #[test]
+    fn out_of_range_fragments_are_rejected() {
+        let mut reassembler = Reassembler::default();
+
+        assert!(matches!(reassembler.insert(&[FRAGMENT_MARKER, 0, 0]), Err(FragmentError::Truncated)));
+        assert!(matches!(
+            reassembler.insert(&fragment(1, 0, MAX_FRAGMENTS + 1, b"x")),
+            Err(FragmentError::BadIndex { .. })
+        ));
+        assert!(matches!(reassembler.insert(&fragment(1, 2, 2, b"x")), Err(FragmentError::BadIndex { .. })));
+        assert!(matches!(reassembler.insert(&fragment(1, 0, 0, b"x")), Err(FragmentError::BadIndex { .. })));
+    }
+
+    #[test]
+    fn a_fragment_count_that_changes_drops_the_message() {
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.insert(&fragment(1, 0, 3, b"x")).unwrap().is_none());
+
+        assert!(matches!(
+            reassembler.insert(&fragment(1, 1, 4, b"x")),
+            Err(FragmentError::CountMismatch { expected: 3, .. })
+        ));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn oversized_messages_are_dropped() {
+        let chunk = vec![0; MAX_MESSAGE_LEN / 2 + 1];
+        let mut reassembler = Reassembler::default();
+
+        assert!(reassembler.insert(&fragment(1, 0, 3, &chunk)).unwrap().is_none());
+        assert!(matches!(reassembler.insert(&fragment(1, 1, 3, &chunk)), Err(FragmentError::TooLarge(1))));
+        assert!(reassembler.pending.is_empty());
+    }
+
+    #[test]
+    fn pending_messages_are_capped() {
+        let mut reassembler = Reassembler::default();
+        for message_id in 0..u32::try_from(MAX_PENDING_MESSAGES).unwrap() {
+            assert!(reassembler.insert(&fragment(message_id, 0, 2, b"x")).unwrap().is_none());
+        }
+
+        assert!(matches!(reassembler.insert(&fragment(99, 0, 2, b"x")), Err(FragmentError::TooManyPending)));
+        // Messages already in progress can still be finished.
+        assert_eq!(reassembler.insert(&fragment(0, 1, 2, b"y")).unwrap(), Some(b"xy".to_vec()));
+    }
+}
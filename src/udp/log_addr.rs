@@ -0,0 +1,31 @@
+use std::fmt;
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+
+/// Whether client addresses are anonymized in log output.
+static ANONYMIZE: AtomicBool = AtomicBool::new(false);
+/// Keys the address hash. Picked once per process, so the same address always
+/// maps to the same tag while the relay runs, but tags can't be reversed or
+/// matched up across restarts.
+static HASHER: LazyLock<RandomState> = LazyLock::new(RandomState::new);
+
+/// Turns anonymization of client addresses in logs on or off.
+pub fn set_anonymize(enabled: bool) {
+    ANONYMIZE.store(enabled, Ordering::Relaxed);
+}
+
+/// Displays a client address for logging.
+/// When anonymization is on, this prints a stable hash of the address instead.
+pub struct LogAddr(pub SocketAddr);
+
+impl fmt::Display for LogAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if ANONYMIZE.load(Ordering::Relaxed) {
+            write!(f, "addr-{:016x}", HASHER.hash_one(self.0))
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
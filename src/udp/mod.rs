@@ -1,4 +1,9 @@
-mod error;
+pub mod bind;
+pub mod error;
 pub mod common;
+mod cookie;
+mod fragment;
+pub mod log_addr;
 pub mod paper_interface;
 mod sessions;
+pub mod socket;
@@ -1,99 +1,113 @@
 use tokio::net::UdpSocket;
-use std::net::SocketAddr;
+use std::future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::task::Poll;
 use std::time::{Duration, Instant};
-use paperudp::channel::DecodeResult;
+use ipnet::IpNet;
+use paperudp::channel::{Channel, DecodeResult};
 use paperudp::packet::PacketType;
 use tracing::{debug, warn};
-use crate::udp::error::UdpError;
+use crate::metrics::METRICS;
+use crate::protocol::builder::PacketBuilder;
+use crate::protocol::error_code::ErrorCode;
+use crate::protocol::packet::{self, Packet, CONNECT_PADDING};
+use crate::relay::rate_limit::RateLimiter;
+use crate::udp::{bind, fragment};
+use crate::udp::cookie::CookieJar;
+use crate::udp::bind::SocketOptions;
+use crate::udp::error::{SendError, UdpError};
+use crate::udp::log_addr::LogAddr;
 use crate::udp::sessions::ConnectionManager;
-use super::common::{ServerEvent, TransferChannel};
+use crate::udp::socket::DatagramSocket;
+use super::common::{DisconnectReason, ServerEvent, TransferChannel};
 
-pub struct PaperInterface {
-    pub(crate) socket: UdpSocket,
+/// How long a disconnected session is kept alive to flush its reliable queue.
+const DISCONNECT_GRACE: Duration = Duration::from_secs(1);
+/// How many undecodable datagrams in a row a session can send before it's dropped.
+/// Corruption is common on mobile networks, so a single bad datagram is tolerated.
+const MAX_CONSECUTIVE_DECODE_ERRORS: u32 = 8;
+
+pub struct PaperInterface<S = UdpSocket> {
+    /// Every socket the relay listens on. Sessions remember which one their client uses.
+    sockets: Vec<S>,
     pub(crate) connection_manager: ConnectionManager,
+    /// Signs the cookies new clients have to echo back before they get a session.
+    cookies: CookieJar,
+    /// Cookies sent per source address, so the handshake can't be used to flood an address someone else spoofed.
+    cookie_replies: RateLimiter<IpAddr>,
     pending_events: Vec<ServerEvent>,
+    /// The most datagrams read in one call to `recv_events`.
+    /// Stops a flood from starving the rest of the server loop.
+    max_datagrams_per_poll: usize,
+    /// The socket to read from first on the next call to `recv_events`,
+    /// so a flood on one socket can't starve the others.
+    next_socket: usize,
 }
 
 impl PaperInterface {
-    pub async fn new(addr: SocketAddr) -> Result<Self, UdpError> {
-        let socket = UdpSocket::bind(addr).await
-            .map_err(|e| UdpError::BindError(e))?;
+    /// Binds a UDP socket on each address and creates an interface over all of them.
+    pub fn bind(addrs: &[SocketAddr], options: SocketOptions, max_datagrams_per_poll: usize) -> Result<Self, UdpError> {
+        let sockets = addrs.iter()
+            .map(|&addr| bind::bind_socket(addr, options))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(UdpError::BindError)?;
+
+        Ok(Self::new(sockets, max_datagrams_per_poll))
+    }
+}
 
-        Ok(Self {
-            socket,
+impl<S: DatagramSocket> PaperInterface<S> {
+    pub fn new(sockets: Vec<S>, max_datagrams_per_poll: usize) -> Self {
+        assert!(!sockets.is_empty(), "PaperInterface needs at least one socket");
+
+        Self {
+            sockets,
             connection_manager: ConnectionManager::new(),
+            cookies: CookieJar::new(),
+            cookie_replies: RateLimiter::new(u32::MAX, Duration::from_secs(1)),
             pending_events: Vec::new(),
-        })
+            max_datagrams_per_poll: max_datagrams_per_poll.max(1),
+            next_socket: 0,
+        }
     }
 
     pub async fn recv_events(&mut self) -> Result<Vec<ServerEvent>, UdpError> {
         let mut buf = [0u8; 65535];
+        let mut processed = 0;
 
         loop {
-            self.socket.readable().await.map_err(UdpError::RecvError)?;
-
-            loop {
-                match self.socket.try_recv_from(&mut buf) {
-                    Ok((len, addr)) => {
-                        if len == 0 { continue; }
-
-                        let (session_id, session_addr, res) = {
-                            let (session, is_new) = self.connection_manager.get_or_create(addr);
-
-                            if is_new {
-                                self.pending_events.push(ServerEvent::ClientConnected {
-                                    client_id: session.id
-                                })
-                            }
-
-                            session.last_heard_from = Instant::now();
-                            let res = session.channel.decode(&buf[..len]);
-                            (session.id, session.addr, res)
-                        };
-
-                        match res {
-                            DecodeResult::Unreliable { payload } => {
-                                for p in payload {
-                                    if p == [3u8] { continue; }
-                                    self.pending_events.push(ServerEvent::PacketReceived {
-                                        client_id: session_id,
-                                        data: p,
-                                        channel: TransferChannel::Unreliable,
-                                    });
-                                }
-                            }
-                            DecodeResult::Reliable { payload, ack_packet, .. } => {
-                                for p in payload {
-                                    self.pending_events.push(ServerEvent::PacketReceived {
-                                        client_id: session_id,
-                                        data: p,
-                                        channel: TransferChannel::Reliable,
-                                    });
-                                }
-
-                                if let Some(ack) = ack_packet {
-                                    if let Err(e) = self.socket.send_to(ack.as_slice(), session_addr).await {
-                                        warn!("failed to send ack to {}: {}", session_addr, e);
-                                    }
-                                }
-                            }
-                            DecodeResult::Ack { .. } => {}
-                            DecodeResult::None => {
-                                debug!("unknown packet: {:?}", &buf[..len]);
-                                self.remove_client(&session_id);
-                            }
-                        }
+            self.readable().await.map_err(UdpError::RecvError)?;
+
+            let count = self.sockets.len();
+            for offset in 0..count {
+                let socket = (self.next_socket + offset) % count;
+
+                loop {
+                    if processed >= self.max_datagrams_per_poll {
+                        // Hand control back to the server loop, even if there's nothing to report.
+                        self.next_socket = (socket + 1) % count;
+                        return Ok(std::mem::take(&mut self.pending_events));
                     }
 
-                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-                    Err(e) if matches!(
-                    e.kind(),
-                    std::io::ErrorKind::Interrupted
-                        | std::io::ErrorKind::ConnectionReset
-                        | std::io::ErrorKind::ConnectionRefused
-                        | std::io::ErrorKind::ConnectionAborted
-                ) => continue,
-                    Err(e) => return Err(UdpError::RecvError(e)),
+                    match self.sockets[socket].try_recv_from(&mut buf) {
+                        Ok((len, addr)) => {
+                            processed += 1;
+                            if len == 0 { continue; }
+
+                            self.handle_datagram(socket, addr, &buf[..len]).await;
+                        }
+
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) if matches!(
+                        e.kind(),
+                        io::ErrorKind::Interrupted
+                            | io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::ConnectionRefused
+                            | io::ErrorKind::ConnectionAborted
+                    ) => continue,
+                        Err(e) => return Err(UdpError::RecvError(e)),
+                    }
                 }
             }
 
@@ -103,38 +117,751 @@ impl PaperInterface {
         }
     }
 
-    pub async fn send(&mut self, target: u64, data: Vec<u8>, channel: TransferChannel) -> Result<(), std::io::Error> {
-        if let Some(session) = self.connection_manager.get_by_id(&target) {
-            match channel {
-                TransferChannel::Reliable => {
-                    let pkt = session.channel.encode(
-                        &*data,
-                        PacketType::ReliableOrdered
-                    );
-                    self.socket.send_to(&pkt, session.addr).await?;
+    /// Waits until any of the sockets may have a datagram to read.
+    async fn readable(&self) -> io::Result<()> {
+        if let [socket] = self.sockets.as_slice() {
+            return socket.readable().await;
+        }
+
+        let mut waits: Vec<_> = self.sockets.iter()
+            .map(|socket| Box::pin(socket.readable()))
+            .collect();
+
+        future::poll_fn(|cx| {
+            for wait in &mut waits {
+                if let Poll::Ready(result) = wait.as_mut().poll(cx) {
+                    return Poll::Ready(result);
                 }
-                TransferChannel::Unreliable => {
-                    let pkt = session.channel.encode(
-                        &data,
-                        PacketType::Unreliable
-                    );
-                    self.socket.send_to(&pkt, session.addr).await?;
+            }
+
+            Poll::Pending
+        }).await
+    }
+
+    /// Decodes a datagram that arrived on `socket` and queues the events it produces.
+    async fn handle_datagram(&mut self, socket: usize, addr: SocketAddr, datagram: &[u8]) {
+        let (session_id, session_addr, is_new, is_closing, decode_errors, res) = {
+            let handshake = if self.connection_manager.has_session(addr) {
+                None
+            } else {
+                let Some(res) = self.open_session(socket, addr, datagram).await else {
+                    return;
+                };
+                Some(res)
+            };
+
+            let Some(session) = self.connection_manager.get_by_addr(addr) else {
+                return;
+            };
+
+            session.last_heard_from = Instant::now();
+            session.socket = socket;
+            let is_new = handshake.is_some();
+            let res = handshake.unwrap_or_else(|| session.channel.decode(datagram));
+
+            if matches!(res, DecodeResult::None) {
+                session.decode_errors += 1;
+            } else {
+                session.decode_errors = 0;
+            }
+
+            (session.id, session.addr, is_new, session.close_deadline.is_some(), session.decode_errors, res)
+        };
+
+        if is_new {
+            self.pending_events.push(ServerEvent::ClientConnected {
+                client_id: session_id
+            });
+        }
+
+        // Closing sessions only need their acks processed (done by decode).
+        if is_closing {
+            return;
+        }
+
+        match res {
+            DecodeResult::Unreliable { payload } => {
+                for p in payload {
+                    let Some((data, channel)) = self.unwrap_sequenced(session_id, p) else {
+                        continue;
+                    };
+
+                    METRICS.record_received(channel);
+                    self.pending_events.push(ServerEvent::PacketReceived {
+                        client_id: session_id,
+                        data,
+                        channel,
+                    });
                 }
             }
+            DecodeResult::Reliable { payload, ack_packet, .. } => {
+                for p in payload {
+                    let Some(p) = self.reassemble(session_id, p) else {
+                        continue;
+                    };
+
+                    METRICS.record_received(TransferChannel::Reliable);
+                    self.pending_events.push(ServerEvent::PacketReceived {
+                        client_id: session_id,
+                        data: p,
+                        channel: TransferChannel::Reliable,
+                    });
+                }
+
+                if let Some(ack) = ack_packet {
+                    if let Err(e) = self.sockets[socket].send_to(ack.as_slice(), session_addr).await {
+                        warn!("failed to send ack to {}: {}", LogAddr(session_addr), e);
+                    }
+                }
+            }
+            DecodeResult::Ack { .. } => {}
+            DecodeResult::None => {
+                debug!("unknown packet ({} in a row): {:?}", decode_errors, datagram);
+
+                if decode_errors > MAX_CONSECUTIVE_DECODE_ERRORS {
+                    warn!("dropping client {} after {} undecodable packets", session_id, decode_errors);
+                    self.remove_client(session_id);
+                    self.pending_events.push(ServerEvent::ClientDisconnected {
+                        client_id: session_id,
+                        reason: DisconnectReason::ProtocolError,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Opens a session for a datagram from an unknown address, but only if it carries a `Connect` handshake
+    /// with a cookie the relay issued to that address. A padded `Connect` without one is answered with a cookie,
+    /// within the address's budget, and anything else is dropped. Either way no session is created, so a spoofed
+    /// source address can't become a client without receiving at that address. Denied addresses get nothing.
+    /// Returns the decoded datagram, since decoding it again on the new session would see it as a duplicate.
+    async fn open_session(&mut self, socket: usize, addr: SocketAddr, datagram: &[u8]) -> Option<DecodeResult> {
+        if self.connection_manager.is_denied(addr) {
+            debug!("dropping packet from denied address {}", LogAddr(addr));
+            return None;
+        }
+
+        let mut channel = Channel::new();
+        let res = channel.decode(datagram);
+
+        let payload = match &res {
+            DecodeResult::Reliable { payload, .. } | DecodeResult::Unreliable { payload } => payload,
+            DecodeResult::Ack { .. } | DecodeResult::None => return None,
+        };
+
+        let Some(cookie) = payload.first().and_then(|p| packet::connect_cookie(p)) else {
+            debug!("dropping packet from {}, it didn't start with a handshake", LogAddr(addr));
+            return None;
+        };
+
+        if !self.cookies.verify(addr, cookie) {
+            if cookie.len() < CONNECT_PADDING {
+                debug!("dropping handshake from {}, it wasn't padded", LogAddr(addr));
+            } else {
+                self.send_cookie(socket, addr).await;
+            }
+            return None;
+        }
+
+        if self.connection_manager.create_session(addr, channel).is_none() {
+            debug!("dropping packet from {}, it's denied or there are too many new connections", LogAddr(addr));
+            return None;
         }
+
+        Some(res)
+    }
+
+    /// Answers a `Connect` that has no valid cookie with one, unreliably and without keeping anything
+    /// but a count of the cookies sent to the address.
+    async fn send_cookie(&mut self, socket: usize, addr: SocketAddr) {
+        if self.cookie_replies.hit(addr.ip().to_canonical()) > self.cookie_replies.limit() {
+            debug!("not sending a cookie to {}, it was sent too many already", LogAddr(addr));
+            return;
+        }
+
+        let reply = Packet::ConnectAccepted { client_id: 0, cookie: self.cookies.issue(addr) };
+        let datagram = Channel::new().encode(&reply.to_bytes(), PacketType::Unreliable);
+
+        if let Err(e) = self.sockets[socket].send_to(&datagram, addr).await {
+            warn!("failed to send a cookie to {}: {}", LogAddr(addr), e);
+        }
+    }
+
+    /// Sends a packet to a client, logging anything that goes wrong instead of returning it.
+    /// Clients can disconnect while something is still being sent to them, so that's only logged at debug.
+    pub async fn send_packet(&mut self, target: u64, packet: &Packet, channel: TransferChannel) {
+        match self.send(target, packet.to_bytes(), channel).await {
+            Ok(()) => {}
+            Err(SendError::NotConnected(_)) => debug!("skipped packet to {}, it already disconnected", target),
+            Err(e) => warn!("failed to send packet: {}", e),
+        }
+    }
+
+    /// Sends an error to a client reliably.
+    pub async fn send_err(&mut self, target: u64, error_code: ErrorCode, msg: &str) {
+        let (packet, channel) = PacketBuilder::error(error_code, msg).reliable().build();
+        self.send_packet(target, &packet, channel).await;
+    }
+
+    /// Sends a packet to a client.
+    /// Returns `SendError::NotConnected` if the client's session is already gone.
+    pub async fn send(&mut self, target: u64, data: Vec<u8>, channel: TransferChannel) -> Result<(), SendError> {
+        let Some(session) = self.connection_manager.get_by_id(target) else {
+            return Err(SendError::NotConnected(target));
+        };
+
+        let datagrams = match channel {
+            TransferChannel::Reliable => {
+                // Big payloads are split up so they don't get fragmented at the IP layer,
+                // where losing any one piece loses the whole datagram.
+                let message_id = if data.len() > fragment::MAX_FRAGMENT_PAYLOAD {
+                    session.next_message_id()
+                } else {
+                    0
+                };
+                let Some(fragments) = fragment::split(message_id, &data) else {
+                    return Err(SendError::TooLarge(data.len()));
+                };
+
+                fragments.iter()
+                    .map(|fragment| session.channel.encode(fragment, PacketType::ReliableOrdered))
+                    .collect()
+            }
+            TransferChannel::Unreliable => vec![session.channel.encode(
+                &data,
+                PacketType::Unreliable
+            )],
+            TransferChannel::UnreliableSequenced => {
+                let framed = packet::wrap_sequenced(session.next_send_sequence(), &data);
+
+                vec![session.channel.encode(
+                    &framed,
+                    PacketType::Unreliable
+                )]
+            }
+        };
+
+        for pkt in datagrams {
+            let result = self.sockets[session.socket].send_to(&pkt, session.addr).await;
+            session.record_send(&result);
+            result?;
+        }
+
+        METRICS.record_sent(channel);
         Ok(())
     }
 
+    /// Sends `payload` unreliably to every client that hasn't been sent anything for `idle`.
+    /// This keeps NAT mappings open, and sends that fail show a path has gone dead before the client times out.
+    pub async fn send_keepalives(&mut self, idle: Duration, payload: &[u8]) {
+        for id in self.connection_manager.idle_sessions(idle) {
+            let Some(session) = self.connection_manager.get_by_id(id) else {
+                continue;
+            };
+
+            let pkt = session.channel.encode(payload, PacketType::Unreliable);
+            let result = self.sockets[session.socket].send_to(&pkt, session.addr).await;
+            session.record_send(&result);
+
+            if let Err(e) = result {
+                debug!("failed to send keepalive to {}: {}", id, e);
+            }
+        }
+    }
+
+    /// Limits how many new clients can connect per window. Clients that are already connected aren't affected.
+    pub fn set_new_connection_limit(&mut self, limit: u32, window: Duration) {
+        self.connection_manager.set_new_session_limit(limit, window);
+    }
+
+    /// Limits how many handshake cookies one IP address can be sent per window.
+    pub fn set_cookie_limit(&mut self, limit: u32, window: Duration) {
+        self.cookie_replies.set_limits(limit, window);
+    }
+
+    /// Forgets cookie counts for addresses that haven't been sent one this window.
+    pub fn prune_cookie_replies(&mut self) {
+        self.cookie_replies.prune();
+    }
+
+    /// Replaces the addresses that can't connect. Clients already connected from them aren't affected.
+    pub fn set_denylist(&mut self, denylist: Vec<IpNet>) {
+        self.connection_manager.set_denylist(denylist);
+    }
+
+    /// Strips the `SEQUENCED` wrapper from an unreliable payload, if it has one.
+    /// Returns `None` if the payload is sequenced but truncated, or older than one already received.
+    fn unwrap_sequenced(&mut self, session_id: u64, payload: Vec<u8>) -> Option<(Vec<u8>, TransferChannel)> {
+        let (seq, data) = match packet::unwrap_sequenced(&payload) {
+            None => return Some((payload, TransferChannel::Unreliable)),
+            Some(Ok(parts)) => parts,
+            Some(Err(e)) => {
+                debug!("dropping sequenced packet from {}: {}", session_id, e);
+                return None;
+            }
+        };
+
+        let session = self.connection_manager.get_by_id(session_id)?;
+
+        if !session.accept_sequence(seq) {
+            debug!("dropping stale sequenced packet {} from {}", seq, session_id);
+            return None;
+        }
+
+        Some((data.to_vec(), TransferChannel::UnreliableSequenced))
+    }
+
+    /// Passes a reliable payload through, or adds it to its message if it's a fragment.
+    /// Returns `None` while a fragmented message is still missing pieces, or if the fragment was bad.
+    fn reassemble(&mut self, session_id: u64, payload: Vec<u8>) -> Option<Vec<u8>> {
+        if !fragment::is_fragment(&payload) {
+            return Some(payload);
+        }
+
+        let session = self.connection_manager.get_by_id(session_id)?;
+        match session.fragments.insert(&payload) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("dropping fragment from {}: {}", session_id, e);
+                None
+            }
+        }
+    }
+
     pub async fn do_resends(&mut self, interval: Duration) {
-        for (addr, pkt) in self.connection_manager.get_resends(interval) {
-            if let Err(e) = self.socket.send_to(&pkt, addr).await {
+        for (socket, addr, pkt) in self.connection_manager.get_resends(interval) {
+            if let Err(e) = self.sockets[socket].send_to(&pkt, addr).await {
                 warn!("failed to resend pkt {}", e);
                 continue;
             }
+            METRICS.record_resend();
         }
+
+        METRICS.set_unacked_reliable_bytes(self.connection_manager.overdue_bytes() as u64);
     }
 
-    pub fn remove_client(&mut self, id: &u64) {
+    pub fn remove_client(&mut self, id: u64) {
         self.connection_manager.remove_session(id);
     }
-}
\ No newline at end of file
+
+    /// Disconnects a client without dropping its session straight away,
+    /// giving any queued reliable packets time to reach it.
+    pub fn disconnect_client(&mut self, id: u64) {
+        self.disconnect_client_after(id, DISCONNECT_GRACE);
+    }
+
+    /// Like `disconnect_client`, but keeps the session alive for `grace` instead of the default.
+    pub fn disconnect_client_after(&mut self, id: u64, grace: Duration) {
+        self.connection_manager.close_session(id, grace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::version::WIRE_VERSION;
+    use crate::udp::socket::memory::MemorySocket;
+    use super::*;
+
+    /// Reads the next datagram waiting on a socket, if there is one.
+    fn try_recv(socket: &MemorySocket) -> Option<Vec<u8>> {
+        let mut buf = [0u8; 2048];
+        match socket.try_recv_from(&mut buf) {
+            Ok((len, _)) => Some(buf[..len].to_vec()),
+            Err(_) => None,
+        }
+    }
+
+    /// A `Connect` carrying the cookie `relay` gives a client at `addr`.
+    fn connect_packet<S: DatagramSocket>(relay: &PaperInterface<S>, addr: SocketAddr) -> Vec<u8> {
+        Packet::Connect { protocol_version: WIRE_VERSION, cookie: relay.cookies.issue(addr) }.to_bytes()
+    }
+
+    /// Lets the interface handle anything waiting on its sockets, including datagrams that produce no events.
+    async fn drain(relay: &mut PaperInterface<MemorySocket>) -> Vec<ServerEvent> {
+        tokio::time::timeout(Duration::from_millis(50), Box::pin(relay.recv_events())).await
+            .map(Result::unwrap)
+            .unwrap_or_default()
+    }
+
+    #[tokio::test]
+    async fn reliable_exchange_is_delivered_and_acked() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+
+        let connect = connect_packet(&relay, client.local_addr());
+        let datagram = channel.encode(&connect, PacketType::ReliableOrdered);
+        client.send_to(&datagram, relay_addr).await.unwrap();
+
+        let events = Box::pin(relay.recv_events()).await.unwrap();
+        let [ServerEvent::ClientConnected { client_id }, ServerEvent::PacketReceived { data, channel: TransferChannel::Reliable, .. }] = events.as_slice() else {
+            panic!("expected a connect and a reliable packet, got {events:?}");
+        };
+        assert_eq!(data, &connect);
+
+        // The relay acks the client's reliable packet straight away.
+        let ack = try_recv(&client).expect("the relay should ack the packet");
+        assert!(matches!(channel.decode(&ack), DecodeResult::Ack { .. }));
+
+        relay.send(*client_id, b"hello".to_vec(), TransferChannel::Reliable).await.unwrap();
+        let datagram = try_recv(&client).expect("the relay should send the packet");
+        let DecodeResult::Reliable { payload, ack_packet: Some(ack), .. } = channel.decode(&datagram) else {
+            panic!("expected a reliable packet");
+        };
+        assert_eq!(payload, vec![b"hello".to_vec()]);
+
+        // Once the client's ack arrives, there's nothing left to resend.
+        client.send_to(&ack, relay_addr).await.unwrap();
+        assert!(Box::pin(drain(&mut relay)).await.is_empty());
+        relay.do_resends(Duration::ZERO).await;
+        assert_eq!(try_recv(&client), None);
+    }
+
+    #[tokio::test]
+    async fn unacked_packets_are_resent() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+
+        let connect = connect_packet(&relay, client.local_addr());
+        client.send_to(&channel.encode(&connect, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+        let events = Box::pin(relay.recv_events()).await.unwrap();
+        let Some(ServerEvent::ClientConnected { client_id }) = events.first() else {
+            panic!("expected a connect, got {events:?}");
+        };
+        try_recv(&client).expect("the relay should ack the packet");
+
+        relay.send(*client_id, b"hello".to_vec(), TransferChannel::Reliable).await.unwrap();
+        let sent = try_recv(&client).expect("the relay should send the packet");
+
+        relay.do_resends(Duration::ZERO).await;
+        assert_eq!(try_recv(&client), Some(sent));
+    }
+
+    #[tokio::test]
+    async fn clients_that_stop_acking_are_reported_as_saturated() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+
+        let connect = connect_packet(&relay, client.local_addr());
+        client.send_to(&channel.encode(&connect, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+        let events = Box::pin(relay.recv_events()).await.unwrap();
+        let Some(ServerEvent::ClientConnected { client_id }) = events.first() else {
+            panic!("expected a connect, got {events:?}");
+        };
+        let client_id = *client_id;
+
+        for _ in 0..3 {
+            relay.send(client_id, vec![0; 100], TransferChannel::Reliable).await.unwrap();
+        }
+
+        // Nothing is overdue until it has gone a whole resend interval without an ack.
+        relay.do_resends(Duration::from_secs(10)).await;
+        assert!(relay.connection_manager.saturated_sessions(0).is_empty());
+
+        relay.do_resends(Duration::ZERO).await;
+        assert!(relay.connection_manager.overdue_bytes() >= 300);
+        assert_eq!(relay.connection_manager.saturated_sessions(200), vec![client_id]);
+        assert!(relay.connection_manager.saturated_sessions(1000).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reliable_packets_are_delivered_in_order_across_gaps_and_duplicates() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+
+        let connect = connect_packet(&relay, client.local_addr());
+        client.send_to(&channel.encode(&connect, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+        Box::pin(relay.recv_events()).await.unwrap();
+        try_recv(&client).expect("the relay should ack the packet");
+
+        let datagrams: Vec<Vec<u8>> = (1..=4u8)
+            .map(|i| channel.encode(&[i], PacketType::ReliableOrdered))
+            .collect();
+
+        // 1 arrives, then 3 and 4 ahead of the missing 2, then a resend of 1, and finally 2.
+        for i in [0, 2, 3, 0, 1] {
+            client.send_to(&datagrams[i], relay_addr).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        loop {
+            let events = Box::pin(drain(&mut relay)).await;
+            if events.is_empty() {
+                break;
+            }
+
+            received.extend(events.into_iter().filter_map(|event| match event {
+                ServerEvent::PacketReceived { data, .. } => Some(data),
+                _ => None,
+            }));
+        }
+        assert_eq!(received, vec![vec![1], vec![2], vec![3], vec![4]]);
+
+        // Everything is acked, the duplicate included, so the client stops resending it.
+        let acks = std::iter::from_fn(|| try_recv(&client))
+            .filter(|datagram| matches!(channel.decode(datagram), DecodeResult::Ack { .. }))
+            .count();
+        assert_eq!(acks, 5);
+    }
+
+    #[tokio::test]
+    async fn replies_go_out_on_the_socket_the_client_used() {
+        let sockets = [
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        ];
+        let relay_addrs: Vec<SocketAddr> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+        let mut relay = PaperInterface::new(sockets.into(), 64);
+
+        for relay_addr in relay_addrs {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut channel = Channel::new();
+
+            let connect = connect_packet(&relay, client.local_addr().unwrap());
+            client.send_to(&channel.encode(&connect, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+
+            let events = Box::pin(relay.recv_events()).await.unwrap();
+            let Some(ServerEvent::ClientConnected { client_id }) = events.first() else {
+                panic!("expected a connect, got {events:?}");
+            };
+            relay.send(*client_id, b"hello".to_vec(), TransferChannel::Reliable).await.unwrap();
+
+            // The ack for the connect comes first, then the reply. Both come from the socket it was sent to.
+            let mut buf = [0u8; 2048];
+            for _ in 0..2 {
+                let (_, from) = client.recv_from(&mut buf).await.unwrap();
+                assert_eq!(from, relay_addr);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_only_open_once_the_cookie_is_echoed() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+
+        // Without a cookie, the relay answers with one but doesn't open a session.
+        let hello = Packet::Connect { protocol_version: WIRE_VERSION, cookie: vec![0; CONNECT_PADDING] }.to_bytes();
+        client.send_to(&channel.encode(&hello, PacketType::Unreliable), relay_addr).await.unwrap();
+        assert!(Box::pin(drain(&mut relay)).await.is_empty());
+        assert!(!relay.connection_manager.has_session(client.local_addr()));
+
+        let reply = try_recv(&client).expect("the relay should send a cookie");
+        assert!(reply.len() <= channel.encode(&hello, PacketType::Unreliable).len());
+        let DecodeResult::Unreliable { payload } = channel.decode(&reply) else {
+            panic!("expected an unreliable packet");
+        };
+        let Ok(Packet::ConnectAccepted { client_id: 0, cookie }) = Packet::from_bytes(&payload[0]) else {
+            panic!("expected a cookie");
+        };
+
+        // A cookie issued to another address doesn't open one either.
+        let spoofed = Packet::Connect {
+            protocol_version: WIRE_VERSION,
+            cookie: relay.cookies.issue("127.0.0.1:1".parse().unwrap()),
+        }.to_bytes();
+        client.send_to(&Channel::new().encode(&spoofed, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+        assert!(Box::pin(drain(&mut relay)).await.is_empty());
+        assert!(!relay.connection_manager.has_session(client.local_addr()));
+        // It's too short to be answered with a fresh cookie.
+        assert_eq!(try_recv(&client), None);
+
+        // Echoing the cookie does.
+        let connect = Packet::Connect { protocol_version: WIRE_VERSION, cookie }.to_bytes();
+        client.send_to(&channel.encode(&connect, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+        let events = Box::pin(relay.recv_events()).await.unwrap();
+        let [ServerEvent::ClientConnected { .. }, ServerEvent::PacketReceived { data, .. }] = events.as_slice() else {
+            panic!("expected a connect and a reliable packet, got {events:?}");
+        };
+        assert_eq!(data, &connect);
+    }
+
+    /// Sends an unreliable, cookie-less `Connect` and returns whether the relay answered it.
+    async fn is_sent_a_cookie(relay: &mut PaperInterface<MemorySocket>, client: &MemorySocket, hello: &Packet) -> bool {
+        let datagram = Channel::new().encode(&hello.to_bytes(), PacketType::Unreliable);
+        client.send_to(&datagram, relay.sockets[0].local_addr()).await.unwrap();
+        assert!(Box::pin(drain(relay)).await.is_empty());
+        try_recv(client).is_some()
+    }
+
+    #[tokio::test]
+    async fn denied_addresses_are_not_sent_a_cookie() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        relay.set_denylist(vec![IpNet::from(client.local_addr().ip())]);
+
+        assert!(!Box::pin(is_sent_a_cookie(&mut relay, &client, &Packet::Connect { protocol_version: WIRE_VERSION, cookie: vec![0; CONNECT_PADDING] })).await);
+    }
+
+    #[tokio::test]
+    async fn unpadded_handshakes_are_not_sent_a_cookie() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+
+        let short = Packet::Connect { protocol_version: WIRE_VERSION, cookie: vec![0; CONNECT_PADDING - 1] };
+        assert!(!Box::pin(is_sent_a_cookie(&mut relay, &client, &short)).await);
+        assert!(Box::pin(is_sent_a_cookie(&mut relay, &client, &Packet::Connect { protocol_version: WIRE_VERSION, cookie: vec![0; CONNECT_PADDING] })).await);
+    }
+
+    #[tokio::test]
+    async fn cookies_sent_to_one_address_are_limited() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        relay.set_cookie_limit(2, Duration::from_secs(10));
+        let hello = Packet::Connect { protocol_version: WIRE_VERSION, cookie: vec![0; CONNECT_PADDING] };
+
+        for _ in 0..2 {
+            assert!(Box::pin(is_sent_a_cookie(&mut relay, &client, &hello)).await);
+        }
+        assert!(!Box::pin(is_sent_a_cookie(&mut relay, &client, &hello)).await);
+    }
+
+    #[tokio::test]
+    async fn datagrams_without_a_handshake_open_no_session() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+
+        let datagram = channel.encode(&Packet::Heartbeat.to_bytes(), PacketType::ReliableOrdered);
+        client.send_to(&datagram, relay_addr).await.unwrap();
+
+        assert!(Box::pin(drain(&mut relay)).await.is_empty());
+        assert!(!relay.connection_manager.has_session(client.local_addr()));
+        assert_eq!(try_recv(&client), None);
+    }
+
+    #[tokio::test]
+    async fn sequenced_packets_are_unwrapped_and_stale_ones_dropped() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+
+        let connect = connect_packet(&relay, client.local_addr());
+        client.send_to(&channel.encode(&connect, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+        let events = Box::pin(relay.recv_events()).await.unwrap();
+        let Some(ServerEvent::ClientConnected { client_id }) = events.first() else {
+            panic!("expected a connect, got {events:?}");
+        };
+        let client_id = *client_id;
+        try_recv(&client).expect("the relay should ack the packet");
+
+        let payloads = [
+            packet::wrap_sequenced(2, b"n"),
+            // Older than the one before, so it's dropped.
+            packet::wrap_sequenced(1, b"o"),
+            // Too short to hold a sequence number.
+            packet::wrap_sequenced(3, b"")[..3].to_vec(),
+            // Not a sequenced packet, however much it looks like one.
+            vec![0xFF, 0, 0, 0, 3, b'x'],
+        ];
+        for payload in payloads {
+            client.send_to(&channel.encode(&payload, PacketType::Unreliable), relay_addr).await.unwrap();
+        }
+
+        let received: Vec<_> = Box::pin(drain(&mut relay)).await.into_iter()
+            .map(|event| match event {
+                ServerEvent::PacketReceived { data, channel, .. } => (data, channel),
+                event => panic!("expected a packet, got {event:?}"),
+            })
+            .collect();
+        assert_eq!(received, vec![
+            (b"n".to_vec(), TransferChannel::UnreliableSequenced),
+            (vec![0xFF, 0, 0, 0, 3, b'x'], TransferChannel::Unreliable),
+        ]);
+
+        relay.send(client_id, b"hi".to_vec(), TransferChannel::UnreliableSequenced).await.unwrap();
+        let datagram = try_recv(&client).expect("the relay should send the packet");
+        let DecodeResult::Unreliable { payload } = channel.decode(&datagram) else {
+            panic!("expected an unreliable packet");
+        };
+        assert_eq!(payload, vec![packet::wrap_sequenced(1, b"hi")]);
+    }
+
+    #[tokio::test]
+    async fn keepalives_go_only_to_clients_that_have_been_sent_nothing_for_a_while() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+        let idle = Duration::from_millis(50);
+
+        let connect = connect_packet(&relay, client.local_addr());
+        client.send_to(&channel.encode(&connect, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+        let events = Box::pin(relay.recv_events()).await.unwrap();
+        let Some(ServerEvent::ClientConnected { client_id }) = events.first() else {
+            panic!("expected a connect, got {events:?}");
+        };
+        let client_id = *client_id;
+        try_recv(&client).expect("the relay should ack the packet");
+
+        relay.send(client_id, b"hello".to_vec(), TransferChannel::Unreliable).await.unwrap();
+        try_recv(&client).expect("the relay should send the packet");
+
+        // It was just sent something, so it doesn't need a keepalive yet.
+        relay.send_keepalives(idle, b"keepalive").await;
+        assert_eq!(try_recv(&client), None);
+
+        // The client stays quiet, so it keeps getting one each time it's been idle long enough.
+        for _ in 0..2 {
+            tokio::time::sleep(idle).await;
+            relay.send_keepalives(idle, b"keepalive").await;
+            let datagram = try_recv(&client).expect("the relay should send a keepalive");
+            let DecodeResult::Unreliable { payload } = channel.decode(&datagram) else {
+                panic!("expected an unreliable packet");
+            };
+            assert_eq!(payload, vec![b"keepalive".to_vec()]);
+
+            relay.send_keepalives(idle, b"keepalive").await;
+            assert_eq!(try_recv(&client), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn sessions_are_dropped_after_too_many_undecodable_datagrams_in_a_row() {
+        let (relay_socket, client) = MemorySocket::pair();
+        let relay_addr = relay_socket.local_addr();
+        let mut relay = PaperInterface::new(vec![relay_socket], 64);
+        let mut channel = Channel::new();
+
+        let connect = connect_packet(&relay, client.local_addr());
+        client.send_to(&channel.encode(&connect, PacketType::ReliableOrdered), relay_addr).await.unwrap();
+        let events = Box::pin(relay.recv_events()).await.unwrap();
+        let Some(ServerEvent::ClientConnected { client_id }) = events.first() else {
+            panic!("expected a connect, got {events:?}");
+        };
+        let client_id = *client_id;
+
+        for _ in 0..MAX_CONSECUTIVE_DECODE_ERRORS {
+            client.send_to(b"garbage", relay_addr).await.unwrap();
+        }
+        assert!(Box::pin(drain(&mut relay)).await.is_empty());
+
+        // A datagram that decodes starts the count over.
+        client.send_to(&channel.encode(b"hello", PacketType::Unreliable), relay_addr).await.unwrap();
+        assert!(matches!(Box::pin(drain(&mut relay)).await.as_slice(), [ServerEvent::PacketReceived { .. }]));
+
+        for _ in 0..MAX_CONSECUTIVE_DECODE_ERRORS {
+            client.send_to(b"garbage", relay_addr).await.unwrap();
+        }
+        assert!(Box::pin(drain(&mut relay)).await.is_empty());
+
+        client.send_to(b"garbage", relay_addr).await.unwrap();
+        let events = Box::pin(drain(&mut relay)).await;
+        assert!(matches!(
+            events.as_slice(),
+            [ServerEvent::ClientDisconnected { client_id: id, reason: DisconnectReason::ProtocolError }] if *id == client_id
+        ));
+        assert!(!relay.connection_manager.has_session(client.local_addr()));
+    }
+}
@@ -1,19 +1,156 @@
 use std::collections::HashMap;
+use std::io;
 use std::net::SocketAddr;
+use ipnet::IpNet;
 use std::time::{Duration, Instant};
 use paperudp::channel::Channel;
+use crate::metrics::METRICS;
+use crate::udp::fragment::Reassembler;
+
+/// How many sends in a row can fail with an unreachable error before the session's timeout is shortened.
+const MAX_UNREACHABLE_SENDS: u32 = 3;
 
 pub struct ClientSession {
     pub id: u64,
     pub addr: SocketAddr,
+    /// Which of the interface's sockets the client was last heard on.
+    /// Replies go out the same one, so they come from the address the client sent to.
+    pub socket: usize,
     pub channel: Channel,
     pub last_heard_from: Instant,
+    /// When anything was last sent to this client, used to decide when it needs a keepalive.
+    pub last_sent_to: Instant,
+    /// The number of sends in a row that failed because the client's address was unreachable.
+    /// A client behind an expired NAT mapping often shows up like this before it times out.
+    pub unreachable_sends: u32,
+    /// Set once the session has been told to disconnect.
+    /// The session is kept around until this deadline so queued reliable
+    /// packets (like `ForceDisconnect`) can still be resent.
+    pub close_deadline: Option<Instant>,
+    /// The number of undecodable packets received in a row.
+    pub decode_errors: u32,
+    /// The sequence number of the last unreliable-sequenced packet sent to this client.
+    pub sequenced_send: u32,
+    /// The sequence number of the newest unreliable-sequenced packet received from this client.
+    pub sequenced_recv: Option<u32>,
+    /// The bytes of reliable packets that were due a resend at the last resend, having gone unacked
+    /// for a whole resend interval. Packets sent more recently than that aren't counted.
+    pub overdue_bytes: usize,
+    /// The ID of the last fragmented message sent to this client.
+    pub fragmented_send: u32,
+    /// Fragmented messages from this client that are still missing pieces.
+    pub fragments: Reassembler,
+}
+
+impl ClientSession {
+    pub fn new(id: u64, addr: SocketAddr, channel: Channel) -> Self {
+        Self {
+            id,
+            addr,
+            socket: 0,
+            channel,
+            last_heard_from: Instant::now(),
+            last_sent_to: Instant::now(),
+            unreachable_sends: 0,
+            close_deadline: None,
+            decode_errors: 0,
+            sequenced_send: 0,
+            sequenced_recv: None,
+            overdue_bytes: 0,
+            fragmented_send: 0,
+            fragments: Reassembler::default(),
+        }
+    }
+
+    /// Records the outcome of a send to this client.
+    pub fn record_send<T>(&mut self, result: &io::Result<T>) {
+        match result {
+            Ok(_) => {
+                self.last_sent_to = Instant::now();
+                self.unreachable_sends = 0;
+            }
+            Err(e) if matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::HostUnreachable
+                    | io::ErrorKind::NetworkUnreachable
+            ) => self.unreachable_sends += 1,
+            Err(_) => {}
+        }
+    }
+
+    /// How long this session can go without being heard from before it's timed out.
+    /// Clients that look unreachable get a quarter of the usual timeout, so a dead path is noticed sooner.
+    fn timeout(&self, timeout: Duration) -> Duration {
+        if self.unreachable_sends >= MAX_UNREACHABLE_SENDS {
+            timeout / 4
+        } else {
+            timeout
+        }
+    }
+
+    /// Gets the sequence number for the next unreliable-sequenced packet sent to this client.
+    pub fn next_send_sequence(&mut self) -> u32 {
+        self.sequenced_send = self.sequenced_send.wrapping_add(1);
+        self.sequenced_send
+    }
+
+    /// Gets the ID for the next fragmented message sent to this client.
+    pub fn next_message_id(&mut self) -> u32 {
+        self.fragmented_send = self.fragmented_send.wrapping_add(1);
+        self.fragmented_send
+    }
+
+    /// Checks an incoming unreliable-sequenced packet.
+    /// Returns true if it's newer than anything received so far, false if it's stale.
+    pub fn accept_sequence(&mut self, seq: u32) -> bool {
+        // Compare with wrapping so the sequence can roll over.
+        #[allow(clippy::cast_possible_wrap)]
+        let is_newer = self.sequenced_recv.is_none_or(|last| (seq.wrapping_sub(last) as i32) > 0);
+
+        if is_newer {
+            self.sequenced_recv = Some(seq);
+        }
+
+        is_newer
+    }
+}
+
+/// Caps how many sessions can be created per window, across all addresses.
+/// Source addresses are easy to spoof, so this stops a flood from creating sessions without limit.
+struct NewSessionBudget {
+    limit: u32,
+    window: Duration,
+    started: Instant,
+    used: u32,
+}
+
+impl NewSessionBudget {
+    /// Uses up one new session from the budget.
+    /// Returns false if the current window's budget is already spent.
+    fn take(&mut self) -> bool {
+        let now = Instant::now();
+        if now.duration_since(self.started) >= self.window {
+            self.started = now;
+            self.used = 0;
+        }
+
+        if self.used >= self.limit {
+            return false;
+        }
+
+        self.used += 1;
+        true
+    }
 }
 
 pub struct ConnectionManager {
     id_to_session: HashMap<u64, ClientSession>,
     addr_to_id: HashMap<SocketAddr, u64>,
     next_client_id: u64,
+    new_sessions: NewSessionBudget,
+    /// Addresses that can't open a session.
+    denylist: Vec<IpNet>,
 }
 
 impl ConnectionManager {
@@ -21,83 +158,248 @@ impl ConnectionManager {
         Self {
             id_to_session: HashMap::new(),
             addr_to_id: HashMap::new(),
-            next_client_id: 1
+            next_client_id: 1,
+            new_sessions: NewSessionBudget {
+                limit: u32::MAX,
+                window: Duration::from_secs(1),
+                started: Instant::now(),
+                used: 0,
+            },
+            denylist: Vec::new(),
         }
     }
 
-    /// Returns a ClientSession and a bool.
-    /// If the session already existed, the bool will be false.
-    /// If it had to be created, it will return true.
-    pub fn get_or_create(&mut self, addr: SocketAddr) -> (&mut ClientSession, bool) {
-        if let Some(id) = self.addr_to_id.get(&addr) {
-            // TODO: get rid of expect
-            let s = self.id_to_session.get_mut(id).expect("session exists in both maps");
-            return (s, false);
-        }
+    /// Replaces the addresses that can't open a session. Existing sessions aren't affected,
+    /// see `denied_sessions` for finding them.
+    pub fn set_denylist(&mut self, denylist: Vec<IpNet>) {
+        self.denylist = denylist;
+    }
 
-        (self.create_session(addr), true)
+    /// Returns true if the address is in the denylist.
+    pub fn is_denied(&self, addr: SocketAddr) -> bool {
+        // Dual-stack sockets report IPv4 clients as IPv4-mapped IPv6 addresses.
+        let ip = addr.ip().to_canonical();
+        self.denylist.iter().any(|net| net.contains(&ip))
     }
 
-    pub fn create_session(&mut self, addr: SocketAddr) -> &mut ClientSession {
-        let id = self.next_client_id;
-        self.next_client_id += 1;
+    /// Gets the IDs of open sessions whose address is in the denylist.
+    pub fn denied_sessions(&self) -> Vec<u64> {
+        self.id_to_session.values()
+            .filter(|session| session.close_deadline.is_none() && self.is_denied(session.addr))
+            .map(|session| session.id)
+            .collect()
+    }
 
-        let session = ClientSession {
-            id,
-            addr,
-            channel: Channel::new(),
-            last_heard_from: Instant::now(),
-        };
+    /// Limits how many sessions can be created per window. Existing sessions aren't affected.
+    pub fn set_new_session_limit(&mut self, limit: u32, window: Duration) {
+        self.new_sessions.limit = limit;
+        self.new_sessions.window = window;
+    }
+
+    /// Returns true if the address has an open session.
+    pub fn has_session(&self, addr: SocketAddr) -> bool {
+        self.addr_to_id.get(&addr).is_some_and(|id| self.id_to_session.contains_key(id))
+    }
 
-        self.id_to_session.insert(id, session);
+    pub fn get_by_addr(&mut self, addr: SocketAddr) -> Option<&mut ClientSession> {
+        let id = self.addr_to_id.get(&addr)?;
+        self.id_to_session.get_mut(id)
+    }
+
+    /// Opens a session for an address, keeping the channel its first datagram was decoded with.
+    /// Returns `None` if the address is denied or the new session limit has been reached.
+    pub fn create_session(&mut self, addr: SocketAddr, channel: Channel) -> Option<&mut ClientSession> {
+        if self.is_denied(addr) || !self.new_sessions.take() {
+            return None;
+        }
+
+        let id = self.allocate_id();
+
+        // Replaces any stale mapping left behind if the maps got out of sync.
         self.addr_to_id.insert(addr, id);
+        METRICS.client_connected();
 
-        self.id_to_session.get_mut(&id).expect("session exists")
+        Some(self.id_to_session.entry(id)
+            .or_insert_with(|| ClientSession::new(id, addr, channel)))
     }
 
-    pub fn get_by_id(&mut self, id: &u64) -> Option<&mut ClientSession> {
-        self.id_to_session.get_mut(id)
+    /// Hands out the next free client ID. IDs aren't reused, but if the counter ever wraps,
+    /// any ID still held by a session (including one that's closing) is skipped.
+    fn allocate_id(&mut self) -> u64 {
+        loop {
+            let id = self.next_client_id;
+            // 0 is never handed out, so it's skipped on the way around too.
+            self.next_client_id = self.next_client_id.wrapping_add(1).max(1);
+
+            if !self.id_to_session.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    pub fn get_by_id(&mut self, id: u64) -> Option<&mut ClientSession> {
+        self.id_to_session.get_mut(&id)
+    }
+
+    pub fn get_addr(&self, id: u64) -> Option<SocketAddr> {
+        self.id_to_session.get(&id).map(|session| session.addr)
     }
 
     pub fn get_resends(
         &mut self,
         interval: Duration,
-    ) -> Vec<(SocketAddr, Vec<u8>)> {
+    ) -> Vec<(usize, SocketAddr, Vec<u8>)> {
         let mut out = Vec::new();
 
         for session in self.id_to_session.values_mut() {
             let packets = session.channel.collect_resends(interval);
+            session.overdue_bytes = packets.iter().map(Vec::len).sum();
 
             for pkt in packets {
-                out.push((session.addr, pkt));
+                out.push((session.socket, session.addr, pkt));
             }
         }
 
         out
     }
 
+    /// Gets the IDs of open sessions with more than `max` bytes of reliable data overdue for an ack.
+    pub fn saturated_sessions(&self, max: usize) -> Vec<u64> {
+        self.id_to_session.values()
+            .filter(|session| session.close_deadline.is_none() && session.overdue_bytes > max)
+            .map(|session| session.id)
+            .collect()
+    }
+
+    /// The reliable data overdue for an ack across every session, in bytes.
+    pub fn overdue_bytes(&self) -> usize {
+        self.id_to_session.values().map(|session| session.overdue_bytes).sum()
+    }
+
+    /// Gets the IDs of open sessions that haven't been sent anything for at least `idle`.
+    pub fn idle_sessions(&self, idle: Duration) -> Vec<u64> {
+        self.id_to_session.values()
+            .filter(|session| session.close_deadline.is_none() && session.last_sent_to.elapsed() >= idle)
+            .map(|session| session.id)
+            .collect()
+    }
+
+    /// Returns true if the session exists and isn't closing.
+    pub fn is_open(&self, id: u64) -> bool {
+        self.id_to_session.get(&id)
+            .is_some_and(|session| session.close_deadline.is_none())
+    }
+
+    /// Returns true if the session is open and was heard from within `within`.
+    pub fn is_active(&self, id: u64, within: Duration) -> bool {
+        self.id_to_session.get(&id)
+            .is_some_and(|session| session.close_deadline.is_none() && session.last_heard_from.elapsed() < within)
+    }
+
+    /// Removes sessions that have timed out and returns their IDs.
+    /// Sessions that were closed with `close_session` are also reaped once
+    /// their grace period ends, but are not returned since they were already handled.
     pub fn cleanup_sessions(&mut self, timeout: Duration) -> Vec<u64> {
         let now = Instant::now();
         let mut expired = Vec::new();
+        let mut closed = Vec::new();
 
         for (&id, session) in &self.id_to_session {
-            if now.duration_since(session.last_heard_from) > timeout {
+            if let Some(deadline) = session.close_deadline {
+                if now >= deadline {
+                    closed.push(id);
+                }
+            } else if now.duration_since(session.last_heard_from) > session.timeout(timeout) {
                 expired.push(id);
             }
         }
 
-        for id in &expired {
-            if let Some(session) = self.id_to_session.remove(id) {
-                self.addr_to_id.remove(&session.addr);
-            }
+        for &id in expired.iter().chain(&closed) {
+            self.remove_session(id);
         }
 
         expired
     }
 
-    pub fn remove_session(&mut self, id: &u64) {
-        if let Some(session) = self.id_to_session.remove(id) {
+    /// Marks a session as closing.
+    /// The session stays alive for `grace` so pending reliable packets can be flushed,
+    /// after which `cleanup_sessions` removes it.
+    pub fn close_session(&mut self, id: u64, grace: Duration) {
+        if let Some(session) = self.id_to_session.get_mut(&id) {
+            session.close_deadline.get_or_insert(Instant::now() + grace);
+        }
+    }
+
+    pub fn remove_session(&mut self, id: u64) {
+        if let Some(session) = self.id_to_session.remove(&id) {
             self.addr_to_id.remove(&session.addr);
+            METRICS.client_disconnected();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn the_budget_refills_each_window() {
+        let mut budget = NewSessionBudget {
+            limit: 2,
+            window: Duration::from_millis(20),
+            started: Instant::now(),
+            used: 0,
+        };
+
+        assert!(budget.take());
+        assert!(budget.take());
+        assert!(!budget.take());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(budget.take());
+    }
+
+    #[test]
+    fn sessions_past_the_limit_are_refused() {
+        let mut manager = ConnectionManager::new();
+        manager.set_new_session_limit(1, Duration::from_secs(10));
+
+        assert!(manager.create_session(addr(1), Channel::new()).is_some());
+        assert!(manager.create_session(addr(2), Channel::new()).is_none());
+        assert!(!manager.has_session(addr(2)));
+    }
+
+    #[test]
+    fn denied_addresses_match_single_ips_and_subnets() {
+        let mut manager = ConnectionManager::new();
+        manager.set_denylist(vec!["203.0.113.7/32".parse().unwrap(), "198.51.100.0/24".parse().unwrap()]);
+
+        assert!(manager.is_denied("203.0.113.7:4000".parse().unwrap()));
+        assert!(!manager.is_denied("203.0.113.8:4000".parse().unwrap()));
+        assert!(manager.is_denied("198.51.100.200:4000".parse().unwrap()));
+        assert!(!manager.is_denied("198.51.101.1:4000".parse().unwrap()));
+
+        // The same addresses as a dual-stack socket reports them.
+        assert!(manager.is_denied("[::ffff:203.0.113.7]:4000".parse().unwrap()));
+        assert!(manager.is_denied("[::ffff:198.51.100.1]:4000".parse().unwrap()));
+        assert!(!manager.is_denied("[::ffff:127.0.0.1]:4000".parse().unwrap()));
+    }
+
+    #[test]
+    fn client_ids_wrap_around_without_reusing_live_ones() {
+        let mut manager = ConnectionManager::new();
+        assert_eq!(manager.create_session(addr(1), Channel::new()).unwrap().id, 1);
+
+        manager.next_client_id = u64::MAX;
+        assert_eq!(manager.create_session(addr(2), Channel::new()).unwrap().id, u64::MAX);
+
+        // 0 is never handed out and 1 is still in use, so the next ID is 2.
+        assert_eq!(manager.create_session(addr(3), Channel::new()).unwrap().id, 2);
+        assert_eq!(manager.get_addr(1), Some(addr(1)));
+        assert_eq!(manager.get_addr(u64::MAX), Some(addr(2)));
+    }
+}
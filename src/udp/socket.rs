@@ -0,0 +1,123 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// The datagram operations `PaperInterface` needs from a socket.
+/// Lets the interface run over something other than a real UDP socket.
+pub trait DatagramSocket {
+    /// Waits until the socket may have a datagram to read.
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Reads a datagram without waiting.
+    /// Returns `WouldBlock` if nothing is ready.
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Sends a datagram to the given address.
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn readable(&self) -> impl Future<Output = io::Result<()>> + Send {
+        UdpSocket::readable(self)
+    }
+
+    fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::try_recv_from(self, buf)
+    }
+
+    fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send {
+        UdpSocket::send_to(self, buf, target)
+    }
+}
+
+#[cfg(test)]
+pub mod memory {
+    use std::collections::{HashMap, VecDeque};
+    use std::future::Future;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::Notify;
+    use super::DatagramSocket;
+
+    #[derive(Default)]
+    struct Inbox {
+        queue: Mutex<VecDeque<(Vec<u8>, SocketAddr)>>,
+        ready: Notify,
+    }
+
+    /// Connects in-memory sockets to each other by address.
+    /// Datagrams sent to an address nothing is bound to are dropped, like with UDP.
+    #[derive(Clone, Default)]
+    pub struct MemoryNetwork {
+        inboxes: Arc<Mutex<HashMap<SocketAddr, Arc<Inbox>>>>,
+    }
+
+    impl MemoryNetwork {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Creates a socket that receives everything sent to `addr` on this network.
+        pub fn bind(&self, addr: SocketAddr) -> MemorySocket {
+            let inbox = Arc::new(Inbox::default());
+            self.inboxes.lock().unwrap().insert(addr, inbox.clone());
+
+            MemorySocket { addr, network: self.clone(), inbox }
+        }
+    }
+
+    /// A socket that delivers datagrams through channels instead of the network, so tests are deterministic.
+    pub struct MemorySocket {
+        addr: SocketAddr,
+        network: MemoryNetwork,
+        inbox: Arc<Inbox>,
+    }
+
+    impl MemorySocket {
+        /// Creates two sockets on their own network that can only reach each other.
+        pub fn pair() -> (Self, Self) {
+            let network = MemoryNetwork::new();
+            let a = network.bind("127.0.0.1:1".parse().unwrap());
+            let b = network.bind("127.0.0.1:2".parse().unwrap());
+            (a, b)
+        }
+
+        pub fn local_addr(&self) -> SocketAddr {
+            self.addr
+        }
+    }
+
+    impl DatagramSocket for MemorySocket {
+        async fn readable(&self) -> io::Result<()> {
+            loop {
+                let notified = self.inbox.ready.notified();
+                if !self.inbox.queue.lock().unwrap().is_empty() {
+                    return Ok(());
+                }
+                notified.await;
+            }
+        }
+
+        fn try_recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            let Some((datagram, from)) = self.inbox.queue.lock().unwrap().pop_front() else {
+                return Err(io::ErrorKind::WouldBlock.into());
+            };
+
+            let len = datagram.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram[..len]);
+            Ok((len, from))
+        }
+
+        fn send_to(&self, buf: &[u8], target: SocketAddr) -> impl Future<Output = io::Result<usize>> + Send {
+            let inbox = self.network.inboxes.lock().unwrap().get(&target).cloned();
+            if let Some(inbox) = inbox {
+                inbox.queue.lock().unwrap().push_back((buf.to_vec(), self.addr));
+                inbox.ready.notify_waiters();
+            }
+
+            async move { Ok(buf.len()) }
+        }
+    }
+}